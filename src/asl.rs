@@ -0,0 +1,227 @@
+//! Label and control volumes of arterial spin labelling (ASL) series.
+//!
+//! An ASL series alternates label and control volumes, and may hold others such as an M0
+//! scan. BIDS lists the type of every volume in `aslcontext.tsv`, with a `volume_type` column;
+//! otherwise the order is a repeating pattern such as `LC` (label first) or `CL`.
+use ndarray::{Array4, Axis};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::AslPattern;
+use crate::error::{Error, Result};
+use crate::io::nifti_stem;
+
+/// The suffix of the table that maps the volumes of the type files back to the series.
+pub const ASL_TABLE: &str = "_types.tsv";
+/// The type of label volumes.
+pub const LABEL: &str = "label";
+/// The type of control volumes.
+pub const CONTROL: &str = "control";
+
+/// The path of the BIDS `aslcontext.tsv` of an ASL series: `sub-01_asl.nii.gz` has
+/// `sub-01_aslcontext.tsv`, and any other name gets `_aslcontext.tsv` appended to its stem.
+pub fn aslcontext_path(nifti: &Path) -> Result<PathBuf> {
+    let stem = nifti_stem(nifti)?;
+    let prefix = stem.strip_suffix("_asl").unwrap_or(&stem);
+    Ok(nifti.with_file_name(format!("{}_aslcontext.tsv", prefix)))
+}
+
+/// Read the `volume_type` column of an `aslcontext.tsv`.
+fn read_aslcontext(path: &Path) -> Result<Vec<String>> {
+    let text = fs::read_to_string(path)?;
+    let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+    let column = lines
+        .next()
+        .and_then(|header| header.split('\t').position(|c| c.trim() == "volume_type"))
+        .ok_or_else(|| Error::Input(format!("{} has no volume_type column.", path.display())))?;
+    lines
+        .enumerate()
+        .map(|(n, line)| {
+            line.split('\t')
+                .nth(column)
+                .map(|t| t.trim().to_string())
+                .filter(|t| !t.is_empty())
+                .ok_or_else(|| {
+                    Error::Input(format!(
+                        "Line {} of {} has no volume_type.",
+                        n + 2,
+                        path.display()
+                    ))
+                })
+        })
+        .collect()
+}
+
+/// The type of every volume of a series of `count` volumes.
+///
+/// # Arguments
+///
+/// * `pattern` - A pattern of label and control volumes repeated over the series, or an
+///   `aslcontext.tsv` listing every volume.
+/// * `count` - The number of volumes in the series.
+///
+/// # Returns
+///
+/// One type per volume, such as `label`, `control` or `m0scan`, or an input error if the
+/// pattern does not fit the series.
+pub fn volume_types(pattern: &AslPattern, count: usize) -> Result<Vec<String>> {
+    let types = match pattern {
+        AslPattern::Letters(letters) => {
+            if !count.is_multiple_of(letters.len()) {
+                return Err(Error::Input(format!(
+                    "The series has {} volumes, which is not a whole number of repeats of the {} volume pattern.",
+                    count,
+                    letters.len()
+                )));
+            }
+            letters
+                .chars()
+                .cycle()
+                .take(count)
+                .map(|c| if c == 'L' { LABEL } else { CONTROL }.to_string())
+                .collect()
+        }
+        AslPattern::Context(path) => {
+            let types = read_aslcontext(path)?;
+            if types.len() != count {
+                return Err(Error::Input(format!(
+                    "{} lists {} volumes but the series has {}.",
+                    path.display(),
+                    types.len(),
+                    count
+                )));
+            }
+            types
+        }
+    };
+    Ok(types)
+}
+
+/// Group the volumes of a series by type.
+///
+/// # Returns
+///
+/// The types in the order they first appear, each with its 0-based volumes.
+pub fn type_groups(types: &[String]) -> Vec<(String, Vec<usize>)> {
+    let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+    for (index, kind) in types.iter().enumerate() {
+        match groups.iter_mut().find(|(k, _)| k == kind) {
+            Some((_, indices)) => indices.push(index),
+            None => groups.push((kind.clone(), vec![index])),
+        }
+    }
+    groups
+}
+
+/// Pair every label volume with the control volume next to it, for subtraction.
+///
+/// Pairs are found by walking the series: a label followed or preceded by a control makes a
+/// pair, as in `LCLC` or `CLCL`, and other volume types such as an M0 scan may sit between
+/// pairs. Labels and controls that are not next to a volume of the other type, as in `LLCC`,
+/// cannot be paired.
+///
+/// # Returns
+///
+/// The 0-based label and control volume of each pair, in order, or an input error naming
+/// the first volume without a partner.
+pub fn pair_volumes(types: &[String]) -> Result<Vec<(usize, usize)>> {
+    let mut pairs = Vec::new();
+    let mut index = 0;
+    while index < types.len() {
+        let kind = types[index].as_str();
+        if kind != LABEL && kind != CONTROL {
+            index += 1;
+            continue;
+        }
+        let next = types.get(index + 1).map(String::as_str);
+        match (kind, next) {
+            (LABEL, Some(CONTROL)) => pairs.push((index, index + 1)),
+            (CONTROL, Some(LABEL)) => pairs.push((index + 1, index)),
+            _ => {
+                return Err(Error::Input(format!(
+                    "Cannot pair {} volume {} for --difference, it is not next to a {} volume.",
+                    kind,
+                    index + 1,
+                    if kind == LABEL { CONTROL } else { LABEL }
+                )))
+            }
+        }
+        index += 2;
+    }
+    if pairs.is_empty() {
+        return Err(Error::Input(
+            "Cannot pair label and control volumes for --difference, the series has none."
+                .to_string(),
+        ));
+    }
+    Ok(pairs)
+}
+
+/// The difference of each label/control pair, control minus label.
+pub fn pair_differences(img: &Array4<f64>, pairs: &[(usize, usize)]) -> Array4<f64> {
+    let shape = img.shape();
+    let mut differences = Array4::<f64>::zeros((shape[0], shape[1], shape[2], pairs.len()));
+    for (k, &(label, control)) in pairs.iter().enumerate() {
+        differences
+            .index_axis_mut(Axis(3), k)
+            .assign(&(&img.index_axis(Axis(3), control) - &img.index_axis(Axis(3), label)));
+    }
+    differences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn types(letters: &str) -> Vec<String> {
+        letters
+            .chars()
+            .map(|c| match c {
+                'L' => LABEL,
+                'C' => CONTROL,
+                _ => "m0scan",
+            })
+            .map(str::to_string)
+            .collect()
+    }
+
+    #[test]
+    fn repeats_the_pattern() {
+        let pattern = AslPattern::Letters("CL".to_string());
+        assert_eq!(volume_types(&pattern, 4).unwrap(), types("CLCL"));
+        assert!(volume_types(&pattern, 5).is_err());
+    }
+
+    #[test]
+    fn groups_types_in_order_of_appearance() {
+        let groups = type_groups(&types("MLCLC"));
+        let kinds: Vec<&str> = groups.iter().map(|(k, _)| k.as_str()).collect();
+        assert_eq!(kinds, ["m0scan", LABEL, CONTROL]);
+        assert_eq!(groups[1].1, vec![1, 3]);
+        assert_eq!(groups[2].1, vec![2, 4]);
+    }
+
+    #[test]
+    fn pairs_adjacent_volumes() {
+        assert_eq!(pair_volumes(&types("LCLC")).unwrap(), [(0, 1), (2, 3)]);
+        assert_eq!(pair_volumes(&types("CLCL")).unwrap(), [(1, 0), (3, 2)]);
+        assert_eq!(pair_volumes(&types("MLCMCL")).unwrap(), [(1, 2), (5, 4)]);
+    }
+
+    #[test]
+    fn rejects_volumes_without_a_partner() {
+        assert!(pair_volumes(&types("LLCC")).is_err());
+        assert!(pair_volumes(&types("LCL")).is_err());
+        assert!(pair_volumes(&types("LMC")).is_err());
+        assert!(pair_volumes(&types("MM")).is_err());
+    }
+
+    #[test]
+    fn subtracts_label_from_control() {
+        let img = Array4::from_shape_fn((2, 1, 1, 4), |(x, _, _, t)| (x + 10 * t * t) as f64);
+        let differences = pair_differences(&img, &[(0, 1), (3, 2)]);
+        assert_eq!(differences.shape(), &[2, 1, 1, 2]);
+        assert_eq!(differences[[0, 0, 0, 0]], 10.0);
+        assert_eq!(differences[[1, 0, 0, 1]], -50.0);
+    }
+}
@@ -0,0 +1,947 @@
+//! Command line arguments shared by the slicenii front end and the combinenii shim.
+//!
+//! Axis, padding and compression are parsed the same way by every subcommand: an axis is
+//! `x`, `y`, `z`, `t` (or `0` to `3`, following the NIfTI dimension order) or `guess`.
+use clap::{Args, Subcommand, ValueEnum};
+use nifti::Extension;
+use std::path::PathBuf;
+
+use crate::common::Direction;
+use crate::naming::{parse_dir_template, parse_file_template, NameTemplate};
+use crate::plan::Shard;
+use crate::provenance::is_provenance;
+
+/// AxisChoice is either a fixed axis or a request for slicenii to guess one.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AxisChoice {
+    Guess,
+    Fixed(Direction),
+}
+
+impl AxisChoice {
+    /// Resolve the choice, using `guessed` when no axis was given.
+    pub fn resolve(&self, guessed: &Direction) -> Direction {
+        match self {
+            AxisChoice::Guess => guessed.clone(),
+            AxisChoice::Fixed(axis) => axis.clone(),
+        }
+    }
+}
+
+/// Parse an axis argument: `x`/`0`, `y`/`1`, `z`/`2`, `t`/`3` or `guess`.
+pub fn parse_axis(s: &str) -> Result<AxisChoice, String> {
+    match s.to_ascii_lowercase().as_str() {
+        "0" | "x" => Ok(AxisChoice::Fixed(Direction::X)),
+        "1" | "y" => Ok(AxisChoice::Fixed(Direction::Y)),
+        "2" | "z" => Ok(AxisChoice::Fixed(Direction::Z)),
+        "3" | "t" | "time" => Ok(AxisChoice::Fixed(Direction::T)),
+        // combinenii numbered the guess 4
+        "4" | "guess" | "auto" => Ok(AxisChoice::Guess),
+        _ => Err(format!(
+            "'{}' is not an axis, use x, y, z, t (or 0-3) or guess",
+            s
+        )),
+    }
+}
+
+/// Parse a shard argument: `i/N`, the `i`th of `N` parts, counting from 1.
+pub fn parse_shard(s: &str) -> Result<Shard, String> {
+    let err = || format!("'{}' is not a shard, use i/N with 1 <= i <= N", s);
+    let (index, count) = s.split_once('/').ok_or_else(err)?;
+    let index: usize = index.trim().parse().map_err(|_| err())?;
+    let count: usize = count.trim().parse().map_err(|_| err())?;
+    if index == 0 || index > count {
+        return Err(err());
+    }
+    Ok(Shard { index, count })
+}
+
+/// PacketChoice is either a fixed number of packets or a request to read it from the header.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PacketChoice {
+    Auto,
+    Count(usize),
+}
+
+/// Parse a packets argument: a number of packets of at least 1, or `auto`.
+pub fn parse_packets(s: &str) -> Result<PacketChoice, String> {
+    if s.eq_ignore_ascii_case("auto") {
+        return Ok(PacketChoice::Auto);
+    }
+    match s.trim().parse::<usize>() {
+        Ok(count) if count >= 1 => Ok(PacketChoice::Count(count)),
+        _ => Err(format!(
+            "'{}' is not a number of packets, use a number of at least 1 or auto",
+            s
+        )),
+    }
+}
+
+/// GroupBy says how to group the volumes of a series into several 4D files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupBy {
+    /// one file per echo, the number of echoes taken from `EchoTime` in the sidecar
+    Echo,
+    /// one file per position in a cycle of this many volumes
+    Cycle(usize),
+}
+
+/// Parse a group-by argument: `echo`, or the number of volumes in a cycle of at least 1.
+pub fn parse_group_by(s: &str) -> Result<GroupBy, String> {
+    if s.eq_ignore_ascii_case("echo") {
+        return Ok(GroupBy::Echo);
+    }
+    match s.trim().parse::<usize>() {
+        Ok(cycle) if cycle >= 1 => Ok(GroupBy::Cycle(cycle)),
+        _ => Err(format!(
+            "'{}' is not a way to group volumes, use echo or a number of at least 1",
+            s
+        )),
+    }
+}
+
+/// AslPattern says which volumes of an ASL series are label and which are control.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AslPattern {
+    /// a pattern of `L` and `C` repeated over the series, such as `LC`
+    Letters(String),
+    /// an `aslcontext.tsv` listing the type of every volume
+    Context(PathBuf),
+}
+
+/// Parse an ASL pattern argument: letters `L` and `C` such as `LC` or `CL`, or the path of
+/// an `aslcontext.tsv`.
+pub fn parse_asl_pattern(s: &str) -> Result<AslPattern, String> {
+    let letters = s.trim().to_ascii_uppercase();
+    if !letters.is_empty() && letters.chars().all(|c| c == 'L' || c == 'C') {
+        return Ok(AslPattern::Letters(letters));
+    }
+    if s.ends_with(".tsv") {
+        return Ok(AslPattern::Context(PathBuf::from(s)));
+    }
+    Err(format!(
+        "'{}' is not an ASL pattern, use letters L and C such as LC or CL, or an aslcontext.tsv",
+        s
+    ))
+}
+
+/// The axis argument.
+#[derive(Args, Debug, Clone)]
+pub struct AxisArg {
+    /// the axis to work along: x, y, z, t (or 0, 1, 2, 3) or guess
+    #[arg(short, long, default_value = "guess", value_parser = parse_axis)]
+    pub axis: AxisChoice,
+}
+
+/// The padding argument.
+#[derive(Args, Debug, Clone)]
+pub struct PadArg {
+    /// how many copies of the slice pad each slice volume
+    #[arg(short, long, default_value_t = 1)]
+    pub pad: usize,
+}
+
+/// The compression argument.
+#[derive(Args, Debug, Clone)]
+pub struct CompressArg {
+    /// gzip the outputs (.nii.gz)
+    #[arg(short = 'z', long)]
+    pub compress: bool,
+}
+
+/// OverwritePolicy decides what happens to outputs that already exist.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub enum OverwritePolicy {
+    /// stop with an error
+    Error,
+    /// keep the existing file
+    Skip,
+    /// replace the existing file
+    Replace,
+    /// keep the existing file only if its checksum matches the new content, else replace it
+    Verify,
+}
+
+/// The overwrite argument.
+#[derive(Args, Debug, Clone)]
+pub struct OverwriteArg {
+    /// what to do with outputs that already exist
+    #[arg(long, value_enum, default_value_t = OverwritePolicy::Error)]
+    pub overwrite: OverwritePolicy,
+}
+
+/// SliceOrder decides how slices are numbered.
+#[derive(Debug, Clone, PartialEq, ValueEnum)]
+pub enum SliceOrder {
+    /// by their position along the axis
+    Space,
+    /// by the time they were acquired, from SliceTiming in the sidecar or slice_code in the
+    /// header
+    Time,
+}
+
+/// The slice ordering arguments.
+#[derive(Args, Debug, Clone)]
+pub struct OrderArgs {
+    /// how to number the slices
+    #[arg(long, value_enum, default_value_t = SliceOrder::Space)]
+    pub order: SliceOrder,
+
+    /// with --order time, write the slices acquired together, as in multiband, to one file
+    #[arg(long)]
+    pub group_simultaneous: bool,
+}
+
+impl Default for OrderArgs {
+    fn default() -> Self {
+        Self {
+            order: SliceOrder::Space,
+            group_simultaneous: false,
+        }
+    }
+}
+
+/// The resume argument.
+#[derive(Args, Debug, Clone)]
+pub struct ResumeArg {
+    /// pick up an interrupted run from its journal, skipping outputs that are already done
+    #[arg(long)]
+    pub resume: bool,
+}
+
+/// The shard argument.
+#[derive(Args, Debug, Clone)]
+pub struct ShardArg {
+    /// only write the i-th of N equal parts of the outputs (counting from 1), for array jobs
+    #[arg(long, value_parser = parse_shard)]
+    pub shard: Option<Shard>,
+}
+
+/// The naming arguments.
+#[derive(Args, Debug, Clone)]
+pub struct NameArgs {
+    /// the name of each output file without its extension, using {stem}, {axis}, {index},
+    /// {index:04}, {pad}, {time_ms} and {mm}, e.g. "{stem}_slice-{index:04}"
+    #[arg(long, value_parser = parse_file_template)]
+    pub name_template: Option<NameTemplate>,
+
+    /// the name of the NEW directory for the outputs, using {stem}, {axis} and {pad}
+    #[arg(long, value_parser = parse_dir_template)]
+    pub dir_name: Option<NameTemplate>,
+}
+
+/// The jobs argument.
+#[derive(Args, Debug, Clone)]
+pub struct JobsArg {
+    /// how many inputs to process at once (0 for one per CPU)
+    #[arg(short, long, default_value_t = 1)]
+    pub jobs: usize,
+}
+
+/// The header check arguments for combining.
+#[derive(Args, Debug, Clone)]
+pub struct CheckArg {
+    /// fail if the voxel sizes, datatype or affine of any input do not match (the default)
+    #[arg(long, conflicts_with = "lenient")]
+    pub strict: bool,
+
+    /// only warn about inputs whose voxel sizes, datatype or affine do not match
+    #[arg(long)]
+    pub lenient: bool,
+}
+
+/// The arguments choosing which header extensions of the input are carried to the outputs.
+#[derive(Args, Debug, Clone, Default)]
+pub struct ExtensionArgs {
+    /// do not carry any header extensions of the input, such as DICOM or AFNI metadata, to
+    /// the outputs
+    #[arg(long, conflicts_with_all = ["keep_ecode", "drop_ecode"])]
+    pub drop_extensions: bool,
+
+    /// only carry the header extensions with these codes, e.g. 2 for DICOM or 4 for AFNI
+    #[arg(long, value_delimiter = ',', conflicts_with = "drop_ecode")]
+    pub keep_ecode: Vec<i32>,
+
+    /// carry every header extension except those with these codes
+    #[arg(long, value_delimiter = ',')]
+    pub drop_ecode: Vec<i32>,
+}
+
+impl ExtensionArgs {
+    /// The extensions of an input that are carried to its outputs.
+    ///
+    /// The provenance of an input written by slicenii is never carried, as the outputs get
+    /// their own.
+    pub fn select(&self, extensions: &[Extension]) -> Vec<Extension> {
+        if self.drop_extensions {
+            return Vec::new();
+        }
+        extensions
+            .iter()
+            .filter(|e| self.keep_ecode.is_empty() || self.keep_ecode.contains(&e.code()))
+            .filter(|e| !self.drop_ecode.contains(&e.code()))
+            .filter(|e| !is_provenance(e))
+            .cloned()
+            .collect()
+    }
+
+    /// Describe which extensions are carried, such as `all` or `only 2,4`.
+    pub fn describe(&self) -> String {
+        let codes = |codes: &[i32]| {
+            codes
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<String>>()
+                .join(",")
+        };
+        if self.drop_extensions {
+            "none".to_string()
+        } else if !self.keep_ecode.is_empty() {
+            format!("only {}", codes(&self.keep_ecode))
+        } else if !self.drop_ecode.is_empty() {
+            format!("all but {}", codes(&self.drop_ecode))
+        } else {
+            "all".to_string()
+        }
+    }
+}
+
+/// Arguments for slicing a 3D volume into 2D slices.
+#[derive(Args, Debug, Clone)]
+pub struct SliceArgs {
+    /// the input nifti file
+    #[arg(short, long)]
+    pub input: String,
+
+    /// an output path where a NEW directory will be created to store the slices
+    #[arg(short, long, default_value = "./")]
+    pub output: String,
+
+    #[command(flatten)]
+    pub axis: AxisArg,
+
+    #[command(flatten)]
+    pub pad: PadArg,
+
+    #[command(flatten)]
+    pub order: OrderArgs,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    #[command(flatten)]
+    pub resume: ResumeArg,
+
+    #[command(flatten)]
+    pub shard: ShardArg,
+
+    #[command(flatten)]
+    pub naming: NameArgs,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for splitting a 4D series into 3D volumes.
+#[derive(Args, Debug, Clone)]
+pub struct SplitArgs {
+    /// the input nifti file
+    #[arg(short, long)]
+    pub input: String,
+
+    /// an output path where a NEW directory will be created to store the volumes
+    #[arg(short, long, default_value = "./")]
+    pub output: String,
+
+    /// write one 4D file per echo ("echo", from the list of EchoTime in the sidecar) or per
+    /// position in a cycle of N volumes (every N-th volume) instead of one file per volume
+    #[arg(long, value_parser = parse_group_by)]
+    pub group_by: Option<GroupBy>,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    #[command(flatten)]
+    pub resume: ResumeArg,
+
+    #[command(flatten)]
+    pub shard: ShardArg,
+
+    #[command(flatten)]
+    pub naming: NameArgs,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for splitting a diffusion series with its gradients.
+#[derive(Args, Debug, Clone)]
+pub struct DwiArgs {
+    /// the input 4D nifti file, with its .bval and .bvec files next to it
+    #[arg(short, long)]
+    pub input: String,
+
+    /// an output path where a NEW directory will be created to store the volumes or shells
+    #[arg(short, long, default_value = "./")]
+    pub output: String,
+
+    /// group the volumes into one 4D file per b-value shell instead of one file per volume
+    #[arg(long)]
+    pub shells: bool,
+
+    /// the largest difference between b-values in one shell, and the largest b-value taken
+    /// as b0
+    #[arg(long, default_value_t = 50.0)]
+    pub shell_tolerance: f64,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    #[command(flatten)]
+    pub resume: ResumeArg,
+
+    #[command(flatten)]
+    pub naming: NameArgs,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for splitting an ASL series into its label and control volumes.
+#[derive(Args, Debug, Clone)]
+pub struct AslArgs {
+    /// the input 4D ASL series
+    #[arg(short, long)]
+    pub input: String,
+
+    /// an output path where a NEW directory will be created to store the label and control
+    /// series
+    #[arg(short, long, default_value = "./")]
+    pub output: String,
+
+    /// the order of the label and control volumes: a pattern of L and C repeated over the
+    /// series, such as LC or CL, or an aslcontext.tsv; by default the aslcontext.tsv next to
+    /// the input
+    #[arg(long, value_parser = parse_asl_pattern)]
+    pub pattern: Option<AslPattern>,
+
+    /// also write the difference of each label/control pair, control minus label
+    #[arg(long)]
+    pub difference: bool,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    #[command(flatten)]
+    pub naming: NameArgs,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for splitting a volume into its interleaved acquisition packets.
+#[derive(Args, Debug, Clone)]
+pub struct PacketArgs {
+    /// the input 3D nifti file
+    #[arg(short, long)]
+    pub input: String,
+
+    /// an output path where a NEW directory will be created to store the packets
+    #[arg(short, long, default_value = "./")]
+    pub output: String,
+
+    #[command(flatten)]
+    pub axis: AxisArg,
+
+    /// how many packets the slices were interleaved into, or auto to read it from the
+    /// slice_code of the header
+    #[arg(long, default_value = "auto", value_parser = parse_packets)]
+    pub packets: PacketChoice,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    #[command(flatten)]
+    pub naming: NameArgs,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for preparing b0 volumes for TOPUP.
+#[derive(Args, Debug, Clone)]
+pub struct TopupArgs {
+    /// the series acquired with one phase encoding direction, such as AP
+    #[arg(long)]
+    pub ap: String,
+
+    /// the series acquired with the opposite phase encoding direction, such as PA
+    #[arg(long)]
+    pub pa: String,
+
+    /// the volumes to take from --ap, counting from 1; by default its b0 volumes by its .bval
+    #[arg(long, value_delimiter = ',')]
+    pub ap_volumes: Vec<usize>,
+
+    /// the volumes to take from --pa, counting from 1; by default its b0 volumes by its .bval
+    #[arg(long, value_delimiter = ',')]
+    pub pa_volumes: Vec<usize>,
+
+    /// the largest b-value taken as b0
+    #[arg(long, default_value_t = 50.0)]
+    pub b0_threshold: f64,
+
+    /// crop odd dimensions by one plane instead of padding them
+    #[arg(long)]
+    pub crop: bool,
+
+    /// the total readout time in seconds, for series whose sidecar has no TotalReadoutTime
+    #[arg(long)]
+    pub readout_time: Option<f64>,
+
+    /// the directory to write the b0 series, acqparams.txt and the grid record to
+    #[arg(short, long, default_value = "./")]
+    pub output: String,
+
+    /// the name of the b0 series without its extension
+    #[arg(long, default_value = "topup_b0")]
+    pub name: String,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for combining slices or volumes back into one image.
+#[derive(Args, Debug, Clone)]
+pub struct CombineArgs {
+    /// the input directories containing the nifti files, such as the output directories of
+    /// several shards
+    #[arg(short, long, num_args = 1.., default_value = "./")]
+    pub input_dir: Vec<String>,
+
+    /// the name of the output nifti file
+    #[arg(short, long, default_value = "combined.nii")]
+    pub output: String,
+
+    /// the original nifti file (required for reference)
+    #[arg(short, long)]
+    pub reference: String,
+
+    #[command(flatten)]
+    pub axis: AxisArg,
+
+    #[command(flatten)]
+    pub check: CheckArg,
+
+    /// a string to select nifti files in the input directory based on the start of
+    /// their file names
+    #[arg(short, long, default_value = "")]
+    pub start_string: String,
+
+    /// the template the files were named with, to read their indices from (see slice
+    /// --name-template); by default the last number in each name is used
+    #[arg(long, value_parser = parse_file_template)]
+    pub name_template: Option<NameTemplate>,
+
+    /// a text file (or - for stdin) listing the files to combine in order, one per line,
+    /// optionally as "<index><TAB><path>" with indices counting from 1; replaces -i and -s
+    #[arg(short = 'f', long, conflicts_with_all = ["input_dir", "start_string", "name_template"])]
+    pub file_list: Option<String>,
+
+    /// place each slice by the affine in its header instead of by its file name, finding
+    /// the axis and position in the reference grid; slices off the grid are rejected
+    #[arg(long)]
+    pub by_affine: bool,
+
+    /// place each slice or volume by the provenance slicenii recorded in its header instead
+    /// of by its file name
+    #[arg(long, conflicts_with = "by_affine")]
+    pub by_header: bool,
+
+    /// order volumes by the onset in their toffset instead of by their file names
+    #[arg(long, conflicts_with_all = ["by_affine", "by_header"])]
+    pub by_toffset: bool,
+
+    /// merge the shell files written by split-dwi --shells back into one series in the
+    /// original order, using the table next to them
+    #[arg(long, conflicts_with_all = ["by_affine", "by_header", "by_toffset", "file_list"])]
+    pub shells: bool,
+
+    /// re-interleave the packets written by slicenii packets into the full grid, placing
+    /// each by its affine
+    #[arg(long, conflicts_with_all = ["by_affine", "by_header", "by_toffset", "shells"])]
+    pub packets: bool,
+
+    /// put slices written with slice --order time back in spatial order, using the slice
+    /// order table next to them
+    #[arg(long, conflicts_with_all = ["by_affine", "by_header", "by_toffset", "shells", "packets", "file_list"])]
+    pub slice_order: bool,
+
+    /// interleave the files written by split-time --group-by back into one series in the
+    /// original order, using the table next to them
+    #[arg(long, conflicts_with_all = ["by_affine", "by_header", "by_toffset", "shells", "packets", "slice_order", "file_list"])]
+    pub groups: bool,
+
+    /// interleave the label and control series written by split-asl back into one series
+    /// in the original order, using the table next to them
+    #[arg(long, conflicts_with_all = ["by_affine", "by_header", "by_toffset", "shells", "packets", "slice_order", "groups", "file_list"])]
+    pub asl: bool,
+
+    /// the grid record written by topup, to put the combined image back on the grid of the
+    /// series before it was padded or cropped
+    #[arg(long, conflicts_with_all = ["shells", "packets", "slice_order", "groups", "asl"])]
+    pub topup_grid: Option<String>,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    #[command(flatten)]
+    pub resume: ResumeArg,
+
+    /// how many times to save the partly combined image while combining, so --resume can
+    /// skip the inputs already placed (4 by default, 0 to never save it)
+    #[arg(long, requires = "resume")]
+    pub checkpoints: Option<usize>,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl CombineArgs {
+    /// Combine the files in `input_dir` into `output`, with every other argument at its
+    /// default.
+    pub fn new(input_dir: &str, output: &str, reference: &str) -> Self {
+        Self {
+            input_dir: vec![input_dir.to_string()],
+            output: output.to_string(),
+            reference: reference.to_string(),
+            axis: AxisArg {
+                axis: AxisChoice::Guess,
+            },
+            check: CheckArg {
+                strict: false,
+                lenient: false,
+            },
+            start_string: String::new(),
+            name_template: None,
+            file_list: None,
+            by_affine: false,
+            by_header: false,
+            by_toffset: false,
+            shells: false,
+            packets: false,
+            slice_order: false,
+            groups: false,
+            asl: false,
+            topup_grid: None,
+            compress: CompressArg { compress: false },
+            extensions: ExtensionArgs::default(),
+            overwrite: OverwriteArg {
+                overwrite: OverwritePolicy::Error,
+            },
+            resume: ResumeArg { resume: false },
+            checkpoints: None,
+            dry_run: false,
+        }
+    }
+}
+
+/// Arguments for slicing or splitting many files at once.
+#[derive(Args, Debug, Clone)]
+pub struct BatchArgs {
+    /// the input nifti files, directories of nifti files or glob patterns
+    #[arg(short, long, num_args = 1.., required = true)]
+    pub input: Vec<String>,
+
+    /// an output path where a NEW directory will be created for each input
+    #[arg(short, long, default_value = "./")]
+    pub output: String,
+
+    #[command(flatten)]
+    pub axis: AxisArg,
+
+    #[command(flatten)]
+    pub pad: PadArg,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    #[command(flatten)]
+    pub resume: ResumeArg,
+
+    #[command(flatten)]
+    pub naming: NameArgs,
+
+    #[command(flatten)]
+    pub jobs: JobsArg,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for combining many slice or volume directories at once.
+#[derive(Args, Debug, Clone)]
+pub struct CombineBatchArgs {
+    /// the slice or volume directories written by slicenii, or glob patterns matching them
+    #[arg(short, long, num_args = 1.., required = true)]
+    pub input: Vec<String>,
+
+    /// the directories holding the original nifti files, which are matched to the input
+    /// directories by name
+    #[arg(short, long, num_args = 1.., required = true)]
+    pub reference_dir: Vec<String>,
+
+    /// the directory the combined files are written to, as {stem}_combined.nii
+    #[arg(short, long, default_value = "./")]
+    pub output: String,
+
+    #[command(flatten)]
+    pub axis: AxisArg,
+
+    #[command(flatten)]
+    pub check: CheckArg,
+
+    /// the template the files were named with, to read their indices from
+    #[arg(long, value_parser = parse_file_template)]
+    pub name_template: Option<NameTemplate>,
+
+    /// the template the directories were named with, to read the reference name from;
+    /// by default {stem}_slices and {stem}_vols are tried
+    #[arg(long, value_parser = parse_dir_template)]
+    pub dir_name: Option<NameTemplate>,
+
+    /// place each slice by the affine in its header instead of by its file name
+    #[arg(long)]
+    pub by_affine: bool,
+
+    /// place each slice or volume by the provenance slicenii recorded in its header
+    #[arg(long, conflicts_with = "by_affine")]
+    pub by_header: bool,
+
+    /// order volumes by the onset in their toffset instead of by their file names
+    #[arg(long, conflicts_with_all = ["by_affine", "by_header"])]
+    pub by_toffset: bool,
+
+    #[command(flatten)]
+    pub compress: CompressArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    #[command(flatten)]
+    pub overwrite: OverwriteArg,
+
+    #[command(flatten)]
+    pub resume: ResumeArg,
+
+    /// how many times to save each partly combined image with --resume (4 by default, 0 to
+    /// never save it)
+    #[arg(long, requires = "resume")]
+    pub checkpoints: Option<usize>,
+
+    #[command(flatten)]
+    pub jobs: JobsArg,
+
+    /// print what would be done without writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+/// Arguments for describing how slicenii would treat a file.
+#[derive(Args, Debug, Clone)]
+pub struct InfoArgs {
+    /// the input nifti files
+    #[arg(short, long, num_args = 1.., required = true)]
+    pub input: Vec<String>,
+
+    #[command(flatten)]
+    pub axis: AxisArg,
+
+    #[command(flatten)]
+    pub pad: PadArg,
+
+    #[command(flatten)]
+    pub extensions: ExtensionArgs,
+
+    /// print the information as JSON instead of text
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Arguments for checking a combined image against its reference.
+#[derive(Args, Debug, Clone)]
+pub struct VerifyArgs {
+    /// the combined nifti file to check
+    #[arg(short, long)]
+    pub input: String,
+
+    /// the reference nifti file the image was combined against
+    #[arg(short, long)]
+    pub reference: String,
+
+    /// the original nifti file the slices were cut from, to compare voxels against
+    #[arg(short, long)]
+    pub source: Option<String>,
+
+    /// the axis along which to report differing slices (4D images report volumes)
+    #[command(flatten)]
+    pub axis: AxisArg,
+
+    /// the largest allowed absolute difference between voxels
+    #[arg(short, long, default_value_t = 0.0)]
+    pub tolerance: f64,
+
+    /// the largest allowed difference between affine entries
+    #[arg(long, default_value_t = 1e-4)]
+    pub affine_tolerance: f64,
+}
+
+/// The subcommands of the slicenii front end.
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Slice a 3D volume into 2D slices
+    Slice(SliceArgs),
+    /// Split a 4D series into 3D volumes
+    SplitTime(SplitArgs),
+    /// Split a diffusion series into volumes or b-value shells, with their bval/bvec files
+    SplitDwi(DwiArgs),
+    /// Split an ASL series into label and control series, optionally with their differences
+    SplitAsl(AslArgs),
+    /// Split a volume into its interleaved acquisition packets
+    Packets(PacketArgs),
+    /// Extract, join and even out the b0 volumes of two series for TOPUP
+    Topup(TopupArgs),
+    /// Combine slices or volumes back into a single image
+    Combine(CombineArgs),
+    /// Slice or split many files, each into its own directory
+    Batch(BatchArgs),
+    /// Combine many slice or volume directories, each with its own reference
+    CombineBatch(CombineBatchArgs),
+    /// Describe how slicenii would treat a file
+    Info(InfoArgs),
+    /// Check a combined image against its reference
+    Verify(VerifyArgs),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provenance::Provenance;
+
+    fn extensions() -> Vec<Extension> {
+        let provenance = Provenance {
+            version: "0.0.0".to_string(),
+            source: None,
+            axis: Direction::Z,
+            index: 0,
+            count: 1,
+            padding: 1,
+            indices: None,
+        };
+        vec![
+            Extension::from_str(2, "dicom"),
+            Extension::from_str(4, "afni"),
+            Extension::from_str(6, "note"),
+            provenance.to_extension(),
+        ]
+    }
+
+    fn codes(args: &ExtensionArgs) -> Vec<i32> {
+        args.select(&extensions())
+            .iter()
+            .map(|e| e.code())
+            .collect()
+    }
+
+    #[test]
+    fn selects_extensions_by_code() {
+        let all = ExtensionArgs::default();
+        // the provenance of the input is never carried, but other comments are
+        assert_eq!(codes(&all), vec![2, 4, 6]);
+        assert_eq!(all.describe(), "all");
+        let none = ExtensionArgs {
+            drop_extensions: true,
+            ..Default::default()
+        };
+        assert!(codes(&none).is_empty());
+        assert_eq!(none.describe(), "none");
+        let keep = ExtensionArgs {
+            keep_ecode: vec![2, 6],
+            ..Default::default()
+        };
+        assert_eq!(codes(&keep), vec![2, 6]);
+        assert_eq!(keep.describe(), "only 2,6");
+        let drop = ExtensionArgs {
+            drop_ecode: vec![4],
+            ..Default::default()
+        };
+        assert_eq!(codes(&drop), vec![2, 6]);
+        assert_eq!(drop.describe(), "all but 4");
+    }
+    #[test]
+    fn parses_shards() {
+        assert_eq!(parse_shard("2/5"), Ok(Shard { index: 2, count: 5 }));
+        assert_eq!(parse_shard(" 1 / 1 "), Ok(Shard { index: 1, count: 1 }));
+        assert_eq!(parse_shard("3/3").unwrap().to_string(), "3/3");
+        for bad in ["0/4", "5/4", "2", "a/4", "2/b", "-1/4", "1/0"] {
+            let err = parse_shard(bad).unwrap_err();
+            assert!(err.contains("is not a shard"), "{}", err);
+        }
+    }
+}
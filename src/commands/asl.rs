@@ -0,0 +1,170 @@
+//! Split an ASL series into its label and control series, and interleave them back.
+//!
+//! Every volume type gets a 4D file of its own, with the timing of its volumes in its header,
+//! and a table next to the files records where every volume came from so `combine --asl`
+//! can put processed series back in the original order.
+use ndarray::{Array4, Axis};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::asl::{
+    aslcontext_path, pair_differences, pair_volumes, type_groups, volume_types, ASL_TABLE,
+};
+use crate::cli::{AslArgs, AslPattern, CombineArgs};
+use crate::commands::groups::{group_dir, merge_groups};
+use crate::error::{Error, Result};
+use crate::groups::{group_header, write_group_table};
+use crate::io::{
+    nifti_extension, nifti_stem, read_header, read_image, report_outcome,
+    write_image_with_extensions, WriteOutcome,
+};
+use crate::naming::ASL_DIR;
+use crate::sidecar::{for_group, read_sidecar, write_sidecar};
+
+/// The pattern given with `--pattern`, otherwise the `aslcontext.tsv` next to the series.
+fn choose_pattern(path: &Path, pattern: Option<&AslPattern>) -> Result<AslPattern> {
+    if let Some(pattern) = pattern {
+        return Ok(pattern.clone());
+    }
+    let context = aslcontext_path(path)?;
+    if !context.exists() {
+        return Err(Error::Usage(format!(
+            "Did not find {}. Give the order of the volumes with --pattern, such as LC or CL.",
+            context.display()
+        )));
+    }
+    println!("Volume types from: {}", context.display());
+    Ok(AslPattern::Context(context))
+}
+
+/// Run the `split-asl` subcommand: split an ASL series by volume type.
+pub fn run(args: &AslArgs) -> Result<()> {
+    if args.naming.name_template.is_some() {
+        return Err(Error::Usage(
+            "--name-template cannot be used with split-asl, files are named by volume type."
+                .to_string(),
+        ));
+    }
+    let path = Path::new(&args.input);
+    let header = read_header(path)?;
+    if header.dim[0] != 4 {
+        return Err(Error::Input(
+            "Input nifti file must be a 4D ASL series.".to_string(),
+        ));
+    }
+    let stem = nifti_stem(path)?;
+    let count = header.dim[4] as usize;
+    let types = volume_types(&choose_pattern(path, args.pattern.as_ref())?, count)?;
+    let groups = type_groups(&types);
+    let pairs = if args.difference {
+        Some(pair_volumes(&types)?)
+    } else {
+        None
+    };
+
+    let dir = group_dir(
+        Path::new(&args.output),
+        args.naming.dir_name.as_ref(),
+        ASL_DIR,
+        &stem,
+        count,
+    )?;
+    let extension = nifti_extension(args.compress.compress);
+    let files: Vec<PathBuf> = groups
+        .iter()
+        .map(|(kind, _)| dir.join(format!("{}_{}.{}", stem, kind, extension)))
+        .collect();
+    let difference_file = dir.join(format!("{}_deltam.{}", stem, extension));
+    for (kind, indices) in &groups {
+        println!("{}: {} volume(s)", kind, indices.len());
+    }
+    if args.dry_run {
+        for ((_, indices), file) in groups.iter().zip(&files) {
+            println!(
+                "Would write: {} with volumes {:?}",
+                file.display(),
+                indices.iter().map(|i| i + 1).collect::<Vec<usize>>()
+            );
+        }
+        if let Some(pairs) = &pairs {
+            println!(
+                "Would write: {} with {} difference(s)",
+                difference_file.display(),
+                pairs.len()
+            );
+        }
+        return Ok(());
+    }
+
+    fs::create_dir_all(&dir)?;
+    let image = read_image(path)?;
+    let img: Array4<f64> = image
+        .data
+        .into_dimensionality()
+        .map_err(|e| Error::Input(e.to_string()))?;
+    let extensions = args.extensions.select(&image.extensions);
+    let sidecar = read_sidecar(path)?;
+    let mut outputs: Vec<(&PathBuf, Vec<usize>)> = groups
+        .iter()
+        .zip(&files)
+        .map(|((_, indices), file)| (file, indices.clone()))
+        .collect();
+    if let Some(pairs) = &pairs {
+        // a pair is acquired when its first volume is
+        let firsts = pairs
+            .iter()
+            .map(|&(label, control)| label.min(control))
+            .collect();
+        outputs.push((&difference_file, firsts));
+    }
+    for (file, indices) in &outputs {
+        // each file is only built when it is written
+        let data = match &pairs {
+            Some(pairs) if *file == &difference_file => pair_differences(&img, pairs),
+            _ => img.select(Axis(3), indices),
+        };
+        let (out_header, even) = group_header(&image.header, indices);
+        if !even {
+            println!(
+                "Warning! The volumes of {} are not evenly spaced, its header only gives the onset of the first.",
+                file.display()
+            );
+        }
+        let outcome = write_image_with_extensions(
+            file,
+            &out_header,
+            &extensions,
+            &data.view(),
+            &args.overwrite.overwrite,
+        )?;
+        report_outcome(file, &outcome);
+        if outcome != WriteOutcome::Skipped {
+            if let Some(sidecar) = &sidecar {
+                write_sidecar(
+                    file,
+                    &for_group(sidecar, &image.header, indices),
+                    &args.overwrite.overwrite,
+                )?;
+            }
+        }
+    }
+
+    let table = dir.join(format!("{}{}", stem, ASL_TABLE));
+    let groups: Vec<Vec<usize>> = groups.into_iter().map(|(_, indices)| indices).collect();
+    let kind = |index: usize| types[index].clone();
+    write_group_table(
+        &table,
+        &files,
+        &groups,
+        ("volume_type", &kind),
+        &args.overwrite.overwrite,
+    )?;
+    println!("Volume type table: {}", table.display());
+    Ok(())
+}
+
+/// Run `combine --asl`: interleave the series written by `split-asl` back into one series.
+pub fn merge_asl(args: &CombineArgs) -> Result<()> {
+    merge_groups(args, ASL_TABLE, "Split with split-asl first.")?;
+    Ok(())
+}
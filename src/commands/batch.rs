@@ -0,0 +1,381 @@
+//! Slice, split or combine many inputs in one invocation.
+//!
+//! `batch` expands its inputs (files, directories of NIfTI files and glob patterns) and slices
+//! or splits each one into its own directory, as `slicenii -i` would. `combine-batch` combines
+//! many slice or volume directories, pairing each with the reference it was cut from by name.
+//! Inputs are processed `--jobs` at a time, and a failed input does not stop the others: the
+//! failures are listed at the end and the command exits with [`crate::error::EXIT_BATCH`].
+use glob::glob;
+use rayon::prelude::*;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{BatchArgs, CombineArgs, CombineBatchArgs, OrderArgs, ShardArg, SliceArgs};
+use crate::commands::{combine, slice};
+use crate::error::{Error, Result};
+use crate::io::{is_nifti, nifti_stem};
+use crate::naming::{parse_template, NameTemplate, SLICE_DIR, VOL_DIR};
+
+/// Failure is an input of a batch that could not be processed.
+#[derive(Debug)]
+pub struct Failure {
+    /// The input as it was given or found.
+    pub input: String,
+    pub error: Error,
+}
+
+/// Whether a path names a hidden file, such as a temporary file or journal.
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .is_some_and(|n| n.starts_with('.'))
+}
+
+/// Expand files, directories and glob patterns into the NIfTI files they name.
+///
+/// Directories contribute the NIfTI files directly inside them, in name order. Arguments that
+/// name nothing become failures, so a typo in one pattern does not stop the rest.
+///
+/// # Arguments
+///
+/// * `inputs` - The arguments given on the command line.
+///
+/// # Returns
+///
+/// The files found, without duplicates, and the arguments that matched nothing.
+pub fn expand_inputs(inputs: &[String]) -> (Vec<PathBuf>, Vec<Failure>) {
+    let mut files = Vec::new();
+    let mut failures = Vec::new();
+    for input in inputs {
+        let path = Path::new(input);
+        let mut found: Vec<PathBuf> = if path.is_dir() {
+            match fs::read_dir(path) {
+                Ok(entries) => entries
+                    .filter_map(|entry| entry.ok().map(|e| e.path()))
+                    .filter(|p| p.is_file() && is_nifti(p) && !is_hidden(p))
+                    .collect(),
+                Err(e) => {
+                    failures.push(Failure {
+                        input: input.clone(),
+                        error: e.into(),
+                    });
+                    continue;
+                }
+            }
+        } else if path.exists() {
+            vec![path.to_path_buf()]
+        } else {
+            match glob(input) {
+                Ok(paths) => paths
+                    .filter_map(std::result::Result::ok)
+                    .filter(|p| p.is_file() && is_nifti(p))
+                    .collect(),
+                Err(e) => {
+                    failures.push(Failure {
+                        input: input.clone(),
+                        error: Error::Usage(e.to_string()),
+                    });
+                    continue;
+                }
+            }
+        };
+        if found.is_empty() {
+            failures.push(Failure {
+                input: input.clone(),
+                error: Error::Input("Did not find any NIfTI files.".to_string()),
+            });
+        }
+        found.sort();
+        for file in found {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+    (files, failures)
+}
+
+/// Run `f` on every item, `jobs` at a time (0 for one per CPU), collecting the results.
+fn run_all<T, F>(items: &[T], jobs: usize, f: F) -> Result<Vec<Result<()>>>
+where
+    T: Sync,
+    F: Fn(&T) -> Result<()> + Sync + Send,
+{
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(jobs)
+        .build()
+        .map_err(|e| Error::Usage(format!("Could not start {} jobs: {}", jobs, e)))?;
+    Ok(pool.install(|| items.par_iter().map(&f).collect()))
+}
+
+/// Print a summary of a batch and turn its failures into an error.
+///
+/// # Arguments
+///
+/// * `names` - The name of each processed input.
+/// * `results` - The result for each processed input, in the same order.
+/// * `failures` - The inputs that failed before they could be processed.
+pub fn summarise(
+    names: &[String],
+    results: Vec<Result<()>>,
+    mut failures: Vec<Failure>,
+) -> Result<()> {
+    let mut succeeded = 0;
+    for (name, result) in names.iter().zip(results) {
+        match result {
+            Ok(()) => succeeded += 1,
+            Err(error) => failures.push(Failure {
+                input: name.clone(),
+                error,
+            }),
+        }
+    }
+    let total = succeeded + failures.len();
+    println!(
+        "Batch finished: {} of {} input(s) succeeded, {} failed.",
+        succeeded,
+        total,
+        failures.len()
+    );
+    for failure in &failures {
+        eprintln!("Failed: {}: {}", failure.input, failure.error);
+    }
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Batch(format!(
+            "{} of {} input(s) failed.",
+            failures.len(),
+            total
+        )))
+    }
+}
+
+/// Keep the inputs whose stem no other input shares, as those would write to the same outputs.
+///
+/// # Arguments
+///
+/// * `named` - Each input with its stem.
+/// * `path` - The path of an input, to name it in its failure.
+/// * `failures` - Where the inputs that share a stem are added.
+///
+/// # Returns
+///
+/// The inputs with a stem of their own, in stem order.
+pub fn unique_stems<T>(
+    named: Vec<(String, T)>,
+    path: impl Fn(&T) -> PathBuf,
+    failures: &mut Vec<Failure>,
+) -> Vec<(String, T)> {
+    let mut by_stem: BTreeMap<String, Vec<T>> = BTreeMap::new();
+    for (stem, item) in named {
+        by_stem.entry(stem).or_default().push(item);
+    }
+    let mut unique = Vec::new();
+    for (stem, items) in by_stem {
+        if items.len() > 1 {
+            for item in &items {
+                failures.push(Failure {
+                    input: path(item).display().to_string(),
+                    error: Error::Usage(format!(
+                        "{} inputs are named {}, so their outputs would collide.",
+                        items.len(),
+                        stem
+                    )),
+                });
+            }
+        } else {
+            unique.extend(items.into_iter().map(|item| (stem.clone(), item)));
+        }
+    }
+    unique
+}
+
+/// Run the `batch` subcommand: slice 3D files and split 4D files, each into its own directory.
+pub fn run(args: &BatchArgs) -> Result<()> {
+    if let Some(dir) = &args.naming.dir_name {
+        if !dir.has_stem() {
+            return Err(Error::Usage(
+                "--dir-name must contain {stem} in a batch, so every input gets its own directory."
+                    .to_string(),
+            ));
+        }
+    }
+    let (files, mut failures) = expand_inputs(&args.input);
+
+    // inputs with the same name would write to the same directory
+    let mut named = Vec::new();
+    for file in files {
+        match nifti_stem(&file) {
+            Ok(stem) => named.push((stem, file)),
+            Err(error) => failures.push(Failure {
+                input: file.display().to_string(),
+                error,
+            }),
+        }
+    }
+    let files: Vec<PathBuf> = unique_stems(named, |file| file.clone(), &mut failures)
+        .into_iter()
+        .map(|(_, file)| file)
+        .collect();
+
+    println!("Batch of {} input(s)", files.len());
+    let results = run_all(&files, args.jobs.jobs, |file| {
+        let slice_args = SliceArgs {
+            input: file.display().to_string(),
+            output: args.output.clone(),
+            axis: args.axis.clone(),
+            pad: args.pad.clone(),
+            order: OrderArgs::default(),
+            compress: args.compress.clone(),
+            extensions: args.extensions.clone(),
+            overwrite: args.overwrite.clone(),
+            resume: args.resume.clone(),
+            shard: ShardArg { shard: None },
+            naming: args.naming.clone(),
+            dry_run: args.dry_run,
+        };
+        slice::run_auto(&slice_args)
+    })?;
+    let names: Vec<String> = files.iter().map(|f| f.display().to_string()).collect();
+    summarise(&names, results, failures)
+}
+
+/// Find the reference for a slice or volume directory.
+///
+/// The stem is read from the directory name with the first template that fits, and the
+/// reference is `<stem>.nii` or `<stem>.nii.gz` in the first reference directory that has it.
+///
+/// # Returns
+///
+/// The stem and the path of the reference.
+pub fn find_reference(
+    dir: &Path,
+    templates: &[NameTemplate],
+    reference_dirs: &[String],
+) -> Result<(String, PathBuf)> {
+    let name = dir
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::Input(format!("Could not parse directory name {}", dir.display())))?;
+    let stem = templates
+        .iter()
+        .find_map(|t| t.parse_stem(name))
+        .ok_or_else(|| {
+            Error::Input(format!(
+                "{} does not fit the directory name template, so its reference is not known.",
+                name
+            ))
+        })?;
+    for reference_dir in reference_dirs {
+        for ext in ["nii", "nii.gz"] {
+            let candidate = Path::new(reference_dir).join(format!("{}.{}", stem, ext));
+            if candidate.is_file() {
+                return Ok((stem, candidate));
+            }
+        }
+    }
+    Err(Error::Input(format!(
+        "Did not find {}.nii or {}.nii.gz in the reference directories.",
+        stem, stem
+    )))
+}
+
+/// Run the `combine-batch` subcommand: combine each slice or volume directory against its
+/// reference into `<output>/<stem>_combined.nii`.
+pub fn run_combine(args: &CombineBatchArgs) -> Result<()> {
+    let templates = match &args.dir_name {
+        Some(template) => vec![template.clone()],
+        None => vec![
+            parse_template(SLICE_DIR).expect("default template"),
+            parse_template(VOL_DIR).expect("default template"),
+        ],
+    };
+
+    let mut dirs = Vec::new();
+    let mut failures = Vec::new();
+    for input in &args.input {
+        let path = Path::new(input);
+        let found: Vec<PathBuf> = if path.is_dir() {
+            vec![path.to_path_buf()]
+        } else {
+            match glob(input) {
+                Ok(paths) => paths
+                    .filter_map(std::result::Result::ok)
+                    .filter(|p| p.is_dir())
+                    .collect(),
+                Err(e) => {
+                    failures.push(Failure {
+                        input: input.clone(),
+                        error: Error::Usage(e.to_string()),
+                    });
+                    continue;
+                }
+            }
+        };
+        if found.is_empty() {
+            failures.push(Failure {
+                input: input.clone(),
+                error: Error::Input("Did not find any directories.".to_string()),
+            });
+        }
+        for dir in found {
+            if !dirs.contains(&dir) {
+                dirs.push(dir);
+            }
+        }
+    }
+
+    let mut paired = Vec::new();
+    for dir in dirs {
+        match find_reference(&dir, &templates, &args.reference_dir) {
+            Ok((stem, reference)) => {
+                println!("Pairing {} with {}", dir.display(), reference.display());
+                paired.push((stem, (dir, reference)));
+            }
+            Err(error) => failures.push(Failure {
+                input: dir.display().to_string(),
+                error,
+            }),
+        }
+    }
+    // directories of the same stem would be combined into the same file
+    let jobs: Vec<(PathBuf, String, PathBuf)> =
+        unique_stems(paired, |(dir, _)| dir.clone(), &mut failures)
+            .into_iter()
+            .map(|(stem, (dir, reference))| (dir, stem, reference))
+            .collect();
+    if !args.dry_run {
+        fs::create_dir_all(&args.output)?;
+    }
+
+    let results = run_all(&jobs, args.jobs.jobs, |(dir, stem, reference)| {
+        let output = Path::new(&args.output).join(format!("{}_combined.nii", stem));
+        let combine_args = CombineArgs {
+            axis: args.axis.clone(),
+            check: args.check.clone(),
+            name_template: args.name_template.clone(),
+            by_affine: args.by_affine,
+            by_header: args.by_header,
+            by_toffset: args.by_toffset,
+            compress: args.compress.clone(),
+            extensions: args.extensions.clone(),
+            overwrite: args.overwrite.clone(),
+            resume: args.resume.clone(),
+            checkpoints: args.checkpoints,
+            dry_run: args.dry_run,
+            ..CombineArgs::new(
+                &dir.display().to_string(),
+                &output.display().to_string(),
+                &reference.display().to_string(),
+            )
+        };
+        combine::run(&combine_args)
+    })?;
+    let names: Vec<String> = jobs
+        .iter()
+        .map(|(d, _, _)| d.display().to_string())
+        .collect();
+    summarise(&names, results, failures)
+}
@@ -0,0 +1,1089 @@
+//! Combine a series of nifti files back into a single volume.
+//!
+//! Slices are put back into a 3D volume along the axis they were cut from, and 3D volumes are
+//! stacked into a 4D series. The reference image supplies the header and the expected shape.
+use glob::{glob_with, MatchOptions, Pattern};
+use ndarray::prelude::*;
+use ndarray::{Array3, Ix3};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{AxisChoice, CombineArgs, OverwritePolicy};
+use crate::commands::asl::merge_asl;
+use crate::commands::dwi::merge_shells;
+use crate::commands::groups::combine_groups;
+use crate::commands::packets::combine_packets;
+use crate::common::{ChunkMeta, Direction, Slice3D, SourceInfo};
+use crate::dwi::gather_gradients;
+use crate::error::{Error, Result};
+use crate::io::{
+    read_extensions, read_header, read_image, report_outcome, with_nifti_extension, write_image,
+    write_image_with_extensions, WriteOutcome,
+};
+use crate::journal::{Fingerprint, Journal, JournalEntry};
+use crate::naming::NameTemplate;
+use crate::ordering::{read_order_table, ORDER_TABLE};
+use crate::placement::{place_by_provenance, place_by_toffset, place_inputs};
+use crate::sidecar::{for_combined, read_sidecar, write_sidecar};
+use crate::topup::EvenGrid;
+use crate::validate::check_inputs;
+
+/// How many times the partly combined image is saved with `--resume`, unless `--checkpoints`
+/// says otherwise.
+const DEFAULT_CHECKPOINTS: usize = 4;
+
+/// InputFile is a file matched for combining.
+#[derive(Debug, Clone)]
+pub struct InputFile {
+    /// The path of the file.
+    pub path: PathBuf,
+    /// The number parsed from the file name, if it has one.
+    pub number: Option<u128>,
+    /// The 0-based index the file will be placed at.
+    pub index: usize,
+}
+
+/// Find the files matching `patterns` and put them in the order they will be combined.
+///
+/// Hidden files are left out.
+///
+/// Files are sorted by the number in their name, then by path, and each is given the next
+/// index in that order. The number is read with `template` if one is given, and files that do
+/// not fit it are left out; otherwise it is the last number in the name (see
+/// `extract_number_from_filename`).
+///
+/// # Arguments
+///
+/// * `patterns` - The glob patterns that select the files.
+/// * `template` - The template the files were named with, if known.
+///
+/// # Returns
+///
+/// The matched files in combining order.
+pub fn resolve_inputs(
+    patterns: &[String],
+    template: Option<&NameTemplate>,
+) -> Result<Vec<InputFile>> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    for pattern in patterns {
+        // hidden files are the temporary files and checkpoints of other runs
+        let options = MatchOptions {
+            require_literal_leading_dot: true,
+            ..MatchOptions::new()
+        };
+        let matches = glob_with(pattern, options).map_err(|e| Error::Usage(e.to_string()))?;
+        paths.extend(matches.filter_map(std::result::Result::ok));
+    }
+    paths.sort();
+    paths.dedup();
+    let mut numbered: Vec<(Option<u128>, PathBuf)> = Vec::new();
+    for path in paths {
+        match template {
+            Some(template) => {
+                let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+                match template.parse_index(name) {
+                    Some(number) => numbered.push((Some(number), path)),
+                    None => println!(
+                        "Warning! {} does not match the name template, leaving it out.",
+                        path.display()
+                    ),
+                }
+            }
+            None => numbered.push((extract_number_from_filename(&path), path)),
+        }
+    }
+    numbered.sort();
+    Ok(numbered
+        .into_iter()
+        .enumerate()
+        .map(|(index, (number, path))| InputFile {
+            path,
+            number,
+            index,
+        })
+        .collect())
+}
+
+/// Load one input file as a 3D chunk placed at the index of its `InputFile`.
+///
+/// The axis of the chunk is not known until the files are compared with the reference, so it
+/// is left unset and given to [`insert_chunk`] instead.
+pub fn load_slice(input: &InputFile) -> Result<Slice3D> {
+    let path = &input.path;
+    let index = input.index;
+    println!("Loading: {} to index: {}", path.display(), index);
+    let image = read_image(path)?;
+    let slice = image
+        .data
+        .into_dimensionality::<Ix3>()
+        .map_err(|e| Error::Input(format!("{} is not 3D: {}", path.display(), e)))?;
+    let header = &image.header;
+    let mut meta = ChunkMeta::new(None, index..index + 1);
+    meta.affine = header.affine::<f64>();
+    meta.toffset = header.toffset;
+    meta.source = Some(SourceInfo::from_header(header, Some(path.clone())));
+    Ok(Slice3D::new(slice, index).with_meta(meta))
+}
+
+/// Parse the slice or volume number from a file name.
+///
+/// This is the last run of digits in the name, which is where slicenii puts the index
+/// (`..._slice-012.nii`, `..._vol-003.nii.gz`), so digits earlier in the name such as a
+/// subject or axis number do not affect the order.
+pub fn extract_number_from_filename(path: &Path) -> Option<u128> {
+    let filename = path.file_name()?.to_str()?;
+    let mut number_str = String::new();
+
+    // Iterate through the characters of the filename, keeping the last run of digits
+    let mut in_run = false;
+    for ch in filename.chars() {
+        if ch.is_ascii_digit() {
+            if !in_run {
+                number_str.clear();
+                in_run = true;
+            }
+            number_str.push(ch);
+        } else {
+            in_run = false;
+        }
+    }
+
+    // Parse the collected digits as a number
+    number_str.parse::<u128>().ok()
+}
+
+/// Guess the axis the slices were cut along by comparing their shape with the reference.
+///
+/// The dimension that is smaller in the slice than in the reference is the slice direction.
+/// If no dimension is smaller, the files are taken to be volumes of a time series.
+pub fn guess_dir(slice_dims: &[usize], ref_dims: &[usize]) -> Direction {
+    // dimension that is smaller in the slice than the reference image should be the direction
+    let mut scores = [0, 0, 0, 0];
+    for i in 0..3 {
+        if slice_dims[i] < ref_dims[i] {
+            scores[i] += 1;
+        }
+    }
+
+    match scores.iter().enumerate().max_by_key(|&(_, score)| score) {
+        Some((2, _)) => Direction::Z,
+        Some((1, _)) => Direction::Y,
+        Some((0, _)) => Direction::X,
+        _ => Direction::T,
+    }
+}
+
+/// Put one slice or volume into the combined image.
+///
+/// Slices contribute their middle plane along `axis` (they may be padded) and volumes are
+/// placed whole, at the index of the chunk along `axis`.
+///
+/// # Arguments
+///
+/// * `combined` - The image being combined, 3D for slices or 4D for volumes.
+/// * `chunk` - The slice or volume to insert.
+/// * `axis` - The axis the image was cut along.
+pub fn insert_chunk(combined: &mut ArrayD<f64>, chunk: &Slice3D, axis: &Direction) -> Result<()> {
+    let a = axis.to_usize();
+    let (kind, plane) = if *axis == Direction::T {
+        ("Volume", chunk.slice.view().into_dyn())
+    } else {
+        // the middle plane, assuming padded slices
+        let mid_index = chunk.slice.shape()[a] / 2;
+        (
+            "Slice",
+            chunk.slice.index_axis(Axis(a), mid_index).into_dyn(),
+        )
+    };
+    if chunk.index() >= combined.shape()[a] {
+        return Err(Error::Input(format!(
+            "{} {} is outside the combined image.",
+            kind,
+            chunk.index() + 1
+        )));
+    }
+    let mut target = combined.index_axis_mut(Axis(a), chunk.index());
+    if target.shape() != plane.shape() {
+        return Err(Error::Input(format!(
+            "{} {} has shape {:?}, expected {:?}.",
+            kind,
+            chunk.index() + 1,
+            plane.shape(),
+            target.shape()
+        )));
+    }
+    target.assign(&plane);
+    Ok(())
+}
+
+/// Check that the inputs cover every index exactly once, judging by the numbers in their names.
+///
+/// This is used when combining the outputs of several shards, where a missing shard or one
+/// written twice would otherwise only show up as a wrong number of files.
+///
+/// # Arguments
+///
+/// * `inputs` - The resolved input files.
+/// * `expected` - How many indices the inputs should cover, if known from the reference.
+///
+/// # Returns
+///
+/// An input error listing the files without a number, the numbers found more than once and
+/// the numbers missing, if there are any.
+pub fn check_coverage(inputs: &[InputFile], expected: Option<usize>) -> Result<()> {
+    let mut problems = Vec::new();
+    let unnumbered: Vec<String> = inputs
+        .iter()
+        .filter(|input| input.number.is_none())
+        .map(|input| input.path.display().to_string())
+        .collect();
+    if !unnumbered.is_empty() {
+        problems.push(format!("no number in {}", unnumbered.join(", ")));
+    }
+
+    let mut seen: BTreeMap<u128, Vec<&Path>> = BTreeMap::new();
+    for input in inputs {
+        if let Some(number) = input.number {
+            seen.entry(number).or_default().push(&input.path);
+        }
+    }
+    for (number, paths) in &seen {
+        if paths.len() > 1 {
+            let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+            problems.push(format!(
+                "{} is covered more than once: {}",
+                number,
+                paths.join(", ")
+            ));
+        }
+    }
+
+    // slicenii numbers from 1, but numbering from 0 is accepted
+    if let Some(&first) = seen.keys().next() {
+        let first = first.min(1);
+        let count = expected.unwrap_or(seen.len()) as u128;
+        let missing: Vec<String> = (first..first + count)
+            .filter(|n| !seen.contains_key(n))
+            .map(|n| n.to_string())
+            .collect();
+        if !missing.is_empty() {
+            problems.push(format!("missing {}", missing.join(", ")));
+        }
+        let extra: Vec<String> = seen
+            .keys()
+            .filter(|&&n| n >= first + count)
+            .map(|n| n.to_string())
+            .collect();
+        if !extra.is_empty() {
+            problems.push(format!("out of range {}", extra.join(", ")));
+        }
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Input(format!(
+            "The inputs do not cover every index exactly once: {}.",
+            problems.join("; ")
+        )))
+    }
+}
+
+/// Check that no two inputs would be placed at the same index, where the later one would
+/// silently overwrite the earlier one.
+///
+/// # Returns
+///
+/// An input error listing the indices given to more than one file, if there are any.
+pub fn check_unique_indices(inputs: &[InputFile]) -> Result<()> {
+    let mut placed: BTreeMap<usize, Vec<&Path>> = BTreeMap::new();
+    for input in inputs {
+        placed.entry(input.index).or_default().push(&input.path);
+    }
+    let clashes: Vec<String> = placed
+        .iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(index, paths)| {
+            let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+            format!("{} ({})", index + 1, paths.join(", "))
+        })
+        .collect();
+    if clashes.is_empty() {
+        Ok(())
+    } else {
+        Err(Error::Input(format!(
+            "More than one input would be placed at index {}.",
+            clashes.join("; ")
+        )))
+    }
+}
+
+/// The glob patterns that select the input files: `.nii` and `.nii.gz` files in `input_dir`
+/// whose names start with `start_string`.
+pub fn input_patterns(input_dir: &Path, start_string: &str) -> Vec<String> {
+    let dir = Pattern::escape(&input_dir.display().to_string());
+    // older versions defaulted to "*", which is the same as no start string
+    let start = Pattern::escape(start_string.trim_end_matches('*'));
+    vec![
+        format!("{}/{}*.nii", dir, start),
+        format!("{}/{}*.nii.gz", dir, start),
+    ]
+}
+
+/// Pick the axis to combine along, guessing it from the shapes if it was not given.
+fn choose_axis(choice: &AxisChoice, slice_dims: &[usize], ref_dims: &[usize]) -> Direction {
+    let guessed_dir = guess_dir(slice_dims, ref_dims);
+    let axis = match choice {
+        AxisChoice::Guess => {
+            println!("Axis not specified. Guessing axis {:?}...", guessed_dir);
+            guessed_dir.clone()
+        }
+        AxisChoice::Fixed(axis) => axis.clone(),
+    };
+    if guessed_dir != axis {
+        println!(
+            "Warning! Guessed axis {:?} does not match specified axis {:?}.",
+            guessed_dir, axis
+        );
+    }
+    axis
+}
+
+/// Check the number of inputs against the reference and work out the shape of the output.
+fn output_shape(axis: &Direction, count: usize, ref_dims: &[usize]) -> Result<Vec<usize>> {
+    if *axis == Direction::T {
+        if ref_dims.len() == 4 && count != ref_dims[3] {
+            return Err(Error::Input(
+                "Number of volumes does not match reference image.".to_string(),
+            ));
+        }
+        Ok(vec![ref_dims[0], ref_dims[1], ref_dims[2], count])
+    } else {
+        if ref_dims.len() != 3 {
+            return Err(Error::Input(
+                "Reference nifti file must be 3D. Tip: You can use slicenii to split a 4D file."
+                    .to_string(),
+            ));
+        }
+        if count != ref_dims[axis.to_usize()] {
+            return Err(Error::Input(
+                "Number of slices does not match reference image.".to_string(),
+            ));
+        }
+        Ok(ref_dims.to_vec())
+    }
+}
+
+/// Find the input files in the input directories, in combining order.
+fn find_inputs(args: &CombineArgs) -> Result<Vec<InputFile>> {
+    // check that the input directories exist and gather the patterns for their nifti files
+    let mut patterns = Vec::new();
+    for input_dir in &args.input_dir {
+        let input_dir = Path::new(input_dir);
+        if !input_dir.exists() {
+            return Err(Error::Input(format!(
+                "Did not find input directory {}. Use -i to pass an existing directory.",
+                input_dir.display()
+            )));
+        } else if !input_dir.is_dir() {
+            return Err(Error::Input(format!(
+                "Input {} is not a directory!",
+                input_dir.display()
+            )));
+        }
+        patterns.extend(input_patterns(input_dir, &args.start_string));
+    }
+    resolve_inputs(&patterns, args.name_template.as_ref())
+}
+
+/// Find the table ending in `suffix` in the input directories; there must be exactly one.
+///
+/// # Arguments
+///
+/// * `input_dirs` - The directories to look in.
+/// * `suffix` - The end of the name of the table, such as `_shells.tsv`.
+/// * `hint` - What to do if there is none, added to the error.
+pub fn find_table(input_dirs: &[String], suffix: &str, hint: &str) -> Result<PathBuf> {
+    let mut tables = Vec::new();
+    for dir in input_dirs {
+        let dir = Path::new(dir);
+        if !dir.is_dir() {
+            return Err(Error::Input(format!(
+                "Did not find input directory {}. Use -i to pass an existing directory.",
+                dir.display()
+            )));
+        }
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+            if name.ends_with(suffix) && !name.starts_with('.') {
+                tables.push(path);
+            }
+        }
+    }
+    match tables.len() {
+        1 => Ok(tables.remove(0)),
+        0 => Err(Error::Input(format!(
+            "Did not find a *{} table in the input directories. {}",
+            suffix, hint
+        ))),
+        n => Err(Error::Input(format!(
+            "Found {} *{} tables in the input directories, expected one.",
+            n, suffix
+        ))),
+    }
+}
+
+/// Parse a list of input files, one per line, optionally preceded by an index and a tab.
+///
+/// Blank lines and lines starting with `#` are skipped. Either every line has an index or none
+/// does. Indices count from 1, like the numbers in slicenii file names, or from 0 if the list
+/// has a 0, as in [`check_coverage`]; without them, files are combined in the order they are
+/// listed.
+///
+/// # Arguments
+///
+/// * `text` - The contents of the list.
+///
+/// # Returns
+///
+/// The listed files in combining order, and whether they had explicit indices.
+pub fn parse_file_list(text: &str) -> Result<(Vec<InputFile>, bool)> {
+    let mut entries: Vec<(Option<u128>, PathBuf)> = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = match line.split_once('\t') {
+            Some((index, path)) => {
+                let number = index.trim().parse::<u128>().map_err(|_| {
+                    Error::Input(format!(
+                        "Line {} of the file list: '{}' is not an index.",
+                        n + 1,
+                        index
+                    ))
+                })?;
+                (Some(number), PathBuf::from(path.trim()))
+            }
+            None => (None, PathBuf::from(line.trim())),
+        };
+        entries.push(entry);
+    }
+
+    let indexed = entries
+        .iter()
+        .filter(|(number, _)| number.is_some())
+        .count();
+    if indexed != 0 && indexed != entries.len() {
+        return Err(Error::Input(
+            "Either every line of the file list needs an index or none.".to_string(),
+        ));
+    }
+    let missing: Vec<String> = entries
+        .iter()
+        .filter(|(_, path)| !path.is_file())
+        .map(|(_, path)| path.display().to_string())
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error::Input(format!(
+            "Did not find listed file(s): {}",
+            missing.join(", ")
+        )));
+    }
+
+    let indexed = indexed > 0;
+    if indexed {
+        entries.sort_by_key(|(number, _)| *number);
+    }
+    // the same numbering base check_coverage judges the list by
+    let base = entries
+        .first()
+        .and_then(|(number, _)| *number)
+        .map_or(1, |n| n.min(1));
+    let inputs = entries
+        .into_iter()
+        .enumerate()
+        .map(|(index, (number, path))| InputFile {
+            path,
+            number: number.or(Some(index as u128 + 1)),
+            index: number.map_or(index, |n| (n - base) as usize),
+        })
+        .collect();
+    Ok((inputs, indexed))
+}
+
+/// Read a list of input files from a file, or from stdin if `source` is `-`.
+///
+/// See `parse_file_list` for the format.
+pub fn read_file_list(source: &str) -> Result<(Vec<InputFile>, bool)> {
+    let text = if source == "-" {
+        let mut text = String::new();
+        std::io::stdin().read_to_string(&mut text)?;
+        text
+    } else {
+        let path = Path::new(source);
+        if !path.is_file() {
+            return Err(Error::Input(format!("Did not find file list {}", source)));
+        }
+        fs::read_to_string(path)?
+    };
+    parse_file_list(&text)
+}
+
+/// Run the `combine` subcommand.
+pub fn run(args: &CombineArgs) -> Result<()> {
+    if args.shells {
+        return merge_shells(args);
+    }
+    if args.slice_order {
+        return combine_by_slice_order(args);
+    }
+    if args.groups {
+        return combine_groups(args);
+    }
+    if args.asl {
+        return merge_asl(args);
+    }
+    let mut output_filename = PathBuf::from(&args.output);
+    if args.compress.compress && !args.output.ends_with(".gz") {
+        output_filename = PathBuf::from(format!("{}.gz", args.output));
+    }
+    let reference_filename = Path::new(&args.reference);
+
+    // a file list takes precedence over the input directories
+    let (inputs, indexed) = match &args.file_list {
+        Some(list) => read_file_list(list)?,
+        None => (find_inputs(args)?, false),
+    };
+    let output_filename = with_nifti_extension(&output_filename);
+    if args.packets {
+        return combine_packets(args, &inputs, &output_filename);
+    }
+
+    // read in reference nifti header; only its header and shape are needed
+    if !reference_filename.exists() {
+        return Err(Error::Input(
+            "Did not find reference nifti file. Use -r to pass an existing file.".to_string(),
+        ));
+    }
+    let ref_header = read_header(reference_filename)?;
+    let ref_ndim = ref_header.dim[0] as usize;
+    if ref_ndim != 3 && ref_ndim != 4 {
+        return Err(Error::Input(
+            "Reference nifti file must be 3D, or 4D when combining volumes.".to_string(),
+        ));
+    }
+    let ref_dims: Vec<usize> = ref_header.dim[1..=ref_ndim]
+        .iter()
+        .map(|&d| d as usize)
+        .collect();
+
+    if inputs.is_empty() {
+        return Err(Error::Input(
+            "Did not find any input files to combine.".to_string(),
+        ));
+    }
+    // get first slice to check dimensions
+    let first_header = read_header(&inputs[0].path)?;
+    let slice_dims: Vec<usize> = first_header.dim[1..4].iter().map(|&d| d as usize).collect();
+
+    let (inputs, axis) = if args.by_affine {
+        let guessed = guess_dir(&slice_dims, &ref_dims);
+        place_inputs(&inputs, &ref_header, &args.axis.axis, &guessed)?
+    } else if args.by_header {
+        place_by_provenance(&inputs, &args.axis.axis)?
+    } else if args.by_toffset {
+        if let AxisChoice::Fixed(axis) = &args.axis.axis {
+            if *axis != Direction::T {
+                return Err(Error::Usage(
+                    "--by-toffset orders volumes, so the axis must be t.".to_string(),
+                ));
+            }
+        }
+        (place_by_toffset(&inputs)?, Direction::T)
+    } else {
+        let axis = choose_axis(&args.axis.axis, &slice_dims, &ref_dims);
+        (inputs, axis)
+    };
+    if args.by_affine
+        || args.by_header
+        || indexed
+        || (args.file_list.is_none() && args.input_dir.len() > 1)
+    {
+        let expected = if axis == Direction::T {
+            ref_dims.get(3).copied()
+        } else {
+            Some(ref_dims[axis.to_usize()])
+        };
+        check_coverage(&inputs, expected)?;
+    }
+    check_unique_indices(&inputs)?;
+    let shape = output_shape(&axis, inputs.len(), &ref_dims)?;
+    check_inputs(&inputs, &ref_header, &axis, !args.check.lenient)?;
+
+    if args.dry_run {
+        let source = if args.by_affine {
+            "affine"
+        } else if args.by_header {
+            "header"
+        } else if args.by_toffset {
+            "toffset"
+        } else if args.file_list.is_some() {
+            "listed"
+        } else {
+            "parsed"
+        };
+        for input in &inputs {
+            let number = match input.number {
+                Some(n) => n.to_string(),
+                None => "none".to_string(),
+            };
+            println!(
+                "Would combine: {} as index {} ({} number {})",
+                input.path.display(),
+                input.index + 1,
+                source,
+                number
+            );
+        }
+        println!("Axis: {:?}", axis);
+        println!("Final shape: {:?}", shape);
+        println!("Dry run: would write {}", output_filename.display());
+        return Ok(());
+    }
+
+    let mut journal = Journal::open(
+        &sidecar_path(&output_filename, "journal.jsonl"),
+        Some(Fingerprint::of(reference_filename)?),
+        &[("axis", axis.to_string())],
+        args.resume.resume,
+    )?;
+    let output_done = journal
+        .output
+        .as_ref()
+        .is_some_and(|output| output.matches(&output_filename));
+    if output_done
+        && journal.completed.len() == inputs.len()
+        && inputs
+            .iter()
+            .all(|input| journal.is_complete(input.index, &input.path))
+    {
+        println!("Already done: {}", output_filename.display());
+        return Ok(());
+    }
+
+    let policy = &journal.pending_policy(&args.overwrite.overwrite);
+    if output_filename.exists() {
+        match policy {
+            OverwritePolicy::Error => {
+                return Err(Error::Output("Output file already exists. Please specify a different output file, remove existing file or use --overwrite.".to_string()));
+            }
+            OverwritePolicy::Skip => {
+                report_outcome(&output_filename, &WriteOutcome::Skipped);
+                return Ok(());
+            }
+            OverwritePolicy::Replace | OverwritePolicy::Verify => {}
+        }
+    }
+
+    // start from the last checkpoint if there is one, otherwise from an empty image
+    let partial_path = sidecar_path(&output_filename, "partial.nii");
+    let mut combined_img = match resume_partial(&journal, &partial_path, &shape)? {
+        Some(partial) => partial,
+        None => {
+            journal.completed.clear();
+            ArrayD::<f64>::zeros(IxDyn(&shape))
+        }
+    };
+    // checkpoints are only of use to a run that keeps a journal
+    let checkpoints = if journal.is_active() {
+        args.checkpoints.unwrap_or(DEFAULT_CHECKPOINTS)
+    } else {
+        0
+    };
+    let interval = if checkpoints == 0 {
+        usize::MAX
+    } else {
+        inputs.len().div_ceil(checkpoints + 1).max(1)
+    };
+
+    let mut placed = Vec::new();
+    for input in &inputs {
+        if journal.is_complete(input.index, &input.path) {
+            println!("Already placed: {}", input.path.display());
+            continue;
+        }
+        let chunk = load_slice(input)?;
+        insert_chunk(&mut combined_img, &chunk, &axis)?;
+        placed.push(input);
+        if placed.len() >= interval {
+            write_image(
+                &partial_path,
+                &ref_header,
+                &combined_img.view(),
+                &OverwritePolicy::Replace,
+            )?;
+            for input in placed.drain(..) {
+                journal
+                    .completed
+                    .insert(input.index, JournalEntry::of(&input.path)?);
+            }
+            journal.save()?;
+            println!("Checkpoint saved: {}", partial_path.display());
+        }
+    }
+    if let Some(grid_path) = &args.topup_grid {
+        let grid = EvenGrid::read(Path::new(grid_path))?;
+        combined_img = grid.restore(&combined_img)?;
+        println!(
+            "Restored the grid from before TOPUP: {:?}",
+            grid.source_shape
+        );
+    }
+    println!("Final shape: {:?}", combined_img.shape());
+
+    // now save the combined image to a Nifti using the reference header and extensions, which
+    // also restores the slice timing, dim_info and display range the slices were given
+    let mut header = ref_header.clone();
+    header.dim[0] = combined_img.ndim() as u16;
+    let extensions = args
+        .extensions
+        .select(&read_extensions(reference_filename)?);
+    let outcome = write_image_with_extensions(
+        &output_filename,
+        &header,
+        &extensions,
+        &combined_img.view(),
+        policy,
+    )?;
+    report_outcome(&output_filename, &outcome);
+    if outcome != WriteOutcome::Skipped {
+        if let Some(sidecar) = for_combined(reference_filename, &ref_header, &inputs, &axis)? {
+            write_sidecar(&output_filename, &sidecar, policy)?;
+        }
+        // volumes split from a diffusion series each have their gradient
+        if axis == Direction::T {
+            let paths: Vec<&Path> = inputs.iter().map(|input| input.path.as_path()).collect();
+            if let Some(gradients) = gather_gradients(&paths)? {
+                gradients.write(&output_filename, policy)?;
+            }
+        }
+    }
+
+    if journal.is_active() {
+        for input in placed {
+            journal
+                .completed
+                .insert(input.index, JournalEntry::of(&input.path)?);
+        }
+        journal.output = Some(JournalEntry::of(&output_filename)?);
+        journal.save()?;
+    }
+    if partial_path.exists() {
+        fs::remove_file(&partial_path)?;
+    }
+    Ok(())
+}
+
+/// Put slices written in the order of acquisition back in spatial order.
+///
+/// The slice order table next to the slices says which slices of the reference each file
+/// holds. A file holding one slice may be padded, and then its first copy is used.
+fn combine_by_slice_order(args: &CombineArgs) -> Result<()> {
+    let mut output = PathBuf::from(&args.output);
+    if args.compress.compress && !args.output.ends_with(".gz") {
+        output = PathBuf::from(format!("{}.gz", args.output));
+    }
+    let output = with_nifti_extension(&output);
+    let reference = Path::new(&args.reference);
+    if !reference.exists() {
+        return Err(Error::Input(
+            "Did not find reference nifti file. Use -r to pass an existing file.".to_string(),
+        ));
+    }
+    let ref_header = read_header(reference)?;
+    if ref_header.dim[0] != 3 {
+        return Err(Error::Input(
+            "Reference nifti file must be 3D to put slices back in order.".to_string(),
+        ));
+    }
+    let ref_dims: Vec<usize> = ref_header.dim[1..4].iter().map(|&d| d as usize).collect();
+    let table = find_table(
+        &args.input_dir,
+        ORDER_TABLE,
+        "Slice with --order time first.",
+    )?;
+    let rows = read_order_table(&table)?;
+    let Some(axis) = rows.first().map(|row| row.axis.clone()) else {
+        return Err(Error::Input(format!("{} lists no files.", table.display())));
+    };
+    if let AxisChoice::Fixed(given) = &args.axis.axis {
+        if *given != axis {
+            return Err(Error::Usage(format!(
+                "{} lists slices along axis {}, not {}.",
+                table.display(),
+                axis,
+                given
+            )));
+        }
+    }
+
+    // every slice of the reference must be held by exactly one file
+    let n = ref_dims[axis.to_usize()];
+    let mut held = vec![0; n];
+    for row in &rows {
+        if row.axis != axis {
+            return Err(Error::Input(format!(
+                "{} lists slices along more than one axis.",
+                table.display()
+            )));
+        }
+        for &slice in &row.slices {
+            if slice >= n {
+                return Err(Error::Input(format!(
+                    "{} puts slice {} of {} past the {} slices of the reference.",
+                    table.display(),
+                    slice + 1,
+                    row.file.display(),
+                    n
+                )));
+            }
+            held[slice] += 1;
+        }
+    }
+    let missing: Vec<usize> = (0..n).filter(|&i| held[i] == 0).map(|i| i + 1).collect();
+    if !missing.is_empty() {
+        return Err(Error::Input(format!(
+            "{} is missing slice(s) {:?}.",
+            table.display(),
+            missing
+        )));
+    }
+    if let Some(i) = (0..n).find(|&i| held[i] > 1) {
+        return Err(Error::Input(format!(
+            "{} lists slice {} more than once.",
+            table.display(),
+            i + 1
+        )));
+    }
+
+    if args.dry_run {
+        for row in &rows {
+            println!(
+                "Would combine: {} as slices {:?} (acquired {} at {})",
+                row.file.display(),
+                row.slices.iter().map(|i| i + 1).collect::<Vec<usize>>(),
+                row.index,
+                row.time
+            );
+        }
+        println!("Axis: {:?}", axis);
+        println!("Final shape: {:?}", ref_dims);
+        println!("Dry run: would write {}", output.display());
+        return Ok(());
+    }
+    if output.exists() && args.overwrite.overwrite == OverwritePolicy::Error {
+        return Err(Error::Output("Output file already exists. Please specify a different output file, remove existing file or use --overwrite.".to_string()));
+    }
+
+    let a = Axis(axis.to_usize());
+    let mut combined = Array3::<f64>::zeros((ref_dims[0], ref_dims[1], ref_dims[2]));
+    for row in &rows {
+        println!("Loading: {}", row.file.display());
+        let data = read_image(&row.file)?
+            .data
+            .into_dimensionality::<Ix3>()
+            .map_err(|e| Error::Input(format!("{}: {}", row.file.display(), e)))?;
+        let fits = (0..3).all(|i| i == axis.to_usize() || data.shape()[i] == ref_dims[i]);
+        let planes = data.shape()[axis.to_usize()];
+        if !fits || (row.slices.len() > 1 && planes != row.slices.len()) {
+            return Err(Error::Input(format!(
+                "{} has shape {:?}, which does not hold {} slice(s) of the reference.",
+                row.file.display(),
+                data.shape(),
+                row.slices.len()
+            )));
+        }
+        for (k, &slice) in row.slices.iter().enumerate() {
+            combined
+                .index_axis_mut(a, slice)
+                .assign(&data.index_axis(a, k));
+        }
+    }
+    println!("Final shape: {:?}", combined.shape());
+
+    let extensions = args.extensions.select(&read_extensions(reference)?);
+    let outcome = write_image_with_extensions(
+        &output,
+        &ref_header,
+        &extensions,
+        &combined.view(),
+        &args.overwrite.overwrite,
+    )?;
+    report_outcome(&output, &outcome);
+    if outcome != WriteOutcome::Skipped {
+        if let Some(sidecar) = read_sidecar(reference)? {
+            write_sidecar(&output, &sidecar, &args.overwrite.overwrite)?;
+        }
+    }
+    Ok(())
+}
+
+/// A hidden file next to the output, used for the journal and checkpoints of combining.
+fn sidecar_path(output: &Path, suffix: &str) -> PathBuf {
+    let name = output
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("combined.nii");
+    output.with_file_name(format!(".{}.{}", name, suffix))
+}
+
+/// Load the last checkpoint of the combined image, if the journal has one of the right shape.
+fn resume_partial(
+    journal: &Journal,
+    partial_path: &Path,
+    shape: &[usize],
+) -> Result<Option<ArrayD<f64>>> {
+    if journal.completed.is_empty() || !partial_path.exists() {
+        return Ok(None);
+    }
+    let partial = read_image(partial_path)?;
+    if partial.data.shape() != shape {
+        println!(
+            "Warning! Checkpoint {} has shape {:?}, expected {:?}, starting over.",
+            partial_path.display(),
+            partial.data.shape(),
+            shape
+        );
+        return Ok(None);
+    }
+    println!("Resuming from checkpoint: {}", partial_path.display());
+    Ok(Some(partial.data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slicing::{SliceIter, VolIter};
+    use nifti::NiftiHeader;
+
+    fn header(dim: &[usize]) -> NiftiHeader {
+        let mut header = NiftiHeader::default();
+        header.dim[0] = dim.len() as u16;
+        for (i, &d) in dim.iter().enumerate() {
+            header.dim[i + 1] = d as u16;
+        }
+        header
+    }
+
+    fn numbered(numbers: &[Option<u128>]) -> Vec<InputFile> {
+        numbers
+            .iter()
+            .enumerate()
+            .map(|(index, &number)| InputFile {
+                path: PathBuf::from(format!("f{}.nii", index)),
+                number,
+                index,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn accepts_full_coverage() {
+        check_coverage(&numbered(&[Some(1), Some(2), Some(3)]), Some(3)).unwrap();
+        // numbering from 0 is accepted too
+        check_coverage(&numbered(&[Some(0), Some(1), Some(2)]), None).unwrap();
+    }
+
+    #[test]
+    fn reports_gaps_and_duplicates() {
+        let err = check_coverage(&numbered(&[Some(1), Some(3), Some(3), None]), Some(4))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no number in f3.nii"), "{}", err);
+        assert!(err.contains("3 is covered more than once"), "{}", err);
+        assert!(err.contains("missing 2, 4"), "{}", err);
+        let err = check_coverage(&numbered(&[Some(1), Some(2), Some(5)]), Some(2))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("out of range 5"), "{}", err);
+    }
+
+    #[test]
+    fn parses_file_lists() {
+        let dir = std::env::temp_dir().join(format!("slicenii-list-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.nii");
+        let b = dir.join("b.nii");
+        fs::write(&a, b"").unwrap();
+        fs::write(&b, b"").unwrap();
+
+        let plain = format!("# slices\n{}\n\n{}\r\n", b.display(), a.display());
+        let (inputs, indexed) = parse_file_list(&plain).unwrap();
+        assert!(!indexed);
+        assert_eq!(inputs[0].path, b);
+        assert_eq!((inputs[1].number, inputs[1].index), (Some(2), 1));
+
+        let numbered = format!("2\t{}\n1\t{}\n", b.display(), a.display());
+        let (inputs, indexed) = parse_file_list(&numbered).unwrap();
+        assert!(indexed);
+        assert_eq!(inputs[0].path, a);
+        assert_eq!((inputs[1].number, inputs[1].index), (Some(2), 1));
+
+        let from_zero = format!("0\t{}\n1\t{}\n", a.display(), b.display());
+        let (inputs, _) = parse_file_list(&from_zero).unwrap();
+        let indices: Vec<usize> = inputs.iter().map(|input| input.index).collect();
+        assert_eq!(indices, vec![0, 1]);
+        check_coverage(&inputs, Some(2)).unwrap();
+        check_unique_indices(&inputs).unwrap();
+
+        let mixed = format!("1\t{}\n{}\n", a.display(), b.display());
+        assert!(parse_file_list(&mixed).is_err());
+        assert!(parse_file_list(&format!("x\t{}\n", a.display())).is_err());
+        let missing = parse_file_list(&format!("{}\n", dir.join("c.nii").display()));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(missing.unwrap_err().to_string().contains("c.nii"));
+    }
+
+    #[test]
+    fn rejects_inputs_placed_twice() {
+        let mut inputs = numbered(&[Some(1), Some(2), Some(3)]);
+        check_unique_indices(&inputs).unwrap();
+        inputs[2].index = 1;
+        let err = check_unique_indices(&inputs).unwrap_err().to_string();
+        assert!(err.contains("index 2 (f1.nii, f2.nii)"), "{}", err);
+    }
+
+    #[test]
+    fn slices_insert_back_into_the_image() {
+        let img = Array3::from_shape_fn((3, 4, 5), |(x, y, z)| (x + 10 * y + 100 * z) as f64);
+        let source = header(&[3, 4, 5]);
+        for axis in [Direction::X, Direction::Y, Direction::Z] {
+            let mut combined = ArrayD::<f64>::zeros(IxDyn(&[3, 4, 5]));
+            for view in SliceIter::new(img.view(), &axis, &source) {
+                // padded slices contribute their middle plane
+                let chunk = view.to_slice3d(3);
+                insert_chunk(&mut combined, &chunk, &axis).unwrap();
+            }
+            assert_eq!(combined, img.clone().into_dyn());
+        }
+    }
+
+    #[test]
+    fn volumes_insert_back_into_the_series() {
+        let img = Array4::from_shape_fn((2, 3, 2, 4), |(x, y, z, t)| {
+            (x + 10 * y + 100 * z + 1000 * t) as f64
+        });
+        let source = header(&[2, 3, 2, 4]);
+        let mut combined = ArrayD::<f64>::zeros(IxDyn(&[2, 3, 2, 4]));
+        for view in VolIter::new(img.view(), &source) {
+            let vol = view.to_vol3d();
+            let chunk = Slice3D::new(vol.vol, vol.meta.index()).with_meta(vol.meta);
+            insert_chunk(&mut combined, &chunk, &Direction::T).unwrap();
+        }
+        assert_eq!(combined, img.into_dyn());
+    }
+}
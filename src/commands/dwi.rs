@@ -0,0 +1,227 @@
+//! Split a diffusion series into volumes or b-value shells, and merge the shells back.
+//!
+//! The gradients of the series are read from the FSL bval/bvec files next to it. Split into
+//! volumes, every volume gets its own bval/bvec; grouped into shells, every shell file gets
+//! the bval/bvec of its volumes, and a table records where each volume came from so
+//! `combine --shells` can put the series back in its original order.
+use ndarray::{Axis, Ix4};
+use nifti::NiftiHeader;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{CombineArgs, DwiArgs, ShardArg, SplitArgs};
+use crate::commands::groups::{group_dir, merge_groups};
+use crate::commands::split;
+use crate::dwi::{group_shells, Gradients, Shell};
+use crate::error::{Error, Result};
+use crate::groups::{write_group_table, GroupRow};
+use crate::io::{
+    nifti_extension, nifti_stem, read_header, read_image, report_outcome,
+    write_image_with_extensions, WriteOutcome,
+};
+use crate::naming::SHELL_DIR;
+use crate::provenance::Provenance;
+use crate::sidecar::{read_sidecar, write_sidecar};
+use crate::slicing::VolIter;
+
+/// The suffix of the table that maps the volumes of the shell files back to the series.
+const SHELL_TABLE: &str = "_shells.tsv";
+
+/// Read the gradients of a diffusion series, checking there is one per volume.
+fn read_gradients(path: &Path, header: &NiftiHeader) -> Result<Gradients> {
+    let gradients = Gradients::read(path)?.ok_or_else(|| {
+        Error::Input(format!(
+            "Did not find the .bval and .bvec files of {}.",
+            path.display()
+        ))
+    })?;
+    let count = header.dim[4] as usize;
+    if gradients.len() != count {
+        return Err(Error::Input(format!(
+            "{} has {} volumes but its bval/bvec files have {} gradients.",
+            path.display(),
+            count,
+            gradients.len()
+        )));
+    }
+    Ok(gradients)
+}
+
+/// Split the series into volumes as `split-time` does, giving each volume its gradient.
+fn split_volumes(args: &DwiArgs, gradients: &Gradients) -> Result<()> {
+    let split_args = SplitArgs {
+        input: args.input.clone(),
+        output: args.output.clone(),
+        group_by: None,
+        compress: args.compress.clone(),
+        extensions: args.extensions.clone(),
+        overwrite: args.overwrite.clone(),
+        resume: args.resume.clone(),
+        shard: ShardArg { shard: None },
+        naming: args.naming.clone(),
+        dry_run: args.dry_run,
+    };
+    split::split_series(&split_args, Some(gradients.clone()))
+}
+
+/// The path of the file of one shell, `<stem>_b<bvalue>.nii[.gz]` in `dir`.
+fn shell_path(dir: &Path, stem: &str, shell: &Shell, compress: bool) -> PathBuf {
+    dir.join(format!(
+        "{}_b{}.{}",
+        stem,
+        shell.bvalue,
+        nifti_extension(compress)
+    ))
+}
+
+/// Group the volumes of the series into one 4D file per b-value shell.
+fn split_shells(args: &DwiArgs, header: &NiftiHeader, gradients: &Gradients) -> Result<()> {
+    if args.naming.name_template.is_some() {
+        return Err(Error::Usage(
+            "--name-template cannot be used with --shells, shell files are named by b-value."
+                .to_string(),
+        ));
+    }
+    if args.resume.resume {
+        return Err(Error::Usage(
+            "--resume cannot be used with --shells. Use --overwrite skip instead.".to_string(),
+        ));
+    }
+    let path = Path::new(&args.input);
+    let stem = nifti_stem(path)?;
+    let dir = group_dir(
+        Path::new(&args.output),
+        args.naming.dir_name.as_ref(),
+        SHELL_DIR,
+        &stem,
+        header.dim[4] as usize,
+    )?;
+
+    let shells = group_shells(&gradients.bvals, args.shell_tolerance);
+    let files: Vec<PathBuf> = shells
+        .iter()
+        .map(|shell| shell_path(&dir, &stem, shell, args.compress.compress))
+        .collect();
+    for shell in &shells {
+        println!("Shell b{}: {} volume(s)", shell.bvalue, shell.indices.len());
+    }
+    if args.dry_run {
+        for (shell, file) in shells.iter().zip(&files) {
+            println!(
+                "Would write: {} with volumes {:?}",
+                file.display(),
+                shell.indices.iter().map(|i| i + 1).collect::<Vec<usize>>()
+            );
+        }
+        println!("Dry run: {} file(s) would be written.", files.len());
+        return Ok(());
+    }
+
+    fs::create_dir_all(&dir)?;
+    let image = read_image(path)?;
+    let img = image
+        .data
+        .into_dimensionality::<Ix4>()
+        .map_err(|e| Error::Input(e.to_string()))?;
+    let extensions = args.extensions.select(&image.extensions);
+    let sidecar = read_sidecar(path)?;
+    let vols = VolIter::new(img.view(), &image.header).with_source_path(path);
+    for (shell, file) in shells.iter().zip(&files) {
+        let data = img.select(Axis(3), &shell.indices);
+        let mut shell_header = image.header.clone();
+        shell_header.dim[4] = shell.indices.len() as u16;
+        // record which volumes of the series the shell holds
+        let first = vols
+            .get(shell.indices[0])
+            .expect("shells hold volumes of the series");
+        let provenance = Provenance::of_group(&first.meta(), &shell.indices);
+        provenance.describe(&mut shell_header);
+        let outcome = write_image_with_extensions(
+            file,
+            &shell_header,
+            &[&extensions[..], &[provenance.to_extension()]].concat(),
+            &data.view(),
+            &args.overwrite.overwrite,
+        )?;
+        report_outcome(file, &outcome);
+        if outcome != WriteOutcome::Skipped {
+            gradients
+                .subset(&shell.indices)
+                .write(file, &args.overwrite.overwrite)?;
+            if let Some(sidecar) = &sidecar {
+                write_sidecar(file, sidecar, &args.overwrite.overwrite)?;
+            }
+        }
+    }
+    let table = dir.join(format!("{}{}", stem, SHELL_TABLE));
+    let groups: Vec<Vec<usize>> = shells.iter().map(|shell| shell.indices.clone()).collect();
+    let bval = |index: usize| gradients.bvals[index].to_string();
+    write_group_table(
+        &table,
+        &files,
+        &groups,
+        ("bval", &bval),
+        &args.overwrite.overwrite,
+    )?;
+    println!("Shell table: {}", table.display());
+    Ok(())
+}
+
+/// Run the `split-dwi` subcommand: split a diffusion series into volumes or shells.
+pub fn run(args: &DwiArgs) -> Result<()> {
+    let path = Path::new(&args.input);
+    let header = read_header(path)?;
+    if header.dim[0] != 4 {
+        return Err(Error::Input(
+            "Input nifti file must be a 4D diffusion series.".to_string(),
+        ));
+    }
+    let gradients = read_gradients(path, &header)?;
+    if args.shells {
+        split_shells(args, &header, &gradients)
+    } else {
+        split_volumes(args, &gradients)
+    }
+}
+
+/// Reassemble the gradients of the series from those of the shell files.
+///
+/// # Returns
+///
+/// The gradients in series order, or `None` if a shell file has no bval/bvec files.
+fn gather_shell_gradients(rows: &[GroupRow]) -> Result<Option<Gradients>> {
+    let mut parts = Vec::new();
+    for row in rows {
+        let Some(gradients) = Gradients::read(&row.file)? else {
+            println!(
+                "Warning! {} has no bval/bvec files, not writing gradients.",
+                row.file.display()
+            );
+            return Ok(None);
+        };
+        if row.volume >= gradients.len() {
+            return Err(Error::Input(format!(
+                "{} has no gradient for volume {}.",
+                row.file.display(),
+                row.volume + 1
+            )));
+        }
+        parts.push(gradients.subset(&[row.volume]));
+    }
+    Ok(Some(Gradients::concat(&parts)))
+}
+
+/// Merge the shell files written by `split-dwi --shells` back into one series.
+///
+/// The volumes are put back in their original order using the shell table next to the shell
+/// files, and the bval/bvec files of the series are reassembled from those of the shells.
+pub fn merge_shells(args: &CombineArgs) -> Result<()> {
+    let Some((output, rows)) = merge_groups(args, SHELL_TABLE, "Split with --shells first.")?
+    else {
+        return Ok(());
+    };
+    if let Some(gradients) = gather_shell_gradients(&rows)? {
+        gradients.write(&output, &args.overwrite.overwrite)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,298 @@
+//! Split a 4D series into one 4D file per echo or position in a cycle, and interleave back.
+//!
+//! `split-time --group-by` writes every group with the timing of its volumes in its header,
+//! and a table next to the files records where every volume came from, so `combine --groups`
+//! can put the series back in its original order.
+use ndarray::{Array4, Axis, Ix4};
+use nifti::NiftiHeader;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{CombineArgs, GroupBy, OverwritePolicy, SplitArgs};
+use crate::commands::combine::find_table;
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::groups::{
+    cycle_groups, echo_times, group_header, read_group_table, write_group_table, GroupRow,
+    GROUP_TABLE,
+};
+use crate::io::{
+    nifti_extension, nifti_stem, read_extensions, read_header, read_image, report_outcome,
+    with_nifti_extension, write_image_with_extensions, WriteOutcome,
+};
+use crate::naming::{parse_template, NameFields, NameTemplate, GROUP_DIR};
+use crate::sidecar::{for_group, read_sidecar, write_sidecar};
+
+/// The directory the files of a regrouped series go in, within `output`.
+///
+/// # Arguments
+///
+/// * `output` - The directory in which to create the group directory.
+/// * `template` - The template given with `--dir-name`, if any.
+/// * `default` - The template used otherwise.
+/// * `stem` - The name of the series without its extension.
+/// * `count` - The number of volumes in the series.
+pub fn group_dir(
+    output: &Path,
+    template: Option<&NameTemplate>,
+    default: &str,
+    stem: &str,
+    count: usize,
+) -> Result<PathBuf> {
+    let template = match template {
+        Some(template) => template.clone(),
+        None => parse_template(default).expect("default template"),
+    };
+    Ok(output.join(template.render(&NameFields {
+        stem,
+        axis: &Direction::T,
+        index: 0,
+        count,
+        padded: false,
+        time_ms: None,
+        mm: None,
+    })?))
+}
+
+/// Run `split-time --group-by`: write one 4D file per echo or position in a cycle.
+pub fn split_groups(args: &SplitArgs, header: &NiftiHeader, group_by: &GroupBy) -> Result<()> {
+    if args.naming.name_template.is_some() {
+        return Err(Error::Usage(
+            "--name-template cannot be used with --group-by, group files are named by echo or cycle."
+                .to_string(),
+        ));
+    }
+    if args.resume.resume || args.shard.shard.is_some() {
+        return Err(Error::Usage(
+            "--resume and --shard cannot be used with --group-by. Use --overwrite skip instead."
+                .to_string(),
+        ));
+    }
+    let path = Path::new(&args.input);
+    let stem = nifti_stem(path)?;
+    let count = header.dim[4] as usize;
+    let sidecar = read_sidecar(path)?;
+    let times = sidecar.as_ref().and_then(echo_times);
+    let (cycle, label, title) = match group_by {
+        GroupBy::Echo => match &times {
+            Some(times) if !times.is_empty() => (times.len(), "echo", "Echo"),
+            _ => {
+                return Err(Error::Usage(format!(
+                    "{} has no list of EchoTime in its sidecar. Give the number of volumes in a cycle with --group-by.",
+                    path.display()
+                )))
+            }
+        },
+        GroupBy::Cycle(cycle) => (*cycle, "cycle", "Cycle"),
+    };
+    if !count.is_multiple_of(cycle) {
+        return Err(Error::Input(format!(
+            "{} has {} volumes, which is not a whole number of cycles of {}.",
+            path.display(),
+            count,
+            cycle
+        )));
+    }
+    // echo times only describe the groups if there is one per group
+    let times = times.filter(|times| times.len() == cycle);
+
+    let dir = group_dir(
+        Path::new(&args.output),
+        args.naming.dir_name.as_ref(),
+        GROUP_DIR,
+        &stem,
+        count,
+    )?;
+    let groups = cycle_groups(count, cycle);
+    let files: Vec<PathBuf> = (0..groups.len())
+        .map(|g| {
+            dir.join(format!(
+                "{}_{}-{}.{}",
+                stem,
+                label,
+                g + 1,
+                nifti_extension(args.compress.compress)
+            ))
+        })
+        .collect();
+    for (g, indices) in groups.iter().enumerate() {
+        match &times {
+            Some(times) => println!(
+                "{} {}: {} volume(s), echo time {} s",
+                title,
+                g + 1,
+                indices.len(),
+                times[g]
+            ),
+            None => println!("{} {}: {} volume(s)", title, g + 1, indices.len()),
+        }
+    }
+    if args.dry_run {
+        for (indices, file) in groups.iter().zip(&files) {
+            println!(
+                "Would write: {} with volumes {:?}",
+                file.display(),
+                indices.iter().map(|i| i + 1).collect::<Vec<usize>>()
+            );
+        }
+        println!("Dry run: {} file(s) would be written.", files.len());
+        return Ok(());
+    }
+
+    fs::create_dir_all(&dir)?;
+    let image = read_image(path)?;
+    let img = image
+        .data
+        .into_dimensionality::<Ix4>()
+        .map_err(|e| Error::Input(e.to_string()))?;
+    let extensions = args.extensions.select(&image.extensions);
+    for (g, (indices, file)) in groups.iter().zip(&files).enumerate() {
+        let data = img.select(Axis(3), indices);
+        let (group, _) = group_header(&image.header, indices);
+        let outcome = write_image_with_extensions(
+            file,
+            &group,
+            &extensions,
+            &data.view(),
+            &args.overwrite.overwrite,
+        )?;
+        report_outcome(file, &outcome);
+        if outcome != WriteOutcome::Skipped {
+            if let Some(sidecar) = &sidecar {
+                let mut sidecar = for_group(sidecar, header, indices);
+                if let Some(times) = &times {
+                    sidecar.insert("EchoTime".to_string(), Value::from(times[g]));
+                }
+                write_sidecar(file, &sidecar, &args.overwrite.overwrite)?;
+            }
+        }
+    }
+    let table = dir.join(format!("{}{}", stem, GROUP_TABLE));
+    let time = |index: usize| match &times {
+        Some(times) => times[index % cycle].to_string(),
+        None => "n/a".to_string(),
+    };
+    write_group_table(
+        &table,
+        &files,
+        &groups,
+        ("echo_time", &time),
+        &args.overwrite.overwrite,
+    )?;
+    println!("Group table: {}", table.display());
+    Ok(())
+}
+
+/// Interleave the volumes of several 4D files back into one series, using a group table.
+///
+/// # Arguments
+///
+/// * `args` - The arguments of the combine.
+/// * `suffix` - The suffix of the table to look for in the input directories.
+/// * `hint` - What to do if there is no table.
+///
+/// # Returns
+///
+/// The path of the series and the rows of the table, or `None` if nothing was written.
+pub fn merge_groups(
+    args: &CombineArgs,
+    suffix: &str,
+    hint: &str,
+) -> Result<Option<(PathBuf, Vec<GroupRow>)>> {
+    let mut output = PathBuf::from(&args.output);
+    if args.compress.compress && !args.output.ends_with(".gz") {
+        output = PathBuf::from(format!("{}.gz", args.output));
+    }
+    let output = with_nifti_extension(&output);
+    let reference = Path::new(&args.reference);
+    if !reference.exists() {
+        return Err(Error::Input(
+            "Did not find reference nifti file. Use -r to pass an existing file.".to_string(),
+        ));
+    }
+    let ref_header = read_header(reference)?;
+    if ref_header.dim[0] != 4 {
+        return Err(Error::Input(
+            "Reference nifti file must be the 4D series to interleave its volumes.".to_string(),
+        ));
+    }
+    let count = ref_header.dim[4] as usize;
+    let table = find_table(&args.input_dir, suffix, hint)?;
+    let rows = read_group_table(&table, count)?;
+
+    let mut files: Vec<&PathBuf> = rows.iter().map(|row| &row.file).collect();
+    files.sort();
+    files.dedup();
+    if args.dry_run {
+        for row in &rows {
+            println!(
+                "Would combine: {} volume {} as index {}",
+                row.file.display(),
+                row.volume + 1,
+                row.index + 1
+            );
+        }
+        println!("Merging {} file(s) from {}", files.len(), table.display());
+        println!("Dry run: would write {}", output.display());
+        return Ok(None);
+    }
+    if output.exists() && args.overwrite.overwrite == OverwritePolicy::Error {
+        return Err(Error::Output("Output file already exists. Please specify a different output file, remove existing file or use --overwrite.".to_string()));
+    }
+
+    let shape: Vec<usize> = ref_header.dim[1..5].iter().map(|&d| d as usize).collect();
+    let mut combined = Array4::<f64>::zeros((shape[0], shape[1], shape[2], shape[3]));
+    for file in files {
+        println!("Loading: {}", file.display());
+        let image = read_image(file)?;
+        let data = image
+            .data
+            .into_dimensionality::<Ix4>()
+            .map_err(|e| Error::Input(format!("{}: {}", file.display(), e)))?;
+        if data.shape()[..3] != shape[..3] {
+            return Err(Error::Input(format!(
+                "{} has shape {:?}, expected {:?} for each volume.",
+                file.display(),
+                &data.shape()[..3],
+                &shape[..3]
+            )));
+        }
+        for row in rows.iter().filter(|row| row.file == *file) {
+            if row.volume >= data.shape()[3] {
+                return Err(Error::Input(format!(
+                    "{} has no volume {}.",
+                    file.display(),
+                    row.volume + 1
+                )));
+            }
+            combined
+                .index_axis_mut(Axis(3), row.index)
+                .assign(&data.index_axis(Axis(3), row.volume));
+        }
+    }
+    println!("Final shape: {:?}", combined.shape());
+
+    let extensions = args.extensions.select(&read_extensions(reference)?);
+    let outcome = write_image_with_extensions(
+        &output,
+        &ref_header,
+        &extensions,
+        &combined.view(),
+        &args.overwrite.overwrite,
+    )?;
+    report_outcome(&output, &outcome);
+    if outcome == WriteOutcome::Skipped {
+        return Ok(None);
+    }
+    if let Some(sidecar) = read_sidecar(reference)? {
+        write_sidecar(&output, &sidecar, &args.overwrite.overwrite)?;
+    }
+    Ok(Some((output, rows)))
+}
+
+/// Run `combine --groups`: interleave the files written by `split-time --group-by`.
+pub fn combine_groups(args: &CombineArgs) -> Result<()> {
+    merge_groups(args, GROUP_TABLE, "Split with --group-by first.")?;
+    Ok(())
+}
@@ -0,0 +1,475 @@
+//! Describe how slicenii would treat a file, without reading its voxels.
+//!
+//! For each input this prints the header fields slicenii relies on, the axis it would pick
+//! and why, and how many files of what size slicing or splitting would write. With `--json`
+//! the same information is printed as a JSON array with one object per input.
+use serde::Serialize;
+use std::path::Path;
+
+use crate::cli::{AxisChoice, ExtensionArgs, InfoArgs};
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::header::{datatype_name, orientation, unit_name, units, xform_name};
+use crate::io::{read_extensions, read_header};
+use crate::provenance::Provenance;
+use crate::slicing::{guess_dir, guess_scores};
+
+/// The size of the header slicenii writes in front of every output file, in bytes, before
+/// any header extensions.
+const OUTPUT_HEADER_BYTES: u64 = 352;
+/// The size of each voxel slicenii writes, as outputs are stored as float64.
+const OUTPUT_VOXEL_BYTES: u64 = 8;
+
+/// FileInfo is what `info` reports for one input.
+#[derive(Debug, Serialize)]
+pub struct FileInfo {
+    pub path: String,
+    pub dims: Vec<u16>,
+    pub pixdims: Vec<f32>,
+    pub datatype: String,
+    pub datatype_code: i16,
+    pub qform_code: i16,
+    pub qform: String,
+    pub sform_code: i16,
+    pub sform: String,
+    pub orientation: String,
+    pub space_units: String,
+    pub time_units: String,
+    /// The repetition time (pixdim[4]) of 4D images, in `time_units`.
+    pub tr: Option<f32>,
+    pub plan: Plan,
+}
+
+/// Plan is what slicenii would do with an input.
+#[derive(Debug, Serialize)]
+pub struct Plan {
+    /// `slice`, `split-time` or `unsupported`.
+    pub mode: String,
+    /// The axis the input would be cut along.
+    pub axis: Option<Direction>,
+    /// The axis `guess_dir` picks for 3D inputs.
+    pub guessed_axis: Option<Direction>,
+    /// The guess scores of the X, Y and Z axes.
+    pub guess_scores: Option<[usize; 3]>,
+    /// Why the axis was chosen.
+    pub reason: String,
+    pub padding: usize,
+    pub output_files: usize,
+    /// The shape of each output file.
+    pub output_shape: Vec<usize>,
+    /// The uncompressed size of each output file, in bytes.
+    pub bytes_per_file: u64,
+    /// The uncompressed size of all output files, in bytes.
+    pub estimated_output_bytes: u64,
+}
+
+/// Explain the result of `guess_dir` from its scores.
+fn guess_reason(
+    dims: [u16; 8],
+    pixdims: [f32; 8],
+    scores: [usize; 3],
+    guessed: &Direction,
+    unit: &str,
+) -> String {
+    let names = ["X", "Y", "Z"];
+    let details: Vec<String> = (0..3)
+        .map(|i| {
+            format!(
+                "{} has {} voxels of {} {} (score {})",
+                names[i],
+                dims[i + 1],
+                pixdims[i + 1],
+                unit,
+                scores[i]
+            )
+        })
+        .collect();
+    format!(
+        "{:?} scored highest for having fewer and larger voxels than the other axes: {}",
+        guessed,
+        details.join(", ")
+    )
+}
+
+/// Work out how slicenii would treat a file from its header.
+///
+/// # Arguments
+///
+/// * `path` - The NIfTI file to describe.
+/// * `axis` - The axis that would be passed to `slice`.
+/// * `padding` - The padding that would be passed to `slice`.
+///
+/// # Returns
+///
+/// The header fields and plan for the file.
+pub fn describe(
+    path: &Path,
+    axis: &AxisChoice,
+    padding: usize,
+    extensions: &ExtensionArgs,
+) -> Result<FileInfo> {
+    let header = read_header(path)?;
+    let ndim = header.dim[0] as usize;
+    if !(1..=7).contains(&ndim) {
+        return Err(Error::Input(format!(
+            "{} has an invalid number of dimensions ({})",
+            path.display(),
+            ndim
+        )));
+    }
+    let dims: Vec<u16> = header.dim[1..=ndim].to_vec();
+    let (space, time) = units(&header);
+    let padding = padding.max(1);
+    let carried: u64 = extensions
+        .select(&read_extensions(path)?)
+        .iter()
+        .map(|e| e.size() as u64)
+        .sum();
+
+    let plan = match ndim {
+        3 => {
+            let scores = guess_scores(header.dim, header.pixdim);
+            let guessed = guess_dir(header.dim, header.pixdim);
+            let chosen = axis.resolve(&guessed);
+            let reason = match axis {
+                AxisChoice::Guess => guess_reason(
+                    header.dim,
+                    header.pixdim,
+                    scores,
+                    &guessed,
+                    unit_name(space),
+                ),
+                AxisChoice::Fixed(_) => format!(
+                    "{:?} was given on the command line (the guess would be {:?})",
+                    chosen, guessed
+                ),
+            };
+            if chosen == Direction::T {
+                Plan {
+                    mode: "unsupported".to_string(),
+                    axis: Some(chosen),
+                    guessed_axis: Some(guessed),
+                    guess_scores: Some(scores),
+                    reason: "a 3D image cannot be sliced along time".to_string(),
+                    padding,
+                    output_files: 0,
+                    output_shape: Vec::new(),
+                    bytes_per_file: 0,
+                    estimated_output_bytes: 0,
+                }
+            } else {
+                let a = chosen.to_usize();
+                let mut shape: Vec<usize> = dims.iter().map(|&d| d as usize).collect();
+                shape[a] = padding;
+                let files = dims[a] as usize;
+                let bytes = OUTPUT_HEADER_BYTES
+                    + carried
+                    + provenance_bytes(path, &chosen, files, padding)
+                    + shape.iter().product::<usize>() as u64 * OUTPUT_VOXEL_BYTES;
+                Plan {
+                    mode: "slice".to_string(),
+                    axis: Some(chosen),
+                    guessed_axis: Some(guessed),
+                    guess_scores: Some(scores),
+                    reason,
+                    padding,
+                    output_files: files,
+                    output_shape: shape,
+                    bytes_per_file: bytes,
+                    estimated_output_bytes: bytes * files as u64,
+                }
+            }
+        }
+        4 => {
+            let shape: Vec<usize> = dims[..3].iter().map(|&d| d as usize).collect();
+            let files = dims[3] as usize;
+            let bytes = OUTPUT_HEADER_BYTES
+                + carried
+                + provenance_bytes(path, &Direction::T, files, 1)
+                + shape.iter().product::<usize>() as u64 * OUTPUT_VOXEL_BYTES;
+            Plan {
+                mode: "split-time".to_string(),
+                axis: Some(Direction::T),
+                guessed_axis: None,
+                guess_scores: None,
+                reason: "4D images are split across time".to_string(),
+                padding: 1,
+                output_files: files,
+                output_shape: shape,
+                bytes_per_file: bytes,
+                estimated_output_bytes: bytes * files as u64,
+            }
+        }
+        _ => Plan {
+            mode: "unsupported".to_string(),
+            axis: None,
+            guessed_axis: None,
+            guess_scores: None,
+            reason: format!("{}D images are not supported", ndim),
+            padding,
+            output_files: 0,
+            output_shape: Vec::new(),
+            bytes_per_file: 0,
+            estimated_output_bytes: 0,
+        },
+    };
+
+    Ok(FileInfo {
+        path: path.display().to_string(),
+        pixdims: header.pixdim[1..=ndim].to_vec(),
+        dims,
+        datatype: datatype_name(&header),
+        datatype_code: header.datatype,
+        qform_code: header.qform_code,
+        qform: xform_name(header.qform_code).to_string(),
+        sform_code: header.sform_code,
+        sform: xform_name(header.sform_code).to_string(),
+        orientation: orientation(&header.affine::<f64>()),
+        space_units: unit_name(space).to_string(),
+        time_units: unit_name(time).to_string(),
+        tr: if ndim >= 4 {
+            Some(header.pixdim[4])
+        } else {
+            None
+        },
+        plan,
+    })
+}
+
+/// The size of the provenance extension of the last of `count` outputs, the longest, in bytes.
+fn provenance_bytes(path: &Path, axis: &Direction, count: usize, padding: usize) -> u64 {
+    let provenance = Provenance {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        source: path.file_name().map(|n| n.to_string_lossy().into_owned()),
+        axis: axis.clone(),
+        index: count.saturating_sub(1),
+        count,
+        padding,
+        indices: None,
+    };
+    provenance.to_extension().size() as u64
+}
+
+/// Format a number of bytes with a binary prefix.
+fn human_bytes(bytes: u64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, units[unit])
+    }
+}
+
+/// Print the human-readable description of a file.
+fn print_info(info: &FileInfo) {
+    println!("File: {}", info.path);
+    println!("  Dimensions: {:?}", info.dims);
+    println!("  Voxel sizes: {:?} {}", info.pixdims, info.space_units);
+    println!("  Datatype: {} ({})", info.datatype, info.datatype_code);
+    println!(
+        "  qform: {} ({}), sform: {} ({})",
+        info.qform, info.qform_code, info.sform, info.sform_code
+    );
+    println!("  Orientation: {}", info.orientation);
+    match info.tr {
+        Some(tr) => println!("  TR: {} {}", tr, info.time_units),
+        None => println!("  Time units: {}", info.time_units),
+    }
+    let plan = &info.plan;
+    match plan.mode.as_str() {
+        "unsupported" => println!("  Not supported: {}", plan.reason),
+        mode => {
+            if let Some(axis) = &plan.axis {
+                println!("  Axis: {:?}, because {}", axis, plan.reason);
+            }
+            println!(
+                "  {} would write {} files of shape {:?}",
+                mode, plan.output_files, plan.output_shape
+            );
+            println!(
+                "  Estimated output size: {} ({} per file, uncompressed)",
+                human_bytes(plan.estimated_output_bytes),
+                human_bytes(plan.bytes_per_file)
+            );
+        }
+    }
+}
+
+/// Run the `info` subcommand.
+pub fn run(args: &InfoArgs) -> Result<()> {
+    let mut infos = Vec::new();
+    for input in &args.input {
+        infos.push(describe(
+            Path::new(input),
+            &args.axis.axis,
+            args.pad.pad,
+            &args.extensions,
+        )?);
+    }
+    if args.json {
+        let json = serde_json::to_string_pretty(&infos)
+            .map_err(|e| Error::Output(format!("Could not format JSON: {}", e)))?;
+        println!("{}", json);
+    } else {
+        for info in &infos {
+            print_info(info);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::OverwritePolicy;
+    use crate::io::write_image_with_extensions;
+    use ndarray::{ArrayD, IxDyn};
+    use nifti::{Extension, NiftiHeader};
+    use std::fs;
+    use std::path::PathBuf;
+
+    /// Write an image of `shape` with 1 mm voxels, but 3 mm along Z and 2 s volumes.
+    fn write(dir: &Path, name: &str, shape: &[usize], extensions: &[Extension]) -> PathBuf {
+        let header = NiftiHeader {
+            pixdim: [1.0, 1.0, 1.0, 3.0, 2.0, 1.0, 1.0, 1.0],
+            xyzt_units: 10,
+            ..Default::default()
+        };
+        let path = dir.join(name);
+        let data = ArrayD::<f64>::zeros(IxDyn(shape));
+        write_image_with_extensions(
+            &path,
+            &header,
+            extensions,
+            &data.view(),
+            &OverwritePolicy::Error,
+        )
+        .unwrap();
+        path
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("slicenii-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn plans_slices_and_volumes() {
+        let dir = scratch("info-plan");
+        let volume = write(&dir, "t1.nii", &[4, 4, 2], &[]);
+        let series = write(&dir, "bold.nii", &[3, 2, 2, 6], &[]);
+        let image = write(&dir, "fields.nii", &[2, 2, 2, 1, 3], &[]);
+        let all = ExtensionArgs::default();
+        let guessed = describe(&volume, &AxisChoice::Guess, 3, &all);
+        let fixed = describe(&volume, &AxisChoice::Fixed(Direction::X), 0, &all);
+        let time = describe(&volume, &AxisChoice::Fixed(Direction::T), 1, &all);
+        let split = describe(&series, &AxisChoice::Guess, 1, &all);
+        let other = describe(&image, &AxisChoice::Guess, 1, &all);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // Z has the fewest and largest voxels
+        let info = guessed.unwrap();
+        assert_eq!((info.dims, info.tr), (vec![4, 4, 2], None));
+        let plan = info.plan;
+        assert_eq!(plan.mode, "slice");
+        assert_eq!(plan.axis, Some(Direction::Z));
+        assert_eq!(plan.guess_scores, Some([0, 0, 4]));
+        assert!(
+            plan.reason.contains("Z has 2 voxels of 3 mm"),
+            "{}",
+            plan.reason
+        );
+        assert_eq!((plan.output_files, plan.output_shape), (2, vec![4, 4, 3]));
+        let bytes = OUTPUT_HEADER_BYTES
+            + provenance_bytes(&volume, &Direction::Z, 2, 3)
+            + 4 * 4 * 3 * OUTPUT_VOXEL_BYTES;
+        assert_eq!(plan.bytes_per_file, bytes);
+        assert_eq!(plan.estimated_output_bytes, 2 * bytes);
+
+        let plan = fixed.unwrap().plan;
+        assert_eq!(plan.axis, Some(Direction::X));
+        assert_eq!(plan.guessed_axis, Some(Direction::Z));
+        assert!(
+            plan.reason.contains("given on the command line"),
+            "{}",
+            plan.reason
+        );
+        // padding is at least one
+        assert_eq!((plan.output_files, plan.output_shape), (4, vec![1, 4, 2]));
+
+        let plan = time.unwrap().plan;
+        assert_eq!((plan.mode.as_str(), plan.output_files), ("unsupported", 0));
+        assert!(plan.reason.contains("along time"), "{}", plan.reason);
+
+        let info = split.unwrap();
+        assert_eq!((info.tr, info.time_units.as_str()), (Some(2.0), "s"));
+        let plan = info.plan;
+        assert_eq!(
+            (plan.mode.as_str(), plan.axis),
+            ("split-time", Some(Direction::T))
+        );
+        assert_eq!((plan.output_files, plan.output_shape), (6, vec![3, 2, 2]));
+        let bytes = OUTPUT_HEADER_BYTES
+            + provenance_bytes(&series, &Direction::T, 6, 1)
+            + 3 * 2 * 2 * OUTPUT_VOXEL_BYTES;
+        assert_eq!(plan.bytes_per_file, bytes);
+
+        let plan = other.unwrap().plan;
+        assert_eq!(plan.mode, "unsupported");
+        assert_eq!(plan.reason, "5D images are not supported");
+    }
+
+    #[test]
+    fn counts_the_extensions_that_are_carried() {
+        let dir = scratch("info-extensions");
+        let provenance = Provenance {
+            version: "0.0.0".to_string(),
+            source: None,
+            axis: Direction::Z,
+            index: 0,
+            count: 1,
+            padding: 1,
+            indices: None,
+        };
+        let note = Extension::from_str(6, "a note longer than sixteen bytes");
+        let extensions = [note.clone(), provenance.to_extension()];
+        let plain = write(&dir, "plain.nii", &[4, 4, 2], &[]);
+        let noted = write(&dir, "noted.nii", &[4, 4, 2], &extensions);
+        let bytes = |path: &Path, extensions: &ExtensionArgs| {
+            describe(path, &AxisChoice::Guess, 1, extensions)
+                .unwrap()
+                .plan
+                .bytes_per_file
+        };
+        let all = ExtensionArgs::default();
+        let none = ExtensionArgs {
+            drop_extensions: true,
+            ..Default::default()
+        };
+        let (plain_bytes, noted_bytes) = (bytes(&plain, &all), bytes(&noted, &all));
+        let dropped = bytes(&noted, &none);
+        fs::remove_dir_all(&dir).unwrap();
+
+        // the provenance of the input is replaced, not carried; both names are as long, so
+        // the provenance written for them is too
+        assert_eq!(noted_bytes, plain_bytes + note.size() as u64);
+        assert_eq!(dropped, plain_bytes);
+    }
+
+    #[test]
+    fn formats_sizes() {
+        assert_eq!(human_bytes(0), "0 B");
+        assert_eq!(human_bytes(1023), "1023 B");
+        assert_eq!(human_bytes(1024), "1.0 KiB");
+        assert_eq!(human_bytes(1536 * 1024), "1.5 MiB");
+        assert_eq!(human_bytes(3 << 30), "3.0 GiB");
+        assert_eq!(human_bytes(2048 << 40), "2048.0 TiB");
+    }
+}
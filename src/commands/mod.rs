@@ -0,0 +1,15 @@
+//! The subcommands of the slicenii front end.
+//!
+//! Each module exposes a `run` function that takes its arguments from [`crate::cli`] and
+//! returns an [`crate::error::Result`], leaving printing of errors and exit codes to the binary.
+pub mod asl;
+pub mod batch;
+pub mod combine;
+pub mod dwi;
+pub mod groups;
+pub mod info;
+pub mod packets;
+pub mod slice;
+pub mod split;
+pub mod topup;
+pub mod verify;
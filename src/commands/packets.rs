@@ -0,0 +1,305 @@
+//! Split a 3D volume into its interleaved acquisition packets, and interleave them back.
+//!
+//! Each packet is written as a volume of its own on a grid with the slice spacing multiplied
+//! by the number of packets, as slice-to-volume reconstruction expects. Combining places each
+//! packet by its affine, so the files may be renamed or processed in between.
+use ndarray::{Array3, Axis, Ix3};
+use nifti::NiftiHeader;
+use std::fs;
+use std::path::Path;
+
+use crate::cli::{AxisChoice, CombineArgs, OverwritePolicy, PacketArgs, PacketChoice};
+use crate::commands::combine::{guess_dir, InputFile};
+use crate::commands::slice::choose_axis;
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::header::slice_dim;
+use crate::io::{
+    nifti_stem, read_extensions, read_header, read_image, report_outcome,
+    write_image_with_extensions, WriteOutcome,
+};
+use crate::naming::{slice_offset_mm, NameFields, OutputNaming};
+use crate::packets::{
+    locate_packet, packet_affine, packet_count, packet_header, plan_packets, Packet,
+};
+use crate::placement::AXES;
+use crate::plan::{print_plan, PlannedOutput};
+use crate::sidecar::{for_packet, read_sidecar, write_sidecar};
+
+/// The axis the slices were acquired along: the one given, otherwise the slice axis in
+/// `dim_info`, otherwise a guess from the shape.
+fn packet_axis(header: &NiftiHeader, choice: &AxisChoice) -> Result<Direction> {
+    if *choice == AxisChoice::Guess {
+        if let Some(axis) = slice_dim(header) {
+            let axis = AXES[axis].clone();
+            println!(
+                "Axis not specified. Using the slice axis in dim_info: {:?}",
+                axis
+            );
+            return Ok(axis);
+        }
+    }
+    choose_axis(header, choice)
+}
+
+/// Run the `packets` subcommand: split a 3D volume into its interleaved packets.
+pub fn run(args: &PacketArgs) -> Result<()> {
+    let path = Path::new(&args.input);
+    let basename = nifti_stem(path)?;
+    let header = read_header(path)?;
+    if header.dim[0] != 3 {
+        return Err(Error::Input(
+            "Input nifti file must be 3D to split into packets.".to_string(),
+        ));
+    }
+    let axis = packet_axis(&header, &args.axis.axis)?;
+    let n = header.dim[axis.to_usize() + 1] as usize;
+    let count = match args.packets {
+        PacketChoice::Count(count) => count,
+        PacketChoice::Auto => packet_count(&header, &axis).ok_or_else(|| {
+            Error::Usage(format!(
+                "The header has no slice order along axis {}. Give the number of packets with --packets.",
+                axis
+            ))
+        })?,
+    };
+    if count > n {
+        return Err(Error::Usage(format!(
+            "Cannot split {} slices into {} packets.",
+            n, count
+        )));
+    }
+    if count == 1 {
+        println!("Warning! The slices were acquired in one packet, writing the volume as it is.");
+    }
+
+    let packets = plan_packets(&header, &axis, count);
+    let naming = OutputNaming::packets(
+        args.naming.name_template.as_ref(),
+        args.naming.dir_name.as_ref(),
+        args.compress.compress,
+    );
+    let fields = |index: usize, packet: &Packet| NameFields {
+        stem: &basename,
+        axis: &axis,
+        index,
+        count: packets.len(),
+        padded: false,
+        time_ms: None,
+        mm: Some(slice_offset_mm(&header, &axis, packet.offset)),
+    };
+    let save_dir = naming.dir_path(Path::new(&args.output), &fields(0, &packets[0]))?;
+    let outputs = packets
+        .iter()
+        .enumerate()
+        .map(|(index, packet)| {
+            let mut shape: Vec<usize> = header.dim[1..4].iter().map(|&d| d as usize).collect();
+            shape[axis.to_usize()] = packet.len;
+            Ok(PlannedOutput {
+                index,
+                path: naming.file_path(&save_dir, &fields(index, packet))?,
+                shape,
+                affine: packet_affine(&header, &axis, packet),
+            })
+        })
+        .collect::<Result<Vec<PlannedOutput>>>()?;
+
+    println!(
+        "Splitting {} slices on axis {:?} into {} packet(s)",
+        n, axis, count
+    );
+    for (index, packet) in packets.iter().enumerate() {
+        println!(
+            "Packet {}: slices {:?}",
+            index + 1,
+            packet.slices().map(|i| i + 1).collect::<Vec<usize>>()
+        );
+    }
+    if args.dry_run {
+        print_plan(&outputs);
+        return Ok(());
+    }
+
+    fs::create_dir_all(&save_dir)?;
+    let image = read_image(path)?;
+    let img = image
+        .data
+        .into_dimensionality::<Ix3>()
+        .map_err(|e| Error::Input(e.to_string()))?;
+    let extensions = args.extensions.select(&image.extensions);
+    let sidecar = read_sidecar(path)?;
+    for (out, packet) in outputs.iter().zip(&packets) {
+        let slices: Vec<usize> = packet.slices().collect();
+        let data = img.select(Axis(axis.to_usize()), &slices);
+        let outcome = write_image_with_extensions(
+            &out.path,
+            &packet_header(&image.header, &axis, packet),
+            &extensions,
+            &data.view(),
+            &args.overwrite.overwrite,
+        )?;
+        report_outcome(&out.path, &outcome);
+        if outcome != WriteOutcome::Skipped {
+            if let Some(sidecar) = &sidecar {
+                write_sidecar(
+                    &out.path,
+                    &for_packet(sidecar, &image.header, &axis, &slices),
+                    &args.overwrite.overwrite,
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Interleave packets back into the grid of the reference.
+///
+/// Every input is placed by its affine (see [`locate_packet`]), and together they must cover
+/// each slice of the reference exactly once.
+///
+/// # Arguments
+///
+/// * `args` - The arguments of the combine.
+/// * `inputs` - The packet files, in any order.
+/// * `output` - The path of the combined image.
+pub fn combine_packets(args: &CombineArgs, inputs: &[InputFile], output: &Path) -> Result<()> {
+    let reference = Path::new(&args.reference);
+    if !reference.exists() {
+        return Err(Error::Input(
+            "Did not find reference nifti file. Use -r to pass an existing file.".to_string(),
+        ));
+    }
+    let ref_header = read_header(reference)?;
+    if ref_header.dim[0] != 3 {
+        return Err(Error::Input(
+            "Reference nifti file must be 3D to interleave packets.".to_string(),
+        ));
+    }
+    if inputs.is_empty() {
+        return Err(Error::Input(
+            "Did not find any input files to combine.".to_string(),
+        ));
+    }
+    let ref_dims: Vec<usize> = ref_header.dim[1..4].iter().map(|&d| d as usize).collect();
+    let axis = match &args.axis.axis {
+        AxisChoice::Fixed(Direction::T) => {
+            return Err(Error::Usage(
+                "Packets are interleaved along a spatial axis, not t.".to_string(),
+            ));
+        }
+        AxisChoice::Fixed(axis) => axis.clone(),
+        AxisChoice::Guess => match slice_dim(&ref_header) {
+            Some(axis) => AXES[axis].clone(),
+            None => {
+                let first = read_header(&inputs[0].path)?;
+                let dims: Vec<usize> = first.dim[1..4].iter().map(|&d| d as usize).collect();
+                guess_dir(&dims, &ref_dims)
+            }
+        },
+    };
+    println!("Interleaving packets on axis: {:?}", axis);
+
+    // place every packet, reporting all the files that do not fit before giving up
+    let mut placed: Vec<(&InputFile, Packet)> = Vec::new();
+    let mut problems = 0;
+    for input in inputs {
+        match locate_packet(&read_header(&input.path)?, &ref_header, &axis) {
+            Ok(packet) => placed.push((input, packet)),
+            Err(message) => {
+                println!("Mismatch! {}: {}", input.path.display(), message);
+                problems += 1;
+            }
+        }
+    }
+    if problems > 0 {
+        return Err(Error::Input(format!(
+            "{} input(s) are not packets of the reference.",
+            problems
+        )));
+    }
+    let n = ref_dims[axis.to_usize()];
+    let mut owners: Vec<Vec<&Path>> = vec![Vec::new(); n];
+    for (input, packet) in &placed {
+        for slice in packet.slices() {
+            if slice >= n {
+                return Err(Error::Input(format!(
+                    "{} reaches slice {}, past the {} slices of the reference.",
+                    input.path.display(),
+                    slice + 1,
+                    n
+                )));
+            }
+            owners[slice].push(&input.path);
+        }
+    }
+    let missing: Vec<usize> = (0..n)
+        .filter(|&i| owners[i].is_empty())
+        .map(|i| i + 1)
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error::Input(format!(
+            "No packet holds slice(s) {:?} of the reference.",
+            missing
+        )));
+    }
+    if let Some(i) = (0..n).find(|&i| owners[i].len() > 1) {
+        return Err(Error::Input(format!(
+            "Slice {} is held by more than one packet: {}",
+            i + 1,
+            owners[i]
+                .iter()
+                .map(|p| p.display().to_string())
+                .collect::<Vec<String>>()
+                .join(", ")
+        )));
+    }
+
+    if args.dry_run {
+        for (input, packet) in &placed {
+            println!(
+                "Would combine: {} as slices {:?} (affine offset {}, stride {})",
+                input.path.display(),
+                packet.slices().map(|i| i + 1).collect::<Vec<usize>>(),
+                packet.offset + 1,
+                packet.stride
+            );
+        }
+        println!("Final shape: {:?}", ref_dims);
+        println!("Dry run: would write {}", output.display());
+        return Ok(());
+    }
+    if output.exists() && args.overwrite.overwrite == OverwritePolicy::Error {
+        return Err(Error::Output("Output file already exists. Please specify a different output file, remove existing file or use --overwrite.".to_string()));
+    }
+
+    let mut combined = Array3::<f64>::zeros((ref_dims[0], ref_dims[1], ref_dims[2]));
+    for (input, packet) in &placed {
+        println!("Loading: {}", input.path.display());
+        let data = read_image(&input.path)?
+            .data
+            .into_dimensionality::<Ix3>()
+            .map_err(|e| Error::Input(format!("{}: {}", input.path.display(), e)))?;
+        for (k, slice) in packet.slices().enumerate() {
+            combined
+                .index_axis_mut(Axis(axis.to_usize()), slice)
+                .assign(&data.index_axis(Axis(axis.to_usize()), k));
+        }
+    }
+    println!("Final shape: {:?}", combined.shape());
+
+    let extensions = args.extensions.select(&read_extensions(reference)?);
+    let outcome = write_image_with_extensions(
+        output,
+        &ref_header,
+        &extensions,
+        &combined.view(),
+        &args.overwrite.overwrite,
+    )?;
+    report_outcome(output, &outcome);
+    if outcome != WriteOutcome::Skipped {
+        if let Some(sidecar) = read_sidecar(reference)? {
+            write_sidecar(output, &sidecar, &args.overwrite.overwrite)?;
+        }
+    }
+    Ok(())
+}
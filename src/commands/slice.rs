@@ -0,0 +1,551 @@
+//! Split a 3D nifti file into a series of 2D slices.
+//!
+//! Each slice is saved as its own NIfTI file, optionally padded with copies of itself so the
+//! slice volume stays 3D, in a NEW directory named after the input.
+use ndarray::Ix3;
+use nifti::NiftiHeader;
+use std::fs;
+use std::path::Path;
+
+use crate::cli::{AxisChoice, OverwritePolicy, SliceArgs, SliceOrder, SplitArgs};
+use crate::commands::split;
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::header::set_slice_fields;
+use crate::io::{
+    nifti_stem, read_header, read_image, report_outcome, write_image_with_extensions, Carried,
+    WriteOutcome,
+};
+use crate::journal::{journal_name, Fingerprint, Journal};
+use crate::naming::{slice_offset_mm, NameFields, OutputNaming};
+use crate::ordering::{acquisition_groups, slice_times, write_order_table, OrderRow, TimeGroup};
+use crate::packets::{packet_affine, Packet};
+use crate::plan::{print_plan, select_shard, PlannedOutput, Shard};
+use crate::provenance::Provenance;
+use crate::sidecar::{for_packet, for_slice, read_sidecar, write_sidecar};
+use crate::slicing::{guess_dir, slice_affine, SliceIter};
+
+/// Pick the axis to slice along, guessing it from the header if it was not given.
+pub fn choose_axis(header: &NiftiHeader, choice: &AxisChoice) -> Result<Direction> {
+    let guessed_dir = guess_dir(header.dim, header.pixdim);
+    let axis = match choice {
+        AxisChoice::Guess => {
+            println!("Axis not specified. Guessing axis: {:?}", guessed_dir);
+            guessed_dir.clone()
+        }
+        AxisChoice::Fixed(Direction::T) => {
+            return Err(Error::Usage(
+                "Cannot slice a 3D volume along time. Use split-time for 4D files.".to_string(),
+            ));
+        }
+        AxisChoice::Fixed(axis) => axis.clone(),
+    };
+    if guessed_dir != axis {
+        println!("Warning! The axis specified might not be along the slice direction");
+    }
+    Ok(axis)
+}
+
+/// Plan the slice files for a 3D image.
+///
+/// By default the files are named according to the original NIfTI file, the axis along which
+/// the slices were taken, and the index of the slice. They go in a directory named after the
+/// original NIfTI file, within the directory specified by `output_basepath`. Both names can be
+/// changed with templates.
+///
+/// # Arguments
+///
+/// * `header` - The header from the original NIfTI file.
+/// * `axis` - The axis along which the slices are taken.
+/// * `padding` - How many copies of each slice to stack in each saved volume.
+/// * `output_basepath` - The directory in which to create the slice directory.
+/// * `basename` - The base name to use for the output files, typically derived from the original NIfTI file.
+/// * `naming` - The templates for the directory and file names, and whether to gzip the files.
+/// * `numbers` - The 0-based number each slice gets in its name, if not its position, such
+///   as its place in the order of acquisition.
+///
+/// # Returns
+///
+/// One `PlannedOutput` per slice, in slice order.
+pub fn plan_slices(
+    header: &NiftiHeader,
+    axis: &Direction,
+    padding: usize,
+    output_basepath: &Path,
+    basename: &str,
+    naming: &OutputNaming,
+    numbers: Option<&[usize]>,
+) -> Result<Vec<PlannedOutput>> {
+    let mut shape: Vec<usize> = header.dim[1..4].iter().map(|&d| d as usize).collect();
+    let end_index = shape[axis.to_usize()];
+    shape[axis.to_usize()] = padding.max(1);
+    let fields = |index: usize| NameFields {
+        stem: basename,
+        axis,
+        index: numbers.map_or(index, |numbers| numbers[index]),
+        count: end_index,
+        padded: padding > 1,
+        time_ms: None,
+        mm: Some(slice_offset_mm(header, axis, index)),
+    };
+    let save_dir = naming.dir_path(output_basepath, &fields(0))?;
+
+    (0..end_index)
+        .map(|index| {
+            Ok(PlannedOutput {
+                index,
+                path: naming.file_path(&save_dir, &fields(index))?,
+                shape: shape.clone(),
+                affine: slice_affine(header, axis, index),
+            })
+        })
+        .collect()
+}
+
+/// Saves the slices from a 3D array as individual NIfTI files.
+///
+/// Each planned output is written from the matching slice view, copying and padding one
+/// slice at a time.
+///
+/// # Arguments
+///
+/// * `slices` - The slices of the source image.
+/// * `outputs` - The planned slice files, from `plan_slices`.
+/// * `header` - The header from the original NIfTI file.
+/// * `carried` - The header extensions and sidecar to give every slice.
+/// * `padding` - How many copies of each slice to stack in each saved volume.
+/// * `policy` - What to do with slice files that already exist.
+/// * `journal` - The progress journal, in which each written slice is recorded.
+pub fn save_slices(
+    slices: &SliceIter,
+    outputs: &[PlannedOutput],
+    header: &NiftiHeader,
+    carried: &Carried,
+    padding: usize,
+    policy: &OverwritePolicy,
+    journal: &mut Journal,
+) -> Result<()> {
+    for out in outputs {
+        if let Some(dir) = out.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let s = slices.get(out.index).ok_or_else(|| {
+            Error::Input(format!("Slice {} is outside the image.", out.index + 1))
+        })?;
+
+        let meta = s.meta(padding);
+        let mut slice_header = meta.header(header);
+        set_slice_fields(&mut slice_header, header, s.axis(), s.index, &s.view);
+        // record where the slice came from, so it can be placed without its file name
+        let provenance = Provenance::of(&meta);
+        provenance.describe(&mut slice_header);
+
+        // save each slice as a nifti file
+        let outcome = write_image_with_extensions(
+            &out.path,
+            &slice_header,
+            &[&carried.extensions[..], &[provenance.to_extension()]].concat(),
+            &s.padded(padding).view(),
+            policy,
+        )?;
+        report_outcome(&out.path, &outcome);
+        if outcome != WriteOutcome::Skipped {
+            if let Some(sidecar) = &carried.sidecar {
+                write_sidecar(
+                    &out.path,
+                    &for_slice(sidecar, header, s.axis(), s.index, padding),
+                    policy,
+                )?;
+            }
+            journal.record(out.index, &out.path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Plan one file per group of slices acquired together, numbered in the order of acquisition.
+///
+/// Each group is written like an interleaved packet (see [`crate::packets`]), so its slices
+/// must be evenly spaced.
+///
+/// # Returns
+///
+/// One `PlannedOutput` per group, with the group's place in the order as its index.
+fn plan_groups(
+    header: &NiftiHeader,
+    axis: &Direction,
+    groups: &[TimeGroup],
+    output_basepath: &Path,
+    basename: &str,
+    naming: &OutputNaming,
+) -> Result<Vec<PlannedOutput>> {
+    let mut packets = Vec::new();
+    for group in groups {
+        let stride = match group.slices[..] {
+            [first, second, ..] => second - first,
+            _ => 1,
+        };
+        if group.slices.windows(2).any(|w| w[1] - w[0] != stride) {
+            return Err(Error::Input(format!(
+                "Slices {:?} were acquired together but are not evenly spaced, so cannot be written to one file.",
+                group.slices.iter().map(|i| i + 1).collect::<Vec<usize>>()
+            )));
+        }
+        packets.push(Packet {
+            offset: group.slices[0],
+            stride,
+            len: group.slices.len(),
+        });
+    }
+    let fields = |index: usize, packet: &Packet| NameFields {
+        stem: basename,
+        axis,
+        index,
+        count: groups.len(),
+        padded: false,
+        time_ms: None,
+        mm: Some(slice_offset_mm(header, axis, packet.offset)),
+    };
+    let save_dir = naming.dir_path(output_basepath, &fields(0, &packets[0]))?;
+    packets
+        .iter()
+        .enumerate()
+        .map(|(index, packet)| {
+            let mut shape: Vec<usize> = header.dim[1..4].iter().map(|&d| d as usize).collect();
+            shape[axis.to_usize()] = packet.len;
+            Ok(PlannedOutput {
+                index,
+                path: naming.file_path(&save_dir, &fields(index, packet))?,
+                shape,
+                affine: packet_affine(header, axis, packet),
+            })
+        })
+        .collect()
+}
+
+/// Saves each group of slices acquired together as one NIfTI file.
+///
+/// The header of a group has the slice timing fields cleared and its acquisition time added
+/// to `toffset`, as for a single slice, and records the slices it holds as its provenance.
+///
+/// # Arguments
+///
+/// * `slices` - The slices of the source image.
+/// * `outputs` - The planned group files, from `plan_groups`.
+/// * `groups` - The groups of slices, in the order they were acquired.
+/// * `carried` - The header extensions and sidecar to give every group.
+/// * `policy` - What to do with files that already exist.
+/// * `journal` - The progress journal, in which each written group is recorded.
+fn save_groups(
+    slices: &SliceIter,
+    outputs: &[PlannedOutput],
+    groups: &[TimeGroup],
+    carried: &Carried,
+    policy: &OverwritePolicy,
+    journal: &mut Journal,
+) -> Result<()> {
+    let header = slices.header();
+    for out in outputs {
+        if let Some(dir) = out.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let group = &groups[out.index];
+        let first = group.slices[0];
+        let data = slices.select(&group.slices);
+        let mut group_header = header.clone();
+        group_header.set_affine(&out.affine);
+        set_slice_fields(
+            &mut group_header,
+            header,
+            slices.axis(),
+            first,
+            &data.view(),
+        );
+        // the time of the group may come from the sidecar rather than the header
+        group_header.toffset = header.toffset + group.time as f32;
+        let meta = slices
+            .get(first)
+            .expect("groups hold slices of the source")
+            .meta(1);
+        let provenance = Provenance::of_group(&meta, &group.slices);
+        provenance.describe(&mut group_header);
+
+        let outcome = write_image_with_extensions(
+            &out.path,
+            &group_header,
+            &[&carried.extensions[..], &[provenance.to_extension()]].concat(),
+            &data.view(),
+            policy,
+        )?;
+        report_outcome(&out.path, &outcome);
+        if outcome != WriteOutcome::Skipped {
+            if let Some(sidecar) = &carried.sidecar {
+                write_sidecar(
+                    &out.path,
+                    &for_packet(sidecar, header, slices.axis(), &group.slices),
+                    policy,
+                )?;
+            }
+            journal.record(out.index, &out.path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Slice a 3D file, or only print what would be written if `args.dry_run` is set.
+fn slice_file(path: &Path, args: &SliceArgs) -> Result<()> {
+    let basename = nifti_stem(path)?;
+    let output_basepath = Path::new(&args.output);
+    let padding = args.pad.pad;
+
+    let header = read_header(path)?;
+    let axis = choose_axis(&header, &args.axis.axis)?;
+    let naming = OutputNaming::slices(
+        args.naming.name_template.as_ref(),
+        args.naming.dir_name.as_ref(),
+        args.compress.compress,
+    );
+    let sidecar = read_sidecar(path)?;
+
+    // slices ordered by time get the number of their group in their name
+    let groups = match args.order.order {
+        SliceOrder::Space => {
+            if args.order.group_simultaneous {
+                return Err(Error::Usage(
+                    "--group-simultaneous needs --order time.".to_string(),
+                ));
+            }
+            None
+        }
+        SliceOrder::Time => {
+            let times = slice_times(&header, sidecar.as_ref(), &axis).ok_or_else(|| {
+                Error::Input(format!(
+                    "{} has no slice timing along axis {} in its header or sidecar.",
+                    path.display(),
+                    axis
+                ))
+            })?;
+            Some(acquisition_groups(&times, args.order.group_simultaneous))
+        }
+    };
+    let outputs = match &groups {
+        Some(groups) if args.order.group_simultaneous => {
+            if padding > 1 {
+                return Err(Error::Usage(
+                    "--group-simultaneous cannot be used with --pad.".to_string(),
+                ));
+            }
+            plan_groups(&header, &axis, groups, output_basepath, &basename, &naming)?
+        }
+        Some(groups) => {
+            let mut numbers = vec![0; groups.len()];
+            for (number, group) in groups.iter().enumerate() {
+                numbers[group.slices[0]] = number;
+            }
+            plan_slices(
+                &header,
+                &axis,
+                padding,
+                output_basepath,
+                &basename,
+                &naming,
+                Some(&numbers),
+            )?
+        }
+        None => plan_slices(
+            &header,
+            &axis,
+            padding,
+            output_basepath,
+            &basename,
+            &naming,
+            None,
+        )?,
+    };
+    if let (Some(groups), false) = (&groups, args.dry_run) {
+        let table = write_order_table(
+            outputs
+                .first()
+                .and_then(|out| out.path.parent())
+                .unwrap_or_else(|| Path::new(".")),
+            &basename,
+            &order_rows(&outputs, groups, args.order.group_simultaneous, &axis),
+        )?;
+        println!("Slice order: {}", table.display());
+    }
+    let total = outputs.len();
+    let outputs = select_shard(outputs, args.shard.shard.as_ref());
+    if let Some(shard) = &args.shard.shard {
+        println!("Shard {}: {} of {} file(s)", shard, outputs.len(), total);
+    }
+    if args.dry_run {
+        print_plan(&outputs);
+        return Ok(());
+    }
+
+    let options = [
+        ("axis", axis.to_string()),
+        ("pad", padding.to_string()),
+        ("extensions", args.extensions.describe()),
+        ("order", format!("{:?}", args.order.order).to_lowercase()),
+        (
+            "group simultaneous",
+            args.order.group_simultaneous.to_string(),
+        ),
+    ];
+    let mut journal = open_journal(
+        &outputs,
+        path,
+        &options,
+        args.resume.resume,
+        args.shard.shard.as_ref(),
+    )?;
+    let outputs = journal.pending(&outputs);
+    if outputs.is_empty() {
+        println!("All slices are already done.");
+        return Ok(());
+    }
+    let policy = journal.pending_policy(&args.overwrite.overwrite);
+
+    println!("Slicing on axis: {:?}", axis);
+    if padding > 1 {
+        println!("Padding slices with {} copies", padding);
+    }
+    let image = read_image(path)?;
+    let header = &image.header;
+    let img = image
+        .data
+        .into_dimensionality::<Ix3>()
+        .map_err(|e| Error::Input(e.to_string()))?;
+    let carried = Carried {
+        extensions: args.extensions.select(&image.extensions),
+        sidecar,
+        gradients: None,
+    };
+    let slices = SliceIter::new(img.view(), &axis, header).with_source_path(path);
+    if let (Some(groups), true) = (&groups, args.order.group_simultaneous) {
+        return save_groups(&slices, &outputs, groups, &carried, &policy, &mut journal);
+    }
+    save_slices(
+        &slices,
+        &outputs,
+        header,
+        &carried,
+        padding,
+        &policy,
+        &mut journal,
+    )
+}
+
+/// The rows of the slice order table for time-ordered outputs.
+///
+/// # Arguments
+///
+/// * `outputs` - Every planned output, by slice position or, if `grouped`, by group.
+/// * `groups` - The groups of slices in the order they were acquired.
+/// * `grouped` - Whether each output holds a whole group.
+/// * `axis` - The axis the slices are cut along.
+fn order_rows(
+    outputs: &[PlannedOutput],
+    groups: &[TimeGroup],
+    grouped: bool,
+    axis: &Direction,
+) -> Vec<OrderRow> {
+    let mut rows: Vec<OrderRow> = Vec::new();
+    for (number, group) in groups.iter().enumerate() {
+        if grouped {
+            rows.push(OrderRow {
+                index: number + 1,
+                file: outputs[number].path.clone(),
+                axis: axis.clone(),
+                slices: group.slices.clone(),
+                time: group.time,
+            });
+        } else {
+            let slice = group.slices[0];
+            rows.push(OrderRow {
+                index: number + 1,
+                file: outputs[slice].path.clone(),
+                axis: axis.clone(),
+                slices: vec![slice],
+                time: group.time,
+            });
+        }
+    }
+    rows
+}
+
+/// Open the progress journal in the directory of the planned outputs.
+///
+/// # Arguments
+///
+/// * `outputs` - The planned outputs of the run.
+/// * `source` - The file the outputs are cut from.
+/// * `options` - The options the outputs depend on, which a resumed journal must share.
+/// * `resume` - Whether to pick up from an existing journal.
+/// * `shard` - The shard being written, which gets a journal of its own.
+pub fn open_journal(
+    outputs: &[PlannedOutput],
+    source: &Path,
+    options: &[(&str, String)],
+    resume: bool,
+    shard: Option<&Shard>,
+) -> Result<Journal> {
+    let dir = outputs
+        .first()
+        .and_then(|out| out.path.parent())
+        .unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+    Journal::open(
+        &dir.join(journal_name(shard)),
+        Some(Fingerprint::of(source)?),
+        options,
+        resume,
+    )
+}
+
+/// The number of dimensions of a file, from its header.
+fn ndim(path: &Path) -> Result<u16> {
+    Ok(read_header(path)?.dim[0])
+}
+
+/// Run the `slice` subcommand: slice a 3D file into 2D slices.
+pub fn run(args: &SliceArgs) -> Result<()> {
+    let path = Path::new(&args.input);
+    match ndim(path)? {
+        3 => slice_file(path, args),
+        4 => Err(Error::Input(
+            "Input is 4D. Use split-time to split it into 3D volumes first.".to_string(),
+        )),
+        _ => Err(Error::Input("Input nifti file must be 3D.".to_string())),
+    }
+}
+
+/// Slice a 3D file or split a 4D file across time, whichever fits the input.
+///
+/// This is what slicenii did before it had subcommands.
+pub fn run_auto(args: &SliceArgs) -> Result<()> {
+    let path = Path::new(&args.input);
+    match ndim(path)? {
+        3 => slice_file(path, args),
+        4 => {
+            println!("4D image detected, splitting into 3D volumes across time. Re-run on invidual 3D volumes to slice in space.");
+            let split_args = SplitArgs {
+                input: args.input.clone(),
+                output: args.output.clone(),
+                group_by: None,
+                compress: args.compress.clone(),
+                extensions: args.extensions.clone(),
+                overwrite: args.overwrite.clone(),
+                resume: args.resume.clone(),
+                shard: args.shard.clone(),
+                naming: args.naming.clone(),
+                dry_run: args.dry_run,
+            };
+            split::run(&split_args)
+        }
+        _ => Err(Error::Input(
+            "Input nifti file must be 4D or 3D.".to_string(),
+        )),
+    }
+}
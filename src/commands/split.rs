@@ -0,0 +1,249 @@
+//! Split a 4D nifti file into a series of 3D volumes across time.
+use ndarray::Ix4;
+use nifti::NiftiHeader;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::{OverwritePolicy, SplitArgs};
+use crate::commands::groups::split_groups;
+use crate::commands::slice::open_journal;
+use crate::common::Direction;
+use crate::dwi::Gradients;
+use crate::error::{Error, Result};
+use crate::header::{seconds_per_unit, unit_name, units, volume_onset};
+use crate::io::{
+    nifti_stem, read_header, read_image, report_outcome, write_image_with_extensions,
+    write_text_atomic, Carried, WriteOutcome,
+};
+use crate::journal::Journal;
+use crate::naming::{volume_time_ms, NameFields, OutputNaming};
+use crate::plan::{print_plan, select_shard, PlannedOutput};
+use crate::provenance::Provenance;
+use crate::sidecar::{for_volume, read_sidecar, write_sidecar};
+use crate::slicing::VolIter;
+
+/// Plan the volume files for a 4D image.
+///
+/// By default the files are named after the original NIfTI file and the index of the volume,
+/// and go in a directory named after the original file within `output_basepath`. Both names
+/// can be changed with templates.
+///
+/// # Arguments
+///
+/// * `header` - The header from the original NIfTI file.
+/// * `output_basepath` - The directory in which to create the volume directory.
+/// * `basename` - The base name to use for the output files.
+/// * `naming` - The templates for the directory and file names, and whether to gzip the files.
+///
+/// # Returns
+///
+/// One `PlannedOutput` per volume, in time order.
+pub fn plan_vols(
+    header: &NiftiHeader,
+    output_basepath: &Path,
+    basename: &str,
+    naming: &OutputNaming,
+) -> Result<Vec<PlannedOutput>> {
+    let shape: Vec<usize> = header.dim[1..4].iter().map(|&d| d as usize).collect();
+    let affine = header.affine::<f64>();
+    let count = header.dim[4] as usize;
+    let fields = |index| NameFields {
+        stem: basename,
+        axis: &Direction::T,
+        index,
+        count,
+        padded: false,
+        time_ms: volume_time_ms(header, index),
+        mm: None,
+    };
+    let save_dir = naming.dir_path(output_basepath, &fields(0))?;
+
+    (0..count)
+        .map(|index| {
+            Ok(PlannedOutput {
+                index,
+                path: naming.file_path(&save_dir, &fields(index))?,
+                shape: shape.clone(),
+                affine,
+            })
+        })
+        .collect()
+}
+
+/// Saves the volumes of a 4D array as individual NIfTI files.
+///
+/// # Arguments
+///
+/// * `vols` - The volumes of the source image.
+/// * `outputs` - The planned volume files, from `plan_vols`.
+/// * `header` - The header from the original NIfTI file.
+/// * `carried` - The header extensions, sidecar and gradients to give every volume.
+/// * `policy` - What to do with volume files that already exist.
+/// * `journal` - The progress journal, in which each written volume is recorded.
+pub fn save_vols(
+    vols: &VolIter,
+    outputs: &[PlannedOutput],
+    header: &NiftiHeader,
+    carried: &Carried,
+    policy: &OverwritePolicy,
+    journal: &mut Journal,
+) -> Result<()> {
+    for out in outputs {
+        if let Some(dir) = out.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let v = vols.get(out.index).ok_or_else(|| {
+            Error::Input(format!("Volume {} is outside the image.", out.index + 1))
+        })?;
+
+        // the chunk header carries the time of the volume
+        let meta = v.meta();
+        let mut vol_header = meta.header(header);
+        let provenance = Provenance::of(&meta);
+        provenance.describe(&mut vol_header);
+
+        // save each volume as a nifti file
+        let outcome = write_image_with_extensions(
+            &out.path,
+            &vol_header,
+            &[&carried.extensions[..], &[provenance.to_extension()]].concat(),
+            &v.view,
+            policy,
+        )?;
+        report_outcome(&out.path, &outcome);
+        if outcome != WriteOutcome::Skipped {
+            if let Some(sidecar) = &carried.sidecar {
+                // the offset of the volume from the first, in seconds
+                let offset_s = seconds_per_unit(header).map(|seconds| {
+                    (volume_onset(header, v.index) - header.toffset as f64) * seconds
+                });
+                write_sidecar(&out.path, &for_volume(sidecar, offset_s), policy)?;
+            }
+            if let Some(gradients) = &carried.gradients {
+                gradients.subset(&[v.index]).write(&out.path, policy)?;
+            }
+            journal.record(out.index, &out.path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a TSV listing the onset of every volume, in the units of the source header.
+///
+/// The table has the columns `index` (counting from 1), `file`, `onset` and `unit`, and is
+/// written next to the volumes as `<stem>_onsets.tsv`. It lists every volume, so of the shards
+/// of a run only the first writes it.
+///
+/// # Returns
+///
+/// The path of the table.
+pub fn write_onsets(
+    header: &NiftiHeader,
+    outputs: &[PlannedOutput],
+    basename: &str,
+) -> Result<PathBuf> {
+    let dir = outputs
+        .first()
+        .and_then(|out| out.path.parent())
+        .unwrap_or_else(|| Path::new("."));
+    fs::create_dir_all(dir)?;
+    let unit = unit_name(units(header).1);
+    let mut table = String::from("index\tfile\tonset\tunit\n");
+    for out in outputs {
+        let file = out.path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        table.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            out.index + 1,
+            file,
+            volume_onset(header, out.index),
+            unit
+        ));
+    }
+    // replaced whatever the policy, as it is the same table on every run
+    let path = dir.join(format!("{}_onsets.tsv", basename));
+    write_text_atomic(&path, &table, &OverwritePolicy::Replace)?;
+    Ok(path)
+}
+
+/// Run the `split-time` subcommand: split a 4D file into 3D volumes.
+pub fn run(args: &SplitArgs) -> Result<()> {
+    split_series(args, None)
+}
+
+/// Split a 4D file into 3D volumes, giving each volume its gradient if there are any.
+pub fn split_series(args: &SplitArgs, gradients: Option<Gradients>) -> Result<()> {
+    let path = Path::new(&args.input);
+    let basename = nifti_stem(path)?;
+    let output_basepath = Path::new(&args.output);
+
+    let header = read_header(path)?;
+    if header.dim[0] != 4 {
+        return Err(Error::Input(
+            "Input nifti file must be 4D to split across time.".to_string(),
+        ));
+    }
+    if let Some(group_by) = &args.group_by {
+        return split_groups(args, &header, group_by);
+    }
+    let outputs = plan_vols(
+        &header,
+        output_basepath,
+        &basename,
+        &OutputNaming::vols(
+            args.naming.name_template.as_ref(),
+            args.naming.dir_name.as_ref(),
+            args.compress.compress,
+        ),
+    )?;
+    let first_shard = args
+        .shard
+        .shard
+        .as_ref()
+        .is_none_or(|shard| shard.index == 1);
+    if !args.dry_run && first_shard {
+        let onsets = write_onsets(&header, &outputs, &basename)?;
+        println!("Volume onsets: {}", onsets.display());
+    }
+    let total = outputs.len();
+    let outputs = select_shard(outputs, args.shard.shard.as_ref());
+    if let Some(shard) = &args.shard.shard {
+        println!("Shard {}: {} of {} file(s)", shard, outputs.len(), total);
+    }
+    if args.dry_run {
+        print_plan(&outputs);
+        return Ok(());
+    }
+
+    let mut journal = open_journal(
+        &outputs,
+        path,
+        &[("extensions", args.extensions.describe())],
+        args.resume.resume,
+        args.shard.shard.as_ref(),
+    )?;
+    let outputs = journal.pending(&outputs);
+    if outputs.is_empty() {
+        println!("All volumes are already done.");
+        return Ok(());
+    }
+    let policy = journal.pending_policy(&args.overwrite.overwrite);
+
+    let image = read_image(path)?;
+    let img = image
+        .data
+        .into_dimensionality::<Ix4>()
+        .map_err(|e| Error::Input(e.to_string()))?;
+    let vols = VolIter::new(img.view(), &image.header).with_source_path(path);
+    save_vols(
+        &vols,
+        &outputs,
+        &image.header,
+        &Carried {
+            extensions: args.extensions.select(&image.extensions),
+            sidecar: read_sidecar(path)?,
+            gradients,
+        },
+        &policy,
+        &mut journal,
+    )
+}
@@ -0,0 +1,229 @@
+//! Prepare the b0 volumes of two series with opposite phase encoding for FSL's TOPUP.
+//!
+//! The b0 volumes are taken from each series, joined into one 4D file with the AP volumes
+//! first, and made even in every spatial dimension. `acqparams.txt` gets one line per volume
+//! from the JSON sidecars, and the change to the grid is recorded so `combine --topup-grid`
+//! can put the results back on the original grid.
+use ndarray::{Array4, Axis, Ix3, Ix4};
+use nifti::NiftiHeader;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::TopupArgs;
+use crate::dwi::Gradients;
+use crate::error::{Error, Result};
+use crate::io::{
+    nifti_extension, read_header, read_image, report_outcome, write_image, write_text_atomic,
+    WriteOutcome,
+};
+use crate::sidecar::read_sidecar;
+use crate::topup::{acqparams_line, EvenGrid};
+use crate::validate::AFFINE_TOLERANCE;
+
+/// The phase encoding directions assumed when a series has no sidecar saying otherwise.
+const AP_DIRECTION: &str = "j-";
+const PA_DIRECTION: &str = "j";
+
+/// Series is one of the two inputs, with the volumes taken from it.
+struct Series {
+    path: PathBuf,
+    header: NiftiHeader,
+    /// The 0-based volumes to take.
+    volumes: Vec<usize>,
+}
+
+/// Work out which volumes to take from a series.
+///
+/// # Arguments
+///
+/// * `path` - The series.
+/// * `explicit` - The volumes given on the command line, counting from 1.
+/// * `threshold` - The largest b-value taken as b0.
+fn choose_volumes(path: &Path, explicit: &[usize], threshold: f64) -> Result<Series> {
+    let header = read_header(path)?;
+    let count = match header.dim[0] {
+        3 => 1,
+        4 => header.dim[4] as usize,
+        _ => {
+            return Err(Error::Input(format!(
+                "{} must be 3D or 4D.",
+                path.display()
+            )))
+        }
+    };
+    let volumes: Vec<usize> = if !explicit.is_empty() {
+        if let Some(&bad) = explicit.iter().find(|&&v| v == 0 || v > count) {
+            return Err(Error::Usage(format!(
+                "Volume {} is outside {}, which has {} volume(s).",
+                bad,
+                path.display(),
+                count
+            )));
+        }
+        explicit.iter().map(|v| v - 1).collect()
+    } else if let Some(gradients) = Gradients::read(path)? {
+        if gradients.len() != count {
+            return Err(Error::Input(format!(
+                "{} has {} volumes but its bval/bvec files have {} gradients.",
+                path.display(),
+                count,
+                gradients.len()
+            )));
+        }
+        (0..count)
+            .filter(|&i| gradients.bvals[i] <= threshold)
+            .collect()
+    } else if count == 1 {
+        vec![0]
+    } else {
+        return Err(Error::Usage(format!(
+            "{} has no .bval file to find its b0 volumes. Give them with --ap-volumes or --pa-volumes.",
+            path.display()
+        )));
+    };
+    if volumes.is_empty() {
+        return Err(Error::Input(format!(
+            "{} has no b0 volumes (b-value at most {}).",
+            path.display(),
+            threshold
+        )));
+    }
+    Ok(Series {
+        path: path.to_path_buf(),
+        header,
+        volumes,
+    })
+}
+
+/// Check that the two series are on the same grid.
+fn check_grids(ap: &Series, pa: &Series) -> Result<()> {
+    if ap.header.dim[1..4] != pa.header.dim[1..4] {
+        return Err(Error::Input(format!(
+            "Mismatch! {}: shape {:?}, expected {:?} as in {}",
+            pa.path.display(),
+            &pa.header.dim[1..4],
+            &ap.header.dim[1..4],
+            ap.path.display()
+        )));
+    }
+    let difference = (ap.header.affine::<f64>() - pa.header.affine::<f64>()).amax();
+    if difference > AFFINE_TOLERANCE {
+        return Err(Error::Input(format!(
+            "Mismatch! {}: affine differs from {} by up to {}",
+            pa.path.display(),
+            ap.path.display(),
+            difference
+        )));
+    }
+    Ok(())
+}
+
+/// The lines of `acqparams.txt` for the volumes taken from one series.
+fn acqparams_lines(
+    series: &Series,
+    default_direction: &str,
+    readout_time: Option<f64>,
+) -> Result<Vec<String>> {
+    let (line, defaulted) = acqparams_line(
+        &series.path,
+        read_sidecar(&series.path)?.as_ref(),
+        default_direction,
+        readout_time,
+    )?;
+    if defaulted {
+        println!(
+            "Warning! {} has no PhaseEncodingDirection in its sidecar, assuming {}.",
+            series.path.display(),
+            default_direction
+        );
+    }
+    Ok(vec![line; series.volumes.len()])
+}
+
+/// Read the volumes taken from a series as a 4D array.
+fn read_volumes(series: &Series) -> Result<Array4<f64>> {
+    let image = read_image(&series.path)?;
+    let data = match series.header.dim[0] {
+        3 => image
+            .data
+            .into_dimensionality::<Ix3>()
+            .map_err(|e| Error::Input(e.to_string()))?
+            .insert_axis(Axis(3)),
+        _ => image
+            .data
+            .into_dimensionality::<Ix4>()
+            .map_err(|e| Error::Input(e.to_string()))?,
+    };
+    Ok(data.select(Axis(3), &series.volumes))
+}
+
+/// Run the `topup` subcommand: prepare the b0 volumes of two series for TOPUP.
+pub fn run(args: &TopupArgs) -> Result<()> {
+    let ap = choose_volumes(Path::new(&args.ap), &args.ap_volumes, args.b0_threshold)?;
+    let pa = choose_volumes(Path::new(&args.pa), &args.pa_volumes, args.b0_threshold)?;
+    check_grids(&ap, &pa)?;
+
+    let shape = [
+        ap.header.dim[1] as usize,
+        ap.header.dim[2] as usize,
+        ap.header.dim[3] as usize,
+    ];
+    let grid = EvenGrid::of(shape, args.crop)?;
+    let mut acqparams = acqparams_lines(&ap, AP_DIRECTION, args.readout_time)?;
+    acqparams.extend(acqparams_lines(&pa, PA_DIRECTION, args.readout_time)?);
+
+    let dir = Path::new(&args.output);
+    let b0_path = dir.join(format!(
+        "{}.{}",
+        args.name,
+        nifti_extension(args.compress.compress)
+    ));
+    let acqparams_path = dir.join("acqparams.txt");
+    let grid_path = dir.join(format!("{}_grid.json", args.name));
+    let one_based = |volumes: &[usize]| volumes.iter().map(|v| v + 1).collect::<Vec<usize>>();
+    println!(
+        "Taking volumes {:?} of {} and {:?} of {}",
+        one_based(&ap.volumes),
+        ap.path.display(),
+        one_based(&pa.volumes),
+        pa.path.display()
+    );
+    if grid.is_unchanged() {
+        println!("Grid {:?} is already even", grid.source_shape);
+    } else {
+        println!(
+            "{} grid {:?} to {:?}",
+            if grid.cropped { "Cropping" } else { "Padding" },
+            grid.source_shape,
+            grid.even_shape
+        );
+    }
+    if args.dry_run {
+        println!("Would write: {}", b0_path.display());
+        println!("Would write: {}", acqparams_path.display());
+        println!("Would write: {}", grid_path.display());
+        return Ok(());
+    }
+
+    let joined = ndarray::concatenate(
+        Axis(3),
+        &[read_volumes(&ap)?.view(), read_volumes(&pa)?.view()],
+    )
+    .map_err(|e| Error::Input(e.to_string()))?;
+    let even = grid.apply(&joined);
+
+    fs::create_dir_all(dir)?;
+    let mut header = ap.header.clone();
+    header.dim[0] = 4;
+    header.dim[4] = even.shape()[3] as u16;
+    let outcome = write_image(&b0_path, &header, &even.view(), &args.overwrite.overwrite)?;
+    report_outcome(&b0_path, &outcome);
+    if outcome != WriteOutcome::Skipped {
+        let policy = &args.overwrite.overwrite;
+        write_text_atomic(&acqparams_path, &(acqparams.join("\n") + "\n"), policy)?;
+        grid.write(&grid_path, policy)?;
+        println!("Acquisition parameters: {}", acqparams_path.display());
+        println!("Grid record: {}", grid_path.display());
+    }
+    Ok(())
+}
@@ -0,0 +1,252 @@
+//! Check that a combined image matches its reference and, optionally, the original source.
+//!
+//! The geometry (dimensions and affine) is compared with the reference. When the source the
+//! slices were cut from is given, the voxels are compared too, and any slice (or volume, for
+//! 4D images) whose largest difference exceeds the tolerance is listed. Any mismatch is a
+//! failed check, so the command exits non-zero and can gate CI or cluster jobs.
+use ndarray::{ArrayD, Axis};
+use std::path::Path;
+
+use crate::cli::VerifyArgs;
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::io::{read_header, read_image};
+use crate::slicing::guess_dir;
+
+/// VoxelDiff summarises the voxel-wise differences between two images.
+#[derive(Debug)]
+pub struct VoxelDiff {
+    /// The largest absolute difference, NaN if any difference is NaN.
+    pub max_abs: f64,
+    /// The mean absolute difference.
+    pub mean_abs: f64,
+    /// The 0-based indices along the compared axis whose largest difference exceeds the
+    /// tolerance or is NaN.
+    pub differing: Vec<usize>,
+}
+
+/// Compare two images of the same shape voxel by voxel.
+///
+/// # Arguments
+///
+/// * `a` - The first image.
+/// * `b` - The second image, with the same shape as `a`.
+/// * `axis` - The axis along which to report differing slices.
+/// * `tolerance` - The largest absolute difference a slice may have before it is reported.
+///
+/// # Returns
+///
+/// The maximum and mean absolute difference and the slices that differ. A NaN difference,
+/// from NaN in either image, counts as a difference beyond any tolerance.
+pub fn voxel_diff(a: &ArrayD<f64>, b: &ArrayD<f64>, axis: &Direction, tolerance: f64) -> VoxelDiff {
+    let diff = (a - b).mapv(f64::abs);
+    // f64::max drops NaN, so a NaN difference has to be carried through by hand
+    let max_abs = diff.iter().cloned().fold(0.0, |max: f64, d| {
+        if d.is_nan() || max.is_nan() {
+            f64::NAN
+        } else {
+            max.max(d)
+        }
+    });
+    let mean_abs = diff.mean().unwrap_or(0.0);
+    let mut differing = Vec::new();
+    let a = axis.to_usize();
+    if a < diff.ndim() {
+        for (i, plane) in diff.axis_iter(Axis(a)).enumerate() {
+            if plane.iter().any(|&d| d > tolerance || d.is_nan()) {
+                differing.push(i);
+            }
+        }
+    }
+    VoxelDiff {
+        max_abs,
+        mean_abs,
+        differing,
+    }
+}
+
+/// Run the `verify` subcommand.
+///
+/// Returns a check error if the dimensions or the affine of the combined image differ from the
+/// reference, or if its voxels differ from the source by more than the tolerance.
+pub fn run(args: &VerifyArgs) -> Result<()> {
+    let combined_path = Path::new(&args.input);
+    let combined = read_header(combined_path)?;
+    let reference = read_header(Path::new(&args.reference))?;
+
+    let mut problems = Vec::new();
+    let ndim = combined.dim[0].max(reference.dim[0]) as usize;
+    if combined.dim[..=ndim.min(7)] != reference.dim[..=ndim.min(7)] {
+        problems.push(format!(
+            "Dimensions {:?} do not match reference {:?}",
+            &combined.dim[1..=combined.dim[0].min(7) as usize],
+            &reference.dim[1..=reference.dim[0].min(7) as usize]
+        ));
+    }
+    let affine_diff = (combined.affine::<f64>() - reference.affine::<f64>())
+        .abs()
+        .max();
+    println!("Largest affine difference: {}", affine_diff);
+    if affine_diff > args.affine_tolerance {
+        problems.push(format!(
+            "Affine differs from reference by up to {} (tolerance {})",
+            affine_diff, args.affine_tolerance
+        ));
+    }
+
+    if let Some(source) = &args.source {
+        let combined_img = read_image(combined_path)?;
+        let source_img = read_image(Path::new(source))?;
+        if combined_img.data.shape() != source_img.data.shape() {
+            problems.push(format!(
+                "Shape {:?} does not match source shape {:?}",
+                combined_img.data.shape(),
+                source_img.data.shape()
+            ));
+        } else {
+            let axis = if source_img.data.ndim() == 4 {
+                Direction::T
+            } else {
+                let guessed = guess_dir(source_img.header.dim, source_img.header.pixdim);
+                args.axis.axis.resolve(&guessed)
+            };
+            if axis.to_usize() >= source_img.data.ndim() {
+                return Err(Error::Usage(format!(
+                    "Axis {} is not a dimension of the {}D source.",
+                    axis,
+                    source_img.data.ndim()
+                )));
+            }
+            let diff = voxel_diff(&combined_img.data, &source_img.data, &axis, args.tolerance);
+            println!("Max absolute difference: {}", diff.max_abs);
+            println!("Mean absolute difference: {}", diff.mean_abs);
+            if diff.max_abs > args.tolerance || diff.max_abs.is_nan() {
+                let indices: Vec<String> =
+                    diff.differing.iter().map(|i| (i + 1).to_string()).collect();
+                problems.push(format!(
+                    "Voxels differ from source by up to {} (tolerance {}) in {} of {} along axis {:?}: {}",
+                    diff.max_abs,
+                    args.tolerance,
+                    diff.differing.len(),
+                    combined_img.data.shape()[axis.to_usize()],
+                    axis,
+                    indices.join(", ")
+                ));
+            }
+        }
+    }
+
+    if problems.is_empty() {
+        println!("OK: {} matches {}", args.input, args.reference);
+        Ok(())
+    } else {
+        for problem in &problems {
+            eprintln!("Mismatch! {}", problem);
+        }
+        Err(Error::Check(format!(
+            "{} failed verification with {} problem(s)",
+            args.input,
+            problems.len()
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::{Matrix4, Vector4};
+    use ndarray::{Array3, ArrayD, IxDyn};
+    use nifti::NiftiHeader;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use crate::cli::{AxisArg, AxisChoice, OverwritePolicy};
+    use crate::io::write_image;
+
+    /// Write `data` with 2 mm voxels, or `voxel` mm along x, to `dir`.
+    fn write(dir: &Path, name: &str, data: &ArrayD<f64>, voxel: f64) -> String {
+        let mut header = NiftiHeader {
+            datatype: 64,
+            bitpix: 64,
+            ..Default::default()
+        };
+        header.set_affine(&Matrix4::from_diagonal(&Vector4::new(voxel, 2.0, 2.0, 1.0)));
+        let path = dir.join(name);
+        write_image(&path, &header, &data.view(), &OverwritePolicy::Replace).unwrap();
+        path.display().to_string()
+    }
+
+    fn verify(
+        input: &str,
+        reference: &str,
+        source: Option<&str>,
+        axis: AxisChoice,
+        tolerance: f64,
+    ) -> Result<()> {
+        run(&VerifyArgs {
+            input: input.to_string(),
+            reference: reference.to_string(),
+            source: source.map(str::to_string),
+            axis: AxisArg { axis },
+            tolerance,
+            affine_tolerance: 1e-4,
+        })
+    }
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("slicenii-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn image() -> ArrayD<f64> {
+        Array3::from_shape_fn((4, 3, 3), |(i, j, k)| (i + j + k) as f64).into_dyn()
+    }
+
+    #[test]
+    fn reports_changed_voxels_beyond_the_tolerance() {
+        let dir = scratch("verify-voxels");
+        let data = image();
+        let src = write(&dir, "src.nii", &data, 2.0);
+        let mut changed = data.clone();
+        changed[[1, 2, 2]] += 0.5;
+        let other = write(&dir, "changed.nii", &changed, 2.0);
+
+        let x = || AxisChoice::Fixed(Direction::X);
+        verify(&src, &src, Some(&src), x(), 0.0).unwrap();
+        let strict = verify(&other, &src, Some(&src), x(), 0.0);
+        let loose = verify(&other, &src, Some(&src), x(), 0.5);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(strict, Err(Error::Check(_))));
+        loose.unwrap();
+        assert_eq!(
+            voxel_diff(&changed, &data, &Direction::X, 0.0).differing,
+            vec![1]
+        );
+    }
+
+    #[test]
+    fn reports_other_geometry() {
+        let dir = scratch("verify-geometry");
+        let src = write(&dir, "src.nii", &image(), 2.0);
+        let wider = write(&dir, "wider.nii", &ArrayD::zeros(IxDyn(&[5, 3, 3])), 2.0);
+        let stretched = write(&dir, "stretched.nii", &image(), 2.5);
+        let shapes = verify(&wider, &src, None, AxisChoice::Guess, 0.0);
+        let affines = verify(&stretched, &src, None, AxisChoice::Guess, 0.0);
+        let axis = verify(&src, &src, Some(&src), AxisChoice::Fixed(Direction::T), 0.0);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(shapes, Err(Error::Check(_))));
+        assert!(matches!(affines, Err(Error::Check(_))));
+        assert!(matches!(axis, Err(Error::Usage(_))));
+    }
+
+    #[test]
+    fn counts_nan_as_a_difference() {
+        let a = image();
+        let mut b = a.clone();
+        b[[3, 0, 0]] = f64::NAN;
+        let diff = voxel_diff(&a, &b, &Direction::X, 10.0);
+        assert!(diff.max_abs.is_nan());
+        assert_eq!(diff.differing, vec![3]);
+    }
+}
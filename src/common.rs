@@ -1,9 +1,14 @@
 //! This file provides common data structures and utilities used across the slicenii and combinenii utilities.
+use nalgebra::Matrix4;
 use ndarray::Array3;
+use nifti::NiftiHeader;
+use serde::{Deserialize, Serialize};
 use std::fmt;
+use std::ops::Range;
+use std::path::PathBuf;
 
 /// The Direction enum represents the three spatial axes (X, Y, Z) in 3D space.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Direction {
     X,
     Y,
@@ -29,35 +34,226 @@ impl fmt::Display for Direction {
             Direction::X => write!(f, "0"),
             Direction::Y => write!(f, "1"),
             Direction::Z => write!(f, "2"),
-            Direction::T => write!(f, "2"),
+            Direction::T => write!(f, "3"),
         }
     }
 }
 
+/// SourceInfo records which image a chunk was cut from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SourceInfo {
+    /// The path of the source file, if it was read from disk.
+    pub path: Option<PathBuf>,
+    /// The `dim` field of the source header.
+    pub dim: [u16; 8],
+    /// The `pixdim` field of the source header.
+    pub pixdim: [f32; 8],
+    /// The NIfTI datatype code of the source.
+    pub datatype: i16,
+    /// The affine of the source.
+    pub affine: Matrix4<f64>,
+    /// The `toffset` field of the source header.
+    pub toffset: f32,
+}
+
+impl SourceInfo {
+    /// Collect the provenance fields from a source header.
+    pub fn from_header(header: &NiftiHeader, path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            dim: header.dim,
+            pixdim: header.pixdim,
+            datatype: header.datatype,
+            affine: header.affine::<f64>(),
+            toffset: header.toffset,
+        }
+    }
+}
+
+/// ChunkMeta describes where a chunk (a slice or a volume) sits within its source image.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ChunkMeta {
+    /// The axis the chunk was cut along, if known. Volumes from a 4D series use
+    /// `Direction::T`; chunks read back from files have none until they are placed.
+    pub axis: Option<Direction>,
+    /// The range of source indices along `axis` covered by the chunk.
+    pub range: Range<usize>,
+    /// How many copies of the chunk are stacked along `axis` in the stored array.
+    pub padding: usize,
+    /// The affine placing the chunk in world space.
+    pub affine: Matrix4<f64>,
+    /// The time offset of the chunk, in the time units of the source header.
+    pub toffset: f32,
+    /// The image the chunk was cut from, if known.
+    pub source: Option<SourceInfo>,
+}
+
+impl ChunkMeta {
+    /// Create metadata for a chunk covering `range` along `axis`, with an identity affine,
+    /// no padding, no time offset and no source.
+    pub fn new(axis: Option<Direction>, range: Range<usize>) -> Self {
+        Self {
+            axis,
+            range,
+            padding: 1,
+            affine: Matrix4::identity(),
+            toffset: 0.0,
+            source: None,
+        }
+    }
+
+    /// The first source index covered by the chunk.
+    pub fn index(&self) -> usize {
+        self.range.start
+    }
+
+    /// Build the header for this chunk from the header of its source image.
+    ///
+    /// The affine and time offset are taken from the chunk. Chunks cut along time are 3D, so
+    /// their 4th dimension has length one and no spacing; the time units are kept, as they
+    /// are the units of the onset in `toffset`.
+    pub fn header(&self, source_header: &NiftiHeader) -> NiftiHeader {
+        let mut header = source_header.clone();
+        header.set_affine(&self.affine);
+        header.toffset = self.toffset;
+        if self.axis == Some(Direction::T) {
+            header.dim[0] = 3;
+            header.dim[4] = 1;
+            header.pixdim[4] = 0.0;
+        }
+        header
+    }
+}
+
 // Slice3D represents a single slice of a 3D image.
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Slice3D {
     pub slice: Array3<f64>,
-    pub index: usize,
+    pub meta: ChunkMeta,
 }
 // Implement methods for the Slice3D struct
 impl Slice3D {
     /// Create a new Slice3D with the given slice and index.
+    ///
+    /// The metadata holds a single unpadded slice along an unknown axis; use `with_meta` when
+    /// more is known.
     pub fn new(slice: Array3<f64>, index: usize) -> Self {
-        Self { slice, index }
+        let meta = ChunkMeta::new(None, index..index + 1);
+        Self { slice, meta }
+    }
+
+    /// Replace the metadata of the slice.
+    pub fn with_meta(mut self, meta: ChunkMeta) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// The position of the slice along its axis, the start of its range.
+    pub fn index(&self) -> usize {
+        self.meta.index()
     }
 }
 
 // Vol3D represents a 3D volume from a 4D image.
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Vol3D {
     pub vol: Array3<f64>,
-    pub index: usize,
+    pub meta: ChunkMeta,
 }
 // Implement methods for the Vol3D struct
 impl Vol3D {
     /// Create a new Vol3D with the given volume and index.
     pub fn new(vol: Array3<f64>, index: usize) -> Self {
-        Self { vol, index }
+        let meta = ChunkMeta::new(Some(Direction::T), index..index + 1);
+        Self { vol, meta }
+    }
+
+    /// Replace the metadata of the volume.
+    pub fn with_meta(mut self, meta: ChunkMeta) -> Self {
+        self.meta = meta;
+        self
+    }
+
+    /// The position of the volume in its series, the start of its range.
+    pub fn index(&self) -> usize {
+        self.meta.index()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector4;
+
+    fn header(dim: &[usize]) -> NiftiHeader {
+        let mut header = NiftiHeader::default();
+        header.dim[0] = dim.len() as u16;
+        for (i, &d) in dim.iter().enumerate() {
+            header.dim[i + 1] = d as u16;
+        }
+        header
+    }
+
+    #[test]
+    fn axes_print_as_their_dimension() {
+        let printed: Vec<String> = [Direction::X, Direction::Y, Direction::Z, Direction::T]
+            .iter()
+            .map(|axis| axis.to_string())
+            .collect();
+        assert_eq!(printed, ["0", "1", "2", "3"]);
+    }
+
+    #[test]
+    fn index_follows_the_range() {
+        let slice = Slice3D::new(Array3::zeros((2, 2, 1)), 3);
+        assert_eq!(slice.index(), 3);
+        assert_eq!(slice.meta.axis, None);
+        let slice = slice.with_meta(ChunkMeta::new(Some(Direction::Y), 5..6));
+        assert_eq!(slice.index(), 5);
+        let vol = Vol3D::new(Array3::zeros((2, 2, 2)), 4);
+        assert_eq!((vol.index(), vol.meta.axis), (4, Some(Direction::T)));
+    }
+
+    #[test]
+    fn volume_headers_have_one_time_point() {
+        let mut source = header(&[2, 2, 2, 5]);
+        source.pixdim[4] = 2.0;
+        source.xyzt_units = 2 | 8;
+        let volume = ChunkMeta::new(Some(Direction::T), 2..3).header(&source);
+        assert_eq!(
+            (volume.dim[0], volume.dim[4], volume.pixdim[4]),
+            (3, 1, 0.0)
+        );
+        // the time units stay, as toffset holds the onset in them
+        assert_eq!(volume.xyzt_units, 2 | 8);
+        // slices, and chunks read back from files, keep the time points of the source
+        let meta = ChunkMeta::new(Some(Direction::Z), 2..3);
+        assert_eq!(meta.header(&header(&[2, 2, 5, 5])).dim[4], 5);
+        let meta = ChunkMeta::new(None, 2..3);
+        assert_eq!(meta.header(&header(&[2, 2, 5, 5])).dim[4], 5);
+    }
+
+    #[test]
+    fn chunk_headers_take_the_place_of_the_chunk() {
+        let mut source = header(&[2, 2, 5]);
+        source.toffset = 1.0;
+        let mut affine = Matrix4::from_diagonal(&Vector4::new(2.0, 2.0, 2.0, 1.0));
+        affine[(2, 3)] = 6.0;
+        let meta = ChunkMeta {
+            affine,
+            toffset: 4.5,
+            ..ChunkMeta::new(Some(Direction::Z), 3..4)
+        };
+        let chunk = meta.header(&source);
+        assert_eq!(chunk.affine::<f64>(), affine);
+        assert_eq!(chunk.toffset, 4.5);
+        // the dimensions are left to the writer, which takes them from the data
+        assert_eq!(chunk.dim, source.dim);
+
+        let slice = Slice3D::new(Array3::from_elem((2, 2, 1), 7.0), 0).with_meta(meta.clone());
+        assert_eq!((slice.index(), &slice.meta), (3, &meta));
+        assert_eq!(slice.slice[[1, 1, 0]], 7.0);
+        let vol = Vol3D::new(Array3::zeros((2, 2, 5)), 0).with_meta(meta);
+        assert_eq!((vol.index(), vol.meta.axis), (3, Some(Direction::Z)));
     }
 }
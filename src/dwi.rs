@@ -0,0 +1,237 @@
+//! Diffusion gradients in FSL's bval/bvec format, and grouping volumes into b-value shells.
+//!
+//! FSL keeps the gradients of `dwi.nii.gz` in `dwi.bval`, one b-value per volume on a single
+//! row, and `dwi.bvec`, three rows with the x, y and z component of each volume's direction.
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::OverwritePolicy;
+use crate::error::{Error, Result};
+use crate::io::{nifti_stem, write_text_atomic};
+
+/// Gradients are the b-values and directions of a diffusion series, one per volume.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Gradients {
+    pub bvals: Vec<f64>,
+    pub bvecs: Vec<[f64; 3]>,
+}
+
+/// The paths of the bval and bvec files of a NIfTI file.
+pub fn gradient_paths(nifti: &Path) -> Result<(PathBuf, PathBuf)> {
+    let stem = nifti_stem(nifti)?;
+    Ok((
+        nifti.with_file_name(format!("{}.bval", stem)),
+        nifti.with_file_name(format!("{}.bvec", stem)),
+    ))
+}
+
+/// Parse the numbers on each non-empty line of a bval or bvec file.
+fn parse_rows(path: &Path) -> Result<Vec<Vec<f64>>> {
+    let text = fs::read_to_string(path)?;
+    text.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            line.split_whitespace()
+                .map(|v| {
+                    v.parse().map_err(|_| {
+                        Error::Input(format!("'{}' in {} is not a number.", v, path.display()))
+                    })
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Format numbers without trailing zeros, the way FSL writes them.
+fn format_row(values: impl Iterator<Item = f64>) -> String {
+    values
+        .map(|v| format!("{}", v))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl Gradients {
+    /// Read the bval and bvec files next to a NIfTI file, if both are there.
+    ///
+    /// # Returns
+    ///
+    /// The gradients, `None` if either file is missing, or an input error if they are
+    /// malformed or do not agree on the number of volumes.
+    pub fn read(nifti: &Path) -> Result<Option<Gradients>> {
+        let (bval_path, bvec_path) = gradient_paths(nifti)?;
+        if !bval_path.is_file() || !bvec_path.is_file() {
+            return Ok(None);
+        }
+        let bvals: Vec<f64> = parse_rows(&bval_path)?.concat();
+        let rows = parse_rows(&bvec_path)?;
+        // FSL writes three rows, but one row of three numbers per volume is also common
+        let bvecs: Vec<[f64; 3]> = if rows.len() == 3 && rows.iter().all(|r| r.len() == bvals.len())
+        {
+            (0..bvals.len())
+                .map(|i| [rows[0][i], rows[1][i], rows[2][i]])
+                .collect()
+        } else if rows.len() == bvals.len() && rows.iter().all(|r| r.len() == 3) {
+            rows.iter().map(|r| [r[0], r[1], r[2]]).collect()
+        } else {
+            return Err(Error::Input(format!(
+                "{} does not hold a direction for each of the {} b-values in {}.",
+                bvec_path.display(),
+                bvals.len(),
+                bval_path.display()
+            )));
+        };
+        Ok(Some(Gradients { bvals, bvecs }))
+    }
+
+    /// Write the bval and bvec files next to a NIfTI file, in FSL's layout, following
+    /// `policy` if they already exist.
+    pub fn write(&self, nifti: &Path, policy: &OverwritePolicy) -> Result<()> {
+        let (bval_path, bvec_path) = gradient_paths(nifti)?;
+        let bvals = format_row(self.bvals.iter().copied()) + "\n";
+        write_text_atomic(&bval_path, &bvals, policy)?;
+        let rows: Vec<String> = (0..3)
+            .map(|axis| format_row(self.bvecs.iter().map(|v| v[axis])))
+            .collect();
+        write_text_atomic(&bvec_path, &(rows.join("\n") + "\n"), policy)?;
+        Ok(())
+    }
+
+    /// How many volumes the gradients describe.
+    pub fn len(&self) -> usize {
+        self.bvals.len()
+    }
+
+    /// Whether there are no gradients at all.
+    pub fn is_empty(&self) -> bool {
+        self.bvals.is_empty()
+    }
+
+    /// The gradients of the volumes at `indices`, in that order.
+    pub fn subset(&self, indices: &[usize]) -> Gradients {
+        Gradients {
+            bvals: indices.iter().map(|&i| self.bvals[i]).collect(),
+            bvecs: indices.iter().map(|&i| self.bvecs[i]).collect(),
+        }
+    }
+
+    /// Join the gradients of several series, one after another.
+    pub fn concat(parts: &[Gradients]) -> Gradients {
+        Gradients {
+            bvals: parts.iter().flat_map(|g| g.bvals.clone()).collect(),
+            bvecs: parts.iter().flat_map(|g| g.bvecs.clone()).collect(),
+        }
+    }
+}
+
+/// Join the gradients of several files, in order.
+///
+/// # Returns
+///
+/// The joined gradients, or `None` if any of the files has no bval/bvec files.
+pub fn gather_gradients(paths: &[&Path]) -> Result<Option<Gradients>> {
+    let mut parts = Vec::new();
+    for path in paths {
+        match Gradients::read(path)? {
+            Some(gradients) => parts.push(gradients),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(Gradients::concat(&parts)))
+}
+
+/// Shell is a group of volumes with about the same b-value.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Shell {
+    /// The b-value of the shell: 0 for b0 volumes, otherwise the rounded mean of its volumes.
+    pub bvalue: u32,
+    /// The indices of its volumes in the series, in order.
+    pub indices: Vec<usize>,
+}
+
+/// Group volumes into shells by b-value.
+///
+/// Volumes with a b-value of at most `tolerance` are b0 volumes. The others join the first
+/// shell whose first b-value is within `tolerance` of theirs, so scanner rounding such as
+/// 995 and 1005 ends up in one shell.
+///
+/// # Returns
+///
+/// The shells in increasing order of b-value.
+pub fn group_shells(bvals: &[f64], tolerance: f64) -> Vec<Shell> {
+    // each shell keeps the first b-value it was started with, to compare the others against
+    let mut groups: Vec<(f64, Vec<usize>)> = Vec::new();
+    for (index, &bval) in bvals.iter().enumerate() {
+        let bval = if bval <= tolerance { 0.0 } else { bval };
+        match groups
+            .iter_mut()
+            .find(|(first, _)| (first - bval).abs() <= tolerance)
+        {
+            Some((_, indices)) => indices.push(index),
+            None => groups.push((bval, vec![index])),
+        }
+    }
+    let mut shells: Vec<Shell> = groups
+        .into_iter()
+        .map(|(first, indices)| {
+            let bvalue = if first == 0.0 {
+                0
+            } else {
+                let mean = indices.iter().map(|&i| bvals[i]).sum::<f64>() / indices.len() as f64;
+                mean.round() as u32
+            };
+            Shell { bvalue, indices }
+        })
+        .collect();
+    shells.sort_by_key(|shell| shell.bvalue);
+    shells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn indices(shells: &[Shell]) -> Vec<(u32, Vec<usize>)> {
+        shells
+            .iter()
+            .map(|s| (s.bvalue, s.indices.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn groups_volumes_by_b_value() {
+        let shells = group_shells(&[0.0, 1000.0, 2000.0, 5.0, 1000.0, 2000.0], 50.0);
+        assert_eq!(
+            indices(&shells),
+            vec![(0, vec![0, 3]), (1000, vec![1, 4]), (2000, vec![2, 5])]
+        );
+    }
+
+    #[test]
+    fn merges_scanner_rounding_into_one_shell() {
+        let shells = group_shells(&[995.0, 0.0, 1005.0, 1000.0], 50.0);
+        assert_eq!(indices(&shells), vec![(0, vec![1]), (1000, vec![0, 2, 3])]);
+    }
+
+    #[test]
+    fn gradients_round_trip_through_fsl_files() {
+        let dir = std::env::temp_dir().join(format!("slicenii-dwi-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let nifti = dir.join("dwi.nii.gz");
+        let gradients = Gradients {
+            bvals: vec![0.0, 1000.0, 2000.0],
+            bvecs: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, -0.5, 0.5]],
+        };
+        gradients.write(&nifti, &OverwritePolicy::Error).unwrap();
+        let read = Gradients::read(&nifti).unwrap();
+        // a second write is refused under the default policy
+        assert!(gradients.write(&nifti, &OverwritePolicy::Error).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read, Some(gradients.clone()));
+        let shell = gradients.subset(&[2, 0]);
+        assert_eq!(shell.bvals, vec![2000.0, 0.0]);
+        assert_eq!(
+            Gradients::concat(&[shell, gradients.subset(&[1])]).bvals,
+            vec![2000.0, 0.0, 1000.0]
+        );
+    }
+}
@@ -0,0 +1,94 @@
+//! Errors shared by the slicenii commands and the exit codes they map to.
+//!
+//! Every command returns a [`Result`] and the binaries turn an error into a message on stderr
+//! and one of the exit codes below, so scripts can tell a bad invocation from a missing input
+//! or a failed write.
+use std::fmt;
+use std::process::ExitCode;
+
+/// Exit code for a successful run.
+pub const EXIT_SUCCESS: u8 = 0;
+/// Exit code for a check that ran but did not pass.
+pub const EXIT_CHECK_FAILED: u8 = 1;
+/// Exit code for an invalid combination of arguments (the same code clap uses).
+pub const EXIT_USAGE: u8 = 2;
+/// Exit code for a missing, unreadable or unsupported input.
+pub const EXIT_INPUT: u8 = 3;
+/// Exit code for an output that could not be written.
+pub const EXIT_OUTPUT: u8 = 4;
+/// Exit code for a batch in which some inputs failed.
+pub const EXIT_BATCH: u8 = 5;
+
+/// The Error enum covers everything that can stop a command.
+#[derive(Debug)]
+pub enum Error {
+    /// The arguments do not make sense together.
+    Usage(String),
+    /// An input is missing, unreadable or not what the command expects.
+    Input(String),
+    /// An output could not be created or already exists.
+    Output(String),
+    /// A check ran to completion and failed.
+    Check(String),
+    /// Some inputs of a batch failed; the others were processed.
+    Batch(String),
+    /// An error reading or writing a NIfTI file.
+    Nifti(nifti::NiftiError),
+    /// Any other I/O error.
+    Io(std::io::Error),
+}
+
+impl Error {
+    /// The process exit code for this error.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Usage(_) => EXIT_USAGE,
+            Error::Input(_) | Error::Nifti(_) => EXIT_INPUT,
+            Error::Output(_) | Error::Io(_) => EXIT_OUTPUT,
+            Error::Check(_) => EXIT_CHECK_FAILED,
+            Error::Batch(_) => EXIT_BATCH,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Usage(msg)
+            | Error::Input(msg)
+            | Error::Output(msg)
+            | Error::Check(msg)
+            | Error::Batch(msg) => write!(f, "{}", msg),
+            Error::Nifti(e) => write!(f, "{}", e),
+            Error::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<nifti::NiftiError> for Error {
+    fn from(e: nifti::NiftiError) -> Self {
+        Error::Nifti(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+/// Result type used throughout the library.
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Print an error, if any, and convert the result of a command into a process exit code.
+pub fn report(result: Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::from(EXIT_SUCCESS),
+        Err(e) => {
+            eprintln!("Error! {}", e);
+            ExitCode::from(e.exit_code())
+        }
+    }
+}
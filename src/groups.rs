@@ -0,0 +1,252 @@
+//! Regrouping the volumes of a 4D series into several 4D files, and back.
+//!
+//! Series whose volumes cycle through echoes or other conditions are split into one 4D file
+//! per position in the cycle. Each file keeps the timing of its volumes in its header, and a
+//! table records where every volume of the series went so the files can be interleaved back.
+use nifti::NiftiHeader;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::OverwritePolicy;
+use crate::error::{Error, Result};
+use crate::header::volume_onset;
+use crate::io::write_text_atomic;
+use crate::sidecar::Sidecar;
+
+/// The suffix of the table that maps the volumes of the group files back to the series.
+pub const GROUP_TABLE: &str = "_groups.tsv";
+
+/// Split `count` volumes into `cycle` groups, group `g` holding volumes `g, g + cycle, ...`.
+pub fn cycle_groups(count: usize, cycle: usize) -> Vec<Vec<usize>> {
+    (0..cycle.min(count))
+        .map(|g| (g..count).step_by(cycle).collect())
+        .collect()
+}
+
+/// The echo times listed in `EchoTime` of a sidecar, in seconds.
+///
+/// # Returns
+///
+/// The times, or `None` if `EchoTime` is missing or a single number.
+pub fn echo_times(sidecar: &Sidecar) -> Option<Vec<f64>> {
+    sidecar
+        .get("EchoTime")?
+        .as_array()?
+        .iter()
+        .map(Value::as_f64)
+        .collect()
+}
+
+/// The header of a group of volumes, from the header of the series.
+///
+/// `toffset` moves on to the onset of the first volume, and if the volumes are evenly spaced
+/// `pixdim[4]` becomes the time between them.
+///
+/// # Returns
+///
+/// The header, and whether the volumes were evenly spaced so the timing holds for all of them.
+pub fn group_header(header: &NiftiHeader, indices: &[usize]) -> (NiftiHeader, bool) {
+    let mut group = header.clone();
+    group.dim[4] = indices.len() as u16;
+    if let Some(&first) = indices.first() {
+        group.toffset = volume_onset(header, first) as f32;
+    }
+    let steps: Vec<usize> = indices.windows(2).map(|w| w[1] - w[0]).collect();
+    let even = steps.windows(2).all(|w| w[0] == w[1]);
+    if let (true, Some(&step)) = (even, steps.first()) {
+        group.pixdim[4] *= step as f32;
+    }
+    (group, even)
+}
+
+/// GroupRow is one line of a group table: where a volume of the series was written.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GroupRow {
+    /// The 0-based position of the volume in the series.
+    pub index: usize,
+    /// The file holding the volume.
+    pub file: PathBuf,
+    /// The 0-based position of the volume in the file.
+    pub volume: usize,
+}
+
+/// Write the table that maps every volume of the series to the file holding it.
+///
+/// The table has the columns `index` (the position in the series, counting from 1), `file`,
+/// `volume` (the position in the file, counting from 1) and one more column describing the
+/// volume, such as its b-value.
+///
+/// # Arguments
+///
+/// * `path` - The path of the table.
+/// * `files` - The file of each group.
+/// * `groups` - The 0-based volumes of the series in each group, in the order of the file.
+/// * `column` - The name and the value for each volume of the last column.
+/// * `policy` - What to do if the table already exists.
+pub fn write_group_table(
+    path: &Path,
+    files: &[PathBuf],
+    groups: &[Vec<usize>],
+    column: (&str, &dyn Fn(usize) -> String),
+    policy: &OverwritePolicy,
+) -> Result<()> {
+    let mut rows: Vec<(usize, &str, usize)> = Vec::new();
+    for (indices, file) in groups.iter().zip(files) {
+        let name = file.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        for (volume, &index) in indices.iter().enumerate() {
+            rows.push((index, name, volume));
+        }
+    }
+    rows.sort();
+    let (name, value) = column;
+    let mut table = format!("index\tfile\tvolume\t{}\n", name);
+    for (index, file, volume) in rows {
+        table.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            index + 1,
+            file,
+            volume + 1,
+            value(index)
+        ));
+    }
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    write_text_atomic(path, &table, policy)?;
+    Ok(())
+}
+
+/// Read a group table, checking that it lists every volume of a series of `count` once.
+///
+/// # Returns
+///
+/// The rows in series order, with the files resolved against the directory of the table.
+pub fn read_group_table(path: &Path, count: usize) -> Result<Vec<GroupRow>> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let text = fs::read_to_string(path)?;
+    let bad_line = |n: usize| {
+        Error::Input(format!(
+            "Line {} of {} does not start with index, file and volume.",
+            n + 1,
+            path.display()
+        ))
+    };
+    let mut rows: Vec<Option<GroupRow>> = (0..count).map(|_| None).collect();
+    for (n, line) in text.lines().enumerate().skip(1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            return Err(bad_line(n));
+        }
+        let index: usize = fields[0].parse().map_err(|_| bad_line(n))?;
+        let volume: usize = fields[2].parse().map_err(|_| bad_line(n))?;
+        if index == 0 || volume == 0 {
+            return Err(bad_line(n));
+        }
+        let slot = rows.get_mut(index - 1).ok_or_else(|| {
+            Error::Input(format!(
+                "{} lists volume {}, but the reference has {} volumes.",
+                path.display(),
+                index,
+                count
+            ))
+        })?;
+        if slot.is_some() {
+            return Err(Error::Input(format!(
+                "{} lists volume {} more than once.",
+                path.display(),
+                index
+            )));
+        }
+        *slot = Some(GroupRow {
+            index: index - 1,
+            file: dir.join(fields[1]),
+            volume: volume - 1,
+        });
+    }
+    let missing: Vec<usize> = rows
+        .iter()
+        .enumerate()
+        .filter(|(_, row)| row.is_none())
+        .map(|(i, _)| i + 1)
+        .collect();
+    if !missing.is_empty() {
+        return Err(Error::Input(format!(
+            "{} is missing volume(s) {:?}.",
+            path.display(),
+            missing
+        )));
+    }
+    Ok(rows.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycles_volumes_into_groups() {
+        assert_eq!(
+            cycle_groups(7, 3),
+            vec![vec![0, 3, 6], vec![1, 4], vec![2, 5]]
+        );
+        assert_eq!(cycle_groups(2, 3), vec![vec![0], vec![1]]);
+    }
+
+    #[test]
+    fn groups_keep_their_timing() {
+        let header = NiftiHeader {
+            dim: [4, 2, 2, 2, 6, 1, 1, 1],
+            pixdim: [1.0, 1.0, 1.0, 1.0, 2.0, 1.0, 1.0, 1.0],
+            toffset: 1.0,
+            ..Default::default()
+        };
+        let (group, even) = group_header(&header, &[1, 4]);
+        assert!(even);
+        assert_eq!(
+            (group.dim[4], group.toffset, group.pixdim[4]),
+            (2, 3.0, 6.0)
+        );
+        let (group, even) = group_header(&header, &[0, 1, 5]);
+        assert!(!even);
+        assert_eq!((group.toffset, group.pixdim[4]), (1.0, 2.0));
+    }
+
+    #[test]
+    fn group_table_round_trip() {
+        let dir = std::env::temp_dir().join(format!("slicenii-groups-{}", std::process::id()));
+        let path = dir.join("t_groups.tsv");
+        let files = [dir.join("t_echo-1.nii"), dir.join("t_echo-2.nii")];
+        let groups = cycle_groups(5, 2);
+        let echo = |index: usize| (index % 2 + 1).to_string();
+        write_group_table(
+            &path,
+            &files,
+            &groups,
+            ("echo", &echo),
+            &OverwritePolicy::Replace,
+        )
+        .unwrap();
+        let rows = read_group_table(&path, 5).unwrap();
+        let short = read_group_table(&path, 4);
+        fs::remove_dir_all(&dir).unwrap();
+        let placed: Vec<(usize, &Path, usize)> = rows
+            .iter()
+            .map(|row| (row.index, row.file.as_path(), row.volume))
+            .collect();
+        assert_eq!(
+            placed,
+            vec![
+                (0, files[0].as_path(), 0),
+                (1, files[1].as_path(), 0),
+                (2, files[0].as_path(), 1),
+                (3, files[1].as_path(), 1),
+                (4, files[0].as_path(), 2),
+            ]
+        );
+        assert!(short.is_err());
+    }
+}
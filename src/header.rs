@@ -0,0 +1,280 @@
+//! Helpers for describing and interpreting NIfTI header fields.
+use nalgebra::Matrix4;
+use ndarray::ArrayView3;
+use nifti::{NiftiHeader, Unit};
+
+use crate::common::Direction;
+
+/// The orientation of the voxel axes as a three letter code, such as `RAS` or `LPI`.
+///
+/// Each letter names the world direction that a voxel axis points towards most, following
+/// the NIfTI convention that world +x is right, +y is anterior and +z is superior.
+pub fn orientation(affine: &Matrix4<f64>) -> String {
+    let mut code = String::new();
+    for col in 0..3 {
+        let mut best = 0;
+        for row in 1..3 {
+            if affine[(row, col)].abs() > affine[(best, col)].abs() {
+                best = row;
+            }
+        }
+        let positive = affine[(best, col)] >= 0.0;
+        code.push(match (best, positive) {
+            (0, true) => 'R',
+            (0, false) => 'L',
+            (1, true) => 'A',
+            (1, false) => 'P',
+            (_, true) => 'S',
+            (_, false) => 'I',
+        });
+    }
+    code
+}
+
+/// The name of a NIfTI datatype code, such as `float32`.
+pub fn datatype_name(header: &NiftiHeader) -> String {
+    match header.data_type() {
+        Ok(t) => format!("{:?}", t).to_lowercase(),
+        Err(_) => format!("unknown ({})", header.datatype),
+    }
+}
+
+/// The short name of a NIfTI unit, such as `mm` or `s`.
+pub fn unit_name(unit: Unit) -> &'static str {
+    match unit {
+        Unit::Unknown => "unknown",
+        Unit::Meter => "m",
+        Unit::Mm => "mm",
+        Unit::Micron => "um",
+        Unit::Sec => "s",
+        Unit::Msec => "ms",
+        Unit::Usec => "us",
+        Unit::Hz => "Hz",
+        Unit::Ppm => "ppm",
+        Unit::Rads => "rad/s",
+    }
+}
+
+/// The space and time units of a header, with unrecognised codes treated as unknown.
+pub fn units(header: &NiftiHeader) -> (Unit, Unit) {
+    (
+        header.xyzt_to_space().unwrap_or(Unit::Unknown),
+        header.xyzt_to_time().unwrap_or(Unit::Unknown),
+    )
+}
+
+/// How many seconds one time unit of `header` is.
+///
+/// # Returns
+///
+/// The length of the unit in seconds, taking unknown units to be seconds, or `None` if the
+/// 4th dimension is not time but frequency (Hz, ppm or rad/s).
+pub fn seconds_per_unit(header: &NiftiHeader) -> Option<f64> {
+    match units(header).1 {
+        Unit::Msec => Some(0.001),
+        Unit::Usec => Some(0.000_001),
+        Unit::Hz | Unit::Ppm | Unit::Rads => None,
+        _ => Some(1.0),
+    }
+}
+
+/// The onset of volume `index` along the 4th dimension, `toffset + index * pixdim[4]`, in
+/// the units of `header`.
+pub fn volume_onset(header: &NiftiHeader, index: usize) -> f64 {
+    header.toffset as f64 + index as f64 * header.pixdim[4] as f64
+}
+
+/// The name of a qform or sform code.
+pub fn xform_name(code: i16) -> &'static str {
+    match code {
+        0 => "unknown",
+        1 => "scanner",
+        2 => "aligned",
+        3 => "talairach",
+        4 => "mni",
+        5 => "template",
+        _ => "invalid",
+    }
+}
+
+/// The 0-based axis the slices were acquired along, from `dim_info`, if it is set.
+pub fn slice_dim(header: &NiftiHeader) -> Option<usize> {
+    match (header.dim_info >> 4) & 0b11 {
+        0 => None,
+        d => Some(d as usize - 1),
+    }
+}
+
+/// The position of slice `index` in the order of acquisition, from `slice_code`.
+///
+/// # Returns
+///
+/// The 0-based position, or `None` if the order is unknown or `index` is outside
+/// `slice_start..=slice_end`.
+pub fn slice_order_position(header: &NiftiHeader, index: usize) -> Option<usize> {
+    let start = usize::try_from(header.slice_start).ok()?;
+    let end = usize::try_from(header.slice_end).ok()?;
+    if end < start || index < start || index > end {
+        return None;
+    }
+    let n = end - start + 1;
+    let k = index - start;
+    // the slice counted from the end, for the decreasing orders
+    let j = n - 1 - k;
+    match header.slice_code {
+        // sequential increasing, then decreasing
+        1 => Some(k),
+        2 => Some(j),
+        // alternating increasing: 0, 2, 4, ... then 1, 3, 5, ...
+        3 if k.is_multiple_of(2) => Some(k / 2),
+        3 => Some(n.div_ceil(2) + k / 2),
+        // alternating decreasing: n-1, n-3, ... then n-2, n-4, ...
+        4 if j.is_multiple_of(2) => Some(j / 2),
+        4 => Some(n.div_ceil(2) + j / 2),
+        // alternating increasing starting at 1: 1, 3, 5, ... then 0, 2, 4, ...
+        5 if !k.is_multiple_of(2) => Some(k / 2),
+        5 => Some(n / 2 + k / 2),
+        // alternating decreasing starting at n-2: n-2, n-4, ... then n-1, n-3, ...
+        6 if !j.is_multiple_of(2) => Some(j / 2),
+        6 => Some(n / 2 + j / 2),
+        _ => None,
+    }
+}
+
+/// The time slice `index` was acquired at after the start of its volume, in the time units
+/// of the header, from `slice_code` and `slice_duration`.
+pub fn slice_time(header: &NiftiHeader, index: usize) -> Option<f32> {
+    if header.slice_duration <= 0.0 {
+        return None;
+    }
+    slice_order_position(header, index).map(|p| p as f32 * header.slice_duration)
+}
+
+/// Rewrite the header fields that describe the whole volume for a single slice.
+///
+/// If the slice was cut along the axis the slices were acquired along, it is a single
+/// acquired slice: its acquisition time is added to `toffset`, and the slice timing fields
+/// and the slice axis in `dim_info` are cleared, as they no longer describe several slices.
+/// If it was cut along another axis, it still holds every acquired slice and those fields are
+/// kept. The display range in `cal_min`/`cal_max` is recomputed from the slice, if the
+/// source has one.
+///
+/// # Arguments
+///
+/// * `header` - The header of the slice, copied from the source.
+/// * `source` - The header of the source image.
+/// * `axis` - The axis the slice was cut along.
+/// * `index` - The position of the slice along `axis`.
+/// * `data` - The voxels of the slice.
+pub fn set_slice_fields(
+    header: &mut NiftiHeader,
+    source: &NiftiHeader,
+    axis: &Direction,
+    index: usize,
+    data: &ArrayView3<f64>,
+) {
+    if slice_dim(source) == Some(axis.to_usize()) {
+        if let Some(time) = slice_time(source, index) {
+            header.toffset += time;
+        }
+        header.dim_info &= 0b0000_1111;
+        header.slice_code = 0;
+        header.slice_start = 0;
+        header.slice_end = 0;
+        header.slice_duration = 0.0;
+    }
+    if source.cal_max > source.cal_min {
+        let (min, max) = data
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &v| {
+                (lo.min(v), hi.max(v))
+            });
+        if min <= max {
+            header.cal_min = min as f32;
+            header.cal_max = max as f32;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::Array3;
+
+    /// A header for 5 slices along z acquired in the order of `slice_code`, 10 ms apart.
+    fn timed(slice_code: u8) -> NiftiHeader {
+        NiftiHeader {
+            dim: [3, 2, 2, 5, 1, 1, 1, 1],
+            dim_info: 3 << 4,
+            slice_code,
+            slice_start: 0,
+            slice_end: 4,
+            slice_duration: 10.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn follows_every_slice_order() {
+        let orders = [
+            (1, [0, 1, 2, 3, 4]),
+            (2, [4, 3, 2, 1, 0]),
+            (3, [0, 2, 4, 1, 3]),
+            (4, [4, 2, 0, 3, 1]),
+            (5, [1, 3, 0, 2, 4]),
+            (6, [3, 1, 4, 2, 0]),
+        ];
+        for (code, acquired) in orders {
+            let header = timed(code);
+            for (position, &slice) in acquired.iter().enumerate() {
+                assert_eq!(
+                    slice_order_position(&header, slice),
+                    Some(position),
+                    "slice {} with slice_code {}",
+                    slice,
+                    code
+                );
+            }
+        }
+        assert_eq!(slice_order_position(&timed(0), 1), None);
+    }
+
+    #[test]
+    fn counts_from_slice_start() {
+        let header = NiftiHeader {
+            slice_start: 1,
+            slice_end: 3,
+            ..timed(1)
+        };
+        assert_eq!(slice_order_position(&header, 0), None);
+        assert_eq!(slice_order_position(&header, 1), Some(0));
+        assert_eq!(slice_order_position(&header, 4), None);
+        assert_eq!(slice_time(&header, 3), Some(20.0));
+        assert_eq!(slice_time(&timed(0), 3), None);
+    }
+
+    #[test]
+    fn clears_timing_of_acquired_slices() {
+        let source = NiftiHeader {
+            cal_min: 0.0,
+            cal_max: 100.0,
+            ..timed(3)
+        };
+        let data = Array3::from_shape_fn((2, 2, 1), |(i, j, _)| (i * 2 + j) as f64);
+
+        let mut slice = source.clone();
+        set_slice_fields(&mut slice, &source, &Direction::Z, 1, &data.view());
+        assert_eq!(slice.toffset, 30.0);
+        assert_eq!((slice.slice_code, slice.slice_duration), (0, 0.0));
+        assert_eq!(slice_dim(&slice), None);
+        assert_eq!((slice.cal_min, slice.cal_max), (0.0, 3.0));
+
+        // slices along another axis still hold every acquired slice
+        let mut slice = source.clone();
+        set_slice_fields(&mut slice, &source, &Direction::X, 1, &data.view());
+        assert_eq!(slice.toffset, 0.0);
+        assert_eq!(slice.slice_code, 3);
+        assert_eq!(slice_dim(&slice), Some(2));
+    }
+}
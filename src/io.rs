@@ -0,0 +1,457 @@
+//! Reading and writing NIfTI files for the slicenii commands.
+use ndarray::{ArrayD, ArrayView, Dimension, RemoveAxis};
+use nifti::writer::WriterOptions;
+use nifti::{
+    Extender, Extension, ExtensionSequence, IntoNdArray, NiftiHeader, NiftiObject, ReaderOptions,
+    ReaderStreamedOptions,
+};
+use sha2::{Digest, Sha256};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::cli::OverwritePolicy;
+use crate::dwi::Gradients;
+use crate::error::{Error, Result};
+use crate::sidecar::Sidecar;
+
+/// Image holds a NIfTI file read into memory as `f64` voxels.
+#[derive(Debug)]
+pub struct Image {
+    /// The path the image was read from.
+    pub path: PathBuf,
+    /// The header of the image.
+    pub header: NiftiHeader,
+    /// The header extensions of the image, in file order.
+    pub extensions: Vec<Extension>,
+    /// The voxel data, with as many dimensions as the header declares.
+    pub data: ArrayD<f64>,
+}
+
+/// Read a NIfTI file (`.nii` or `.nii.gz`) and convert its volume to `f64`.
+///
+/// # Arguments
+///
+/// * `path` - The file to read.
+///
+/// # Returns
+///
+/// The header and data of the file, or an input error naming the file.
+pub fn read_image(path: &Path) -> Result<Image> {
+    if !path.exists() {
+        return Err(Error::Input(format!("Did not find {}", path.display())));
+    }
+    let obj = ReaderOptions::new()
+        .read_file(path)
+        .map_err(|e| Error::Input(format!("Could not read {}: {}", path.display(), e)))?;
+    let header = obj.header().clone();
+    let extensions = obj.extensions().iter().cloned().collect();
+    let data = obj
+        .into_volume()
+        .into_ndarray::<f64>()
+        .map_err(|e| Error::Input(format!("Could not read {}: {}", path.display(), e)))?;
+    Ok(Image {
+        path: path.to_path_buf(),
+        header,
+        extensions,
+        data,
+    })
+}
+
+/// Read only the header of a NIfTI file.
+pub fn read_header(path: &Path) -> Result<NiftiHeader> {
+    if !path.exists() {
+        return Err(Error::Input(format!("Did not find {}", path.display())));
+    }
+    NiftiHeader::from_file(path)
+        .map_err(|e| Error::Input(format!("Could not read {}: {}", path.display(), e)))
+}
+
+/// Read the header extensions of a NIfTI file, without its voxels.
+pub fn read_extensions(path: &Path) -> Result<Vec<Extension>> {
+    if !path.exists() {
+        return Err(Error::Input(format!("Did not find {}", path.display())));
+    }
+    let obj = ReaderStreamedOptions::new()
+        .read_file(path)
+        .map_err(|e| Error::Input(format!("Could not read {}: {}", path.display(), e)))?;
+    Ok(obj.extensions().iter().cloned().collect())
+}
+
+/// Carried is what an input passes on to its outputs besides its header.
+#[derive(Debug, Clone, Default)]
+pub struct Carried {
+    /// The header extensions to write after the header.
+    pub extensions: Vec<Extension>,
+    /// The JSON sidecar of the input, if it has one.
+    pub sidecar: Option<Sidecar>,
+    /// The gradients of a diffusion series, one for each of its volumes.
+    pub gradients: Option<Gradients>,
+}
+
+/// WriteOutcome is what happened to an output under an overwrite policy.
+#[derive(Debug, Clone, PartialEq)]
+pub enum WriteOutcome {
+    /// The file did not exist and was written.
+    Written,
+    /// The file existed and was replaced.
+    Replaced,
+    /// The file existed and was left alone.
+    Skipped,
+    /// The file existed with the same content and was left alone.
+    Unchanged,
+}
+
+/// Count of the temporary files made by this process, so writers on parallel threads, such as
+/// the jobs of a batch, never share one.
+static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A part of a temporary file name no other writer uses: the process id and the number of
+/// temporary files this process made before.
+fn temp_tag() -> String {
+    let n = TEMP_FILES.fetch_add(1, Ordering::Relaxed);
+    format!("{}-{}", std::process::id(), n)
+}
+
+/// The path of the temporary file an output is written to before it is renamed into place.
+///
+/// It sits next to the output, so the rename stays on one filesystem, and keeps the NIfTI
+/// extension so the writer compresses it the same way. Other dots in the name are replaced,
+/// as the writer would otherwise take everything after the first one for the extension.
+fn temp_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("output.nii");
+    let ext = if name.ends_with(".nii.gz") {
+        "nii.gz"
+    } else {
+        "nii"
+    };
+    let stem = name
+        .strip_suffix(".nii.gz")
+        .or_else(|| name.strip_suffix(".nii"))
+        .unwrap_or(name)
+        .replace('.', "_");
+    path.with_file_name(format!(".{}-part{}.{}", stem, temp_tag(), ext))
+}
+
+/// Give a path the `.nii` extension if it has none, as the NIfTI writer would.
+pub fn with_nifti_extension(path: &Path) -> PathBuf {
+    if path.extension().is_none() {
+        path.with_extension("nii")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// The SHA-256 checksum of a file, as lowercase hex.
+pub fn file_checksum(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
+
+/// Write an array to a NIfTI file using `header` for everything but the dimensions and
+/// datatype. The output is gzip compressed if `path` ends in `.gz`.
+///
+/// This is [`write_image_with_extensions`] without any header extensions.
+pub fn write_image<D>(
+    path: &Path,
+    header: &NiftiHeader,
+    data: &ArrayView<f64, D>,
+    policy: &OverwritePolicy,
+) -> Result<WriteOutcome>
+where
+    D: Dimension + RemoveAxis,
+{
+    write_image_with_extensions(path, header, &[], data, policy)
+}
+
+/// Write an array and header extensions to a NIfTI file using `header` for everything but
+/// the dimensions and datatype. The output is gzip compressed if `path` ends in `.gz`.
+///
+/// The data is written to a temporary file next to `path`, synced, and then renamed over
+/// `path`, so a job killed part way through never leaves a truncated NIfTI behind. What
+/// happens when `path` already exists is decided by `policy`.
+///
+/// # Arguments
+///
+/// * `path` - Where to write the file.
+/// * `header` - The header to write, apart from the dimensions and datatype.
+/// * `extensions` - The header extensions to write after the header.
+/// * `data` - The voxels to write.
+/// * `policy` - What to do if `path` already exists.
+///
+/// # Returns
+///
+/// What was done with the file.
+pub fn write_image_with_extensions<D>(
+    path: &Path,
+    header: &NiftiHeader,
+    extensions: &[Extension],
+    data: &ArrayView<f64, D>,
+    policy: &OverwritePolicy,
+) -> Result<WriteOutcome>
+where
+    D: Dimension + RemoveAxis,
+{
+    let path = with_nifti_extension(path);
+    let exists = path.exists();
+    if let Some(outcome) = check_existing(&path, policy)? {
+        return Ok(outcome);
+    }
+
+    let tmp = temp_path(&path);
+    let mut writer = WriterOptions::new(&tmp).reference_header(header);
+    if !extensions.is_empty() {
+        writer = writer.with_extensions(ExtensionSequence::new(
+            Extender::from([1, 0, 0, 0]),
+            extensions.to_vec(),
+        ));
+    }
+    let written = writer
+        .write_nifti(data)
+        .map_err(|e| Error::Output(format!("Could not write {}: {}", path.display(), e)))
+        .and_then(|_| Ok(File::open(&tmp)?.sync_all()?));
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+
+    move_into_place(&tmp, &path, exists, policy)
+}
+
+/// Write a text file, such as a sidecar or a table, next to the images it describes.
+///
+/// Like [`write_image_with_extensions`], the text goes to a temporary file next to `path`
+/// that is renamed over it, so readers never see half a file, and `policy` decides what
+/// happens when `path` already exists.
+///
+/// # Returns
+///
+/// What was done with the file.
+pub fn write_text_atomic(
+    path: &Path,
+    text: &str,
+    policy: &OverwritePolicy,
+) -> Result<WriteOutcome> {
+    let exists = path.exists();
+    if let Some(outcome) = check_existing(path, policy)? {
+        return Ok(outcome);
+    }
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or("output");
+    let tmp = path.with_file_name(format!(".{}-part{}", name, temp_tag()));
+    let written = fs::write(&tmp, text).and_then(|_| File::open(&tmp)?.sync_all());
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp);
+        return Err(e.into());
+    }
+    move_into_place(&tmp, path, exists, policy)
+}
+
+/// Decide what to do with an output that already exists, before writing it.
+///
+/// # Returns
+///
+/// The outcome if the output is to be left alone, `None` if it is to be written, or an
+/// output error if it exists and `policy` is `OverwritePolicy::Error`.
+fn check_existing(path: &Path, policy: &OverwritePolicy) -> Result<Option<WriteOutcome>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    match policy {
+        OverwritePolicy::Error => Err(Error::Output(format!(
+            "Output file already exists: {}. Use --overwrite to skip, replace or verify it.",
+            path.display()
+        ))),
+        OverwritePolicy::Skip => Ok(Some(WriteOutcome::Skipped)),
+        OverwritePolicy::Replace | OverwritePolicy::Verify => Ok(None),
+    }
+}
+
+/// Rename a finished temporary file over `path`, unless `policy` is to verify and the
+/// contents are the same. The temporary file is removed if this fails.
+fn move_into_place(
+    tmp: &Path,
+    path: &Path,
+    exists: bool,
+    policy: &OverwritePolicy,
+) -> Result<WriteOutcome> {
+    let moved = (|| {
+        let verify = exists && *policy == OverwritePolicy::Verify;
+        if verify && file_checksum(tmp)? == file_checksum(path)? {
+            fs::remove_file(tmp)?;
+            return Ok(WriteOutcome::Unchanged);
+        }
+        fs::rename(tmp, path)?;
+        if exists {
+            Ok(WriteOutcome::Replaced)
+        } else {
+            Ok(WriteOutcome::Written)
+        }
+    })();
+    if moved.is_err() {
+        let _ = fs::remove_file(tmp);
+    }
+    moved
+}
+
+/// Print what happened to an output, for the outcomes worth mentioning.
+pub fn report_outcome(path: &Path, outcome: &WriteOutcome) {
+    match outcome {
+        WriteOutcome::Written => {}
+        WriteOutcome::Replaced => println!("Replaced: {}", path.display()),
+        WriteOutcome::Skipped => eprintln!(
+            "Warning! Output file already exists: {} not saving.",
+            path.display()
+        ),
+        WriteOutcome::Unchanged => println!("Unchanged: {}", path.display()),
+    }
+}
+
+/// The file extension used for outputs, depending on whether they are compressed.
+pub fn nifti_extension(compress: bool) -> &'static str {
+    if compress {
+        "nii.gz"
+    } else {
+        "nii"
+    }
+}
+
+/// Whether a path names a NIfTI file, judging by its extension.
+pub fn is_nifti(path: &Path) -> bool {
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    name.ends_with(".nii") || name.ends_with(".nii.gz")
+}
+
+/// The file name of a NIfTI file without its `.nii` or `.nii.gz` extension.
+///
+/// Unlike `Path::file_stem`, this keeps any other periods in the name, so
+/// `sub-01.run-1.nii.gz` becomes `sub-01.run-1`.
+pub fn nifti_stem(path: &Path) -> Result<String> {
+    let name = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| Error::Input("Could not parse input file name.".to_string()))?;
+    let stem = name.strip_suffix(".gz").unwrap_or(name);
+    let stem = stem.strip_suffix(".nii").unwrap_or(stem);
+    Ok(stem.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn text_follows_the_overwrite_policy() {
+        let dir = std::env::temp_dir().join(format!("slicenii-text-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("table.tsv");
+        let write = |text: &str, policy| write_text_atomic(&path, text, &policy);
+
+        assert_eq!(
+            write("a\n", OverwritePolicy::Error).unwrap(),
+            WriteOutcome::Written
+        );
+        assert!(write("b\n", OverwritePolicy::Error).is_err());
+        assert_eq!(
+            write("b\n", OverwritePolicy::Skip).unwrap(),
+            WriteOutcome::Skipped
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "a\n");
+        assert_eq!(
+            write("a\n", OverwritePolicy::Verify).unwrap(),
+            WriteOutcome::Unchanged
+        );
+        assert_eq!(
+            write("b\n", OverwritePolicy::Verify).unwrap(),
+            WriteOutcome::Replaced
+        );
+        assert_eq!(
+            write("c\n", OverwritePolicy::Replace).unwrap(),
+            WriteOutcome::Replaced
+        );
+        assert_eq!(fs::read_to_string(&path).unwrap(), "c\n");
+        // no temporary files are left behind
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn images_follow_the_overwrite_policy() {
+        let dir = std::env::temp_dir().join(format!("slicenii-image-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("slice.nii");
+        let header = NiftiHeader::default();
+        let note = [Extension::from_str(6, "note")];
+        let write = |value: f64, policy| {
+            let data = ndarray::Array3::from_elem((2, 2, 1), value);
+            write_image_with_extensions(&path, &header, &note, &data.view(), &policy)
+        };
+        let value = |path: &Path| read_image(path).unwrap().data[[1, 1, 0]];
+
+        assert_eq!(
+            write(1.0, OverwritePolicy::Error).unwrap(),
+            WriteOutcome::Written
+        );
+        assert!(matches!(
+            write(2.0, OverwritePolicy::Error),
+            Err(Error::Output(_))
+        ));
+        assert_eq!(
+            write(2.0, OverwritePolicy::Skip).unwrap(),
+            WriteOutcome::Skipped
+        );
+        assert_eq!(value(&path), 1.0);
+        assert_eq!(
+            write(1.0, OverwritePolicy::Verify).unwrap(),
+            WriteOutcome::Unchanged
+        );
+        assert_eq!(
+            write(2.0, OverwritePolicy::Verify).unwrap(),
+            WriteOutcome::Replaced
+        );
+        assert_eq!(value(&path), 2.0);
+        assert_eq!(
+            write(3.0, OverwritePolicy::Replace).unwrap(),
+            WriteOutcome::Replaced
+        );
+        assert_eq!(value(&path), 3.0);
+        assert_eq!(read_extensions(&path).unwrap().len(), 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+        // a directory in the way fails the write without leaving the temporary file behind
+        let blocked = dir.join("blocked.nii");
+        fs::create_dir_all(blocked.join("inside")).unwrap();
+        let data = ndarray::Array3::<f64>::zeros((2, 2, 1));
+        for policy in [OverwritePolicy::Replace, OverwritePolicy::Verify] {
+            assert!(write_image(&blocked, &header, &data.view(), &policy).is_err());
+        }
+        let names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(names.len(), 2, "{:?}", names);
+    }
+
+    #[test]
+    fn temp_files_are_never_shared() {
+        let path = Path::new("out/sub-01.bold.nii.gz");
+        let first = temp_path(path);
+        let second = temp_path(path);
+        assert_ne!(first, second);
+        let name = first.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with(".sub-01_bold-part"), "{}", name);
+        assert!(name.ends_with(".nii.gz"), "{}", name);
+    }
+}
@@ -0,0 +1,476 @@
+//! A progress journal that lets an interrupted run pick up where it stopped.
+//!
+//! With `--resume`, slicing and splitting keep a journal in the directory they write to,
+//! listing every output that was completed with its checksum. Combining keeps one next to its
+//! output, listing the inputs already placed in the last checkpoint of the combined image.
+//! Entries whose files still match their checksums are not written (or decoded) again. Without
+//! `--resume` no journal is kept, so nothing is checksummed.
+//!
+//! The journal is saved as JSON lines: the first describes the run, and each completed entry
+//! is appended as a line of its own, so recording an output does not rewrite the journal.
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::cli::OverwritePolicy;
+use crate::error::{Error, Result};
+use crate::io::{file_checksum, write_text_atomic};
+use crate::plan::{PlannedOutput, Shard};
+
+/// The name of the journal slicing and splitting keep in their output directory.
+pub const JOURNAL_NAME: &str = ".slicenii-journal.jsonl";
+
+/// The name of the journal for a run, or for one shard of it.
+///
+/// Shards of the same run may be written at the same time, so each keeps its own journal.
+pub fn journal_name(shard: Option<&Shard>) -> String {
+    match shard {
+        Some(shard) => format!(
+            ".slicenii-journal-shard-{}-of-{}.jsonl",
+            shard.index, shard.count
+        ),
+        None => JOURNAL_NAME.to_string(),
+    }
+}
+
+/// Fingerprint identifies a version of a file by its size and modification time.
+///
+/// It is used for the source of a run, which may be too large to checksum cheaply.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    pub path: PathBuf,
+    pub size: u64,
+    /// The modification time, in nanoseconds since the Unix epoch.
+    pub modified_ns: u64,
+}
+
+impl Fingerprint {
+    /// Fingerprint the file at `path`.
+    pub fn of(path: &Path) -> Result<Fingerprint> {
+        let metadata = fs::metadata(path)?;
+        let modified_ns = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Ok(Fingerprint {
+            path: path.to_path_buf(),
+            size: metadata.len(),
+            modified_ns,
+        })
+    }
+}
+
+/// JournalEntry is one completed file and its checksum.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub path: PathBuf,
+    /// The SHA-256 checksum of the file, as lowercase hex.
+    pub checksum: String,
+}
+
+impl JournalEntry {
+    /// Checksum the file at `path`.
+    pub fn of(path: &Path) -> Result<JournalEntry> {
+        Ok(JournalEntry {
+            path: path.to_path_buf(),
+            checksum: file_checksum(path)?,
+        })
+    }
+
+    /// Whether the file is still where it was and has the same checksum.
+    pub fn matches(&self, path: &Path) -> bool {
+        self.path == path && path.exists() && file_checksum(path).is_ok_and(|c| c == self.checksum)
+    }
+}
+
+/// Line is one line of a saved journal.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Line {
+    /// The first line: the source of the run and the options its outputs depend on.
+    Run {
+        source: Option<Fingerprint>,
+        #[serde(default)]
+        options: BTreeMap<String, String>,
+    },
+    /// An entry completed as the file it names; a later line for the same index replaces it.
+    Completed { index: usize, entry: JournalEntry },
+    /// The final output of the run.
+    Output(JournalEntry),
+}
+
+/// Journal records the progress of a run.
+#[derive(Debug, Default)]
+pub struct Journal {
+    /// Where the journal is saved.
+    path: PathBuf,
+    /// Whether the journal is kept; one opened without `--resume` records and saves nothing.
+    active: bool,
+    /// Whether the file at `path` holds every line of the journal, so entries can be appended.
+    saved: bool,
+    /// The file the run reads from; a journal for another version of it is not resumed.
+    pub source: Option<Fingerprint>,
+    /// The options the outputs depend on, such as the padding of slices, by name. A journal
+    /// written with other options is not resumed, as its outputs may have the same names but
+    /// other contents.
+    pub options: BTreeMap<String, String>,
+    /// The completed entries, by index.
+    pub completed: BTreeMap<usize, JournalEntry>,
+    /// The final output of the run, once it has been written.
+    pub output: Option<JournalEntry>,
+}
+
+/// Turn option names and values into the options of a journal.
+fn option_map(options: &[(&str, String)]) -> BTreeMap<String, String> {
+    options
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.clone()))
+        .collect()
+}
+
+impl Journal {
+    /// Start an empty journal at `path`.
+    pub fn new(path: &Path, source: Option<Fingerprint>, options: &[(&str, String)]) -> Journal {
+        Journal {
+            path: path.to_path_buf(),
+            active: true,
+            source,
+            options: option_map(options),
+            ..Default::default()
+        }
+    }
+
+    /// A journal that records nothing, for runs without `--resume`.
+    pub fn inactive(path: &Path) -> Journal {
+        Journal {
+            path: path.to_path_buf(),
+            ..Default::default()
+        }
+    }
+
+    /// Read the journal saved at `path`.
+    ///
+    /// Lines that cannot be read, such as one cut short when a run was killed, are skipped.
+    ///
+    /// # Returns
+    ///
+    /// The journal, or `None` if there is none or its first line does not describe a run.
+    pub fn load(path: &Path) -> Option<Journal> {
+        let text = fs::read_to_string(path).ok()?;
+        let mut lines = text
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Line>(line).ok());
+        let Some(Line::Run { source, options }) = lines.next() else {
+            return None;
+        };
+        let mut journal = Journal {
+            path: path.to_path_buf(),
+            active: true,
+            saved: true,
+            source,
+            options,
+            ..Default::default()
+        };
+        for line in lines {
+            match line {
+                Line::Run { .. } => return None,
+                Line::Completed { index, entry } => {
+                    journal.completed.insert(index, entry);
+                }
+                Line::Output(entry) => journal.output = Some(entry),
+            }
+        }
+        Some(journal)
+    }
+
+    /// Open the journal at `path` to resume from it.
+    ///
+    /// A missing or unreadable journal, or one written for a different version of `source`,
+    /// is replaced by an empty one, so everything is done again.
+    ///
+    /// # Returns
+    ///
+    /// The journal, or a usage error if it was written with other `options`, whose outputs
+    /// would be taken for those of this run.
+    pub fn resume(
+        path: &Path,
+        source: Option<Fingerprint>,
+        options: &[(&str, String)],
+    ) -> Result<Journal> {
+        match Journal::load(path) {
+            Some(journal) if journal.source != source => {
+                println!(
+                    "Warning! {} was written for a different source, starting over.",
+                    path.display()
+                );
+                Ok(Journal::new(path, source, options))
+            }
+            Some(journal) => {
+                let options = option_map(options);
+                let names: BTreeSet<&String> =
+                    journal.options.keys().chain(options.keys()).collect();
+                let show = |o: &BTreeMap<String, String>, name: &String| {
+                    o.get(name).cloned().unwrap_or_else(|| "unset".to_string())
+                };
+                let changed: Vec<String> = names
+                    .into_iter()
+                    .filter(|name| journal.options.get(*name) != options.get(*name))
+                    .map(|name| {
+                        format!(
+                            "{} {} (now {})",
+                            name,
+                            show(&journal.options, name),
+                            show(&options, name)
+                        )
+                    })
+                    .collect();
+                if !changed.is_empty() {
+                    return Err(Error::Usage(format!(
+                        "{} was written with {}. Resume with the same options, or run without --resume to start over.",
+                        path.display(),
+                        changed.join(", ")
+                    )));
+                }
+                println!(
+                    "Resuming from {} with {} completed entries",
+                    path.display(),
+                    journal.completed.len()
+                );
+                Ok(journal)
+            }
+            None => Ok(Journal::new(path, source, options)),
+        }
+    }
+
+    /// Open the journal at `path` if `resume` is set, otherwise keep none.
+    ///
+    /// See [`Journal::resume`] for when an existing journal is used.
+    pub fn open(
+        path: &Path,
+        source: Option<Fingerprint>,
+        options: &[(&str, String)],
+        resume: bool,
+    ) -> Result<Journal> {
+        if resume {
+            Journal::resume(path, source, options)
+        } else {
+            Ok(Journal::inactive(path))
+        }
+    }
+
+    /// Whether the journal is kept.
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// The overwrite policy for the outputs the journal has not recorded yet.
+    ///
+    /// A run killed between moving an output into place and recording it leaves an output the
+    /// journal does not list, so with a journal kept an existing output is replaced instead of
+    /// being an error: `OverwritePolicy::Error` becomes `OverwritePolicy::Replace`, and the
+    /// other policies are kept. Outputs the journal does list are never written again.
+    pub fn pending_policy(&self, policy: &OverwritePolicy) -> OverwritePolicy {
+        match policy {
+            OverwritePolicy::Error if self.active => OverwritePolicy::Replace,
+            _ => policy.clone(),
+        }
+    }
+
+    /// Whether entry `index` was completed as `path` and the file is unchanged since.
+    pub fn is_complete(&self, index: usize, path: &Path) -> bool {
+        self.completed
+            .get(&index)
+            .is_some_and(|entry| entry.matches(path))
+    }
+
+    /// The planned outputs that still have to be written, printing the ones that are done.
+    pub fn pending(&self, outputs: &[PlannedOutput]) -> Vec<PlannedOutput> {
+        outputs
+            .iter()
+            .filter(|out| {
+                let done = self.is_complete(out.index, &out.path);
+                if done {
+                    println!("Already done: {}", out.path.display());
+                }
+                !done
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Record entry `index` as completed, if the journal is kept.
+    ///
+    /// The entry is appended to the saved journal, or the whole journal is saved if it has
+    /// not been yet.
+    pub fn record(&mut self, index: usize, path: &Path) -> Result<()> {
+        if !self.active {
+            return Ok(());
+        }
+        let entry = JournalEntry::of(path)?;
+        self.completed.insert(index, entry.clone());
+        if !self.saved || !self.path.exists() {
+            return self.save();
+        }
+        let mut file = OpenOptions::new().append(true).open(&self.path)?;
+        writeln!(file, "{}", to_line(&Line::Completed { index, entry })?)?;
+        Ok(())
+    }
+
+    /// Save the whole journal, if it is kept, replacing the previous one in a single rename.
+    pub fn save(&mut self) -> Result<()> {
+        if !self.active {
+            return Ok(());
+        }
+        let mut lines = vec![to_line(&Line::Run {
+            source: self.source.clone(),
+            options: self.options.clone(),
+        })?];
+        for (index, entry) in &self.completed {
+            lines.push(to_line(&Line::Completed {
+                index: *index,
+                entry: entry.clone(),
+            })?);
+        }
+        if let Some(output) = &self.output {
+            lines.push(to_line(&Line::Output(output.clone()))?);
+        }
+        write_text_atomic(
+            &self.path,
+            &(lines.join("\n") + "\n"),
+            &OverwritePolicy::Replace,
+        )?;
+        self.saved = true;
+        Ok(())
+    }
+
+    /// Where the journal is saved.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Format one line of a journal.
+fn to_line(line: &Line) -> Result<String> {
+    serde_json::to_string(line)
+        .map_err(|e| Error::Output(format!("Could not format journal: {}", e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Matrix4;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("slicenii-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn planned(index: usize, path: &Path) -> PlannedOutput {
+        PlannedOutput {
+            index,
+            path: path.to_path_buf(),
+            shape: vec![1],
+            affine: Matrix4::identity(),
+        }
+    }
+
+    fn pad(n: usize) -> [(&'static str, String); 1] {
+        [("pad", n.to_string())]
+    }
+
+    #[test]
+    fn appends_entries_and_reads_them_back() {
+        let dir = scratch("journal-append");
+        let path = dir.join(JOURNAL_NAME);
+        let outputs: Vec<PathBuf> = (0..3).map(|i| dir.join(format!("{}.nii", i))).collect();
+        for (i, output) in outputs.iter().enumerate() {
+            fs::write(output, format!("slice {}", i)).unwrap();
+        }
+
+        let mut journal = Journal::open(&path, None, &pad(1), true).unwrap();
+        journal.record(0, &outputs[0]).unwrap();
+        journal.record(1, &outputs[1]).unwrap();
+        // one line for the run and one for each entry
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 3);
+        // a line cut short by a killed run is skipped
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        write!(file, "{{\"completed\":{{\"ind").unwrap();
+        drop(file);
+
+        let resumed = Journal::open(&path, None, &pad(1), true).unwrap();
+        let planned: Vec<PlannedOutput> = outputs
+            .iter()
+            .enumerate()
+            .map(|(i, output)| planned(i, output))
+            .collect();
+        // the second output changed since it was recorded
+        fs::write(&outputs[1], "changed").unwrap();
+        let pending: Vec<usize> = resumed.pending(&planned).iter().map(|o| o.index).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(resumed.completed.len(), 2);
+        assert_eq!(pending, vec![1, 2]);
+    }
+
+    #[test]
+    fn resumes_only_the_same_run() {
+        let dir = scratch("journal-resume");
+        let path = dir.join(JOURNAL_NAME);
+        let output = dir.join("1.nii");
+        fs::write(&output, "slice").unwrap();
+        let source = |size| {
+            Some(Fingerprint {
+                path: dir.join("src.nii"),
+                size,
+                modified_ns: 0,
+            })
+        };
+        let mut journal = Journal::open(&path, source(10), &pad(1), true).unwrap();
+        journal.record(0, &output).unwrap();
+
+        let other_source = Journal::open(&path, source(11), &pad(1), true).unwrap();
+        let other_options = Journal::open(&path, source(10), &pad(3), true);
+        let same = Journal::open(&path, source(10), &pad(1), true).unwrap();
+        let done = same.is_complete(0, &output);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(other_source.completed.is_empty());
+        let err = other_options.unwrap_err();
+        assert!(matches!(err, Error::Usage(_)));
+        assert!(err.to_string().contains("pad 1 (now 3)"), "{}", err);
+        assert!(done);
+    }
+
+    #[test]
+    fn keeps_nothing_without_resume() {
+        let dir = scratch("journal-inactive");
+        let path = dir.join(JOURNAL_NAME);
+        let output = dir.join("1.nii");
+        fs::write(&output, "slice").unwrap();
+        let mut journal = Journal::open(&path, None, &pad(1), false).unwrap();
+        journal.record(0, &output).unwrap();
+        journal.save().unwrap();
+        let saved = path.exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(!saved);
+        assert!(journal.completed.is_empty());
+        // only a kept journal replaces the outputs it has not recorded
+        assert_eq!(
+            journal.pending_policy(&OverwritePolicy::Error),
+            OverwritePolicy::Error
+        );
+        let active = Journal::new(&path, None, &[]);
+        assert_eq!(
+            active.pending_policy(&OverwritePolicy::Error),
+            OverwritePolicy::Replace
+        );
+        assert_eq!(
+            active.pending_policy(&OverwritePolicy::Skip),
+            OverwritePolicy::Skip
+        );
+    }
+}
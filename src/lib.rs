@@ -1 +1,20 @@
+pub mod asl;
+pub mod cli;
+pub mod commands;
 pub mod common;
+pub mod dwi;
+pub mod error;
+pub mod groups;
+pub mod header;
+pub mod io;
+pub mod journal;
+pub mod naming;
+pub mod ordering;
+pub mod packets;
+pub mod placement;
+pub mod plan;
+pub mod provenance;
+pub mod sidecar;
+pub mod slicing;
+pub mod topup;
+pub mod validate;
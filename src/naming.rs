@@ -0,0 +1,464 @@
+//! Output file names built from templates with BIDS-style entities.
+//!
+//! A template is a file name without its extension, with placeholders in braces:
+//!
+//! * `{stem}` - the input file name without `.nii` or `.nii.gz`
+//! * `{axis}` - the axis the input is cut along (0, 1, 2, or 3 for time)
+//! * `{index}` - the 1-based index of the slice or volume, zero padded to the width needed
+//!   for the total count (at least 3); `{index:04}` sets the width
+//! * `{pad}` - `padded-` if slices are padded, otherwise nothing
+//! * `{time_ms}` - the start time of a volume in milliseconds
+//! * `{mm}` - the distance of a slice from the first slice, in millimetres
+//!
+//! The same template parses file names back into indices when combining.
+use nifti::{NiftiHeader, Unit};
+use std::path::{Path, PathBuf};
+
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::header::{seconds_per_unit, units, volume_onset};
+use crate::io::nifti_extension;
+
+/// The default name of slice files.
+pub const SLICE_TEMPLATE: &str = "{stem}_axis-{axis}_slice-{pad}{index}";
+/// The default name of volume files.
+pub const VOL_TEMPLATE: &str = "{stem}_vol-{index}";
+/// The default name of the directory slice files are written to.
+pub const SLICE_DIR: &str = "{stem}_slices";
+/// The default name of the directory volume files are written to.
+pub const VOL_DIR: &str = "{stem}_vols";
+/// The default name of packet files.
+pub const PACKET_TEMPLATE: &str = "{stem}_axis-{axis}_packet-{index}";
+/// The default name of the directory packet files are written to.
+pub const PACKET_DIR: &str = "{stem}_packets";
+/// The default name of the directory diffusion shell files are written to.
+pub const SHELL_DIR: &str = "{stem}_shells";
+/// The default name of the directory echo or cycle group files are written to.
+pub const GROUP_DIR: &str = "{stem}_groups";
+/// The default name of the directory ASL label and control series are written to.
+pub const ASL_DIR: &str = "{stem}_types";
+
+/// The narrowest zero padding of `{index}`, so names match those of earlier versions.
+const MIN_INDEX_WIDTH: usize = 3;
+
+/// Part is a piece of a parsed template.
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Stem,
+    Axis,
+    /// The index, with an explicit width if one was given.
+    Index(Option<usize>),
+    Pad,
+    TimeMs,
+    Mm,
+}
+
+/// NameTemplate is a parsed naming template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NameTemplate {
+    text: String,
+    parts: Vec<Part>,
+}
+
+/// Parse a template, checking its placeholders.
+pub fn parse_template(s: &str) -> std::result::Result<NameTemplate, String> {
+    let mut parts = Vec::new();
+    let mut rest = s;
+    while let Some(open) = rest.find('{') {
+        if open > 0 {
+            parts.push(Part::Literal(rest[..open].to_string()));
+        }
+        let close = rest[open..]
+            .find('}')
+            .ok_or_else(|| format!("'{}' has an unclosed {{", s))?
+            + open;
+        let name = &rest[open + 1..close];
+        let part = match name {
+            "stem" => Part::Stem,
+            "axis" => Part::Axis,
+            "index" => Part::Index(None),
+            "pad" => Part::Pad,
+            "time_ms" => Part::TimeMs,
+            "mm" => Part::Mm,
+            _ => match name.strip_prefix("index:") {
+                Some(width) => {
+                    Part::Index(Some(width.parse().map_err(|_| {
+                        format!("'{}' is not a width in {{{}}}", width, name)
+                    })?))
+                }
+                None => return Err(format!("{{{}}} is not a known placeholder", name)),
+            },
+        };
+        parts.push(part);
+        rest = &rest[close + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Literal(rest.to_string()));
+    }
+    if parts
+        .iter()
+        .any(|p| matches!(p, Part::Literal(l) if l.contains('}')))
+    {
+        return Err(format!("'{}' has an unopened }}", s));
+    }
+    if s.contains('/') || s.contains('\\') {
+        return Err(format!("'{}' must be a file name, not a path", s));
+    }
+    Ok(NameTemplate {
+        text: s.to_string(),
+        parts,
+    })
+}
+
+/// Parse a template for output files, which must contain `{index}` so names are unique.
+pub fn parse_file_template(s: &str) -> std::result::Result<NameTemplate, String> {
+    let template = parse_template(s)?;
+    if !template.has_index() {
+        return Err(format!(
+            "'{}' has no {{index}}, so every file would get the same name",
+            s
+        ));
+    }
+    Ok(template)
+}
+
+/// Parse a template for the output directory, which is shared by every file of a run and so
+/// can only use `{stem}`, `{axis}` and `{pad}`.
+pub fn parse_dir_template(s: &str) -> std::result::Result<NameTemplate, String> {
+    let template = parse_template(s)?;
+    if template
+        .parts
+        .iter()
+        .any(|p| matches!(p, Part::Index(_) | Part::TimeMs | Part::Mm))
+    {
+        return Err(format!(
+            "'{}' can only use {{stem}}, {{axis}} and {{pad}}, as it names one directory for every file",
+            s
+        ));
+    }
+    Ok(template)
+}
+
+/// NameFields are the values placeholders are filled with.
+#[derive(Debug, Clone)]
+pub struct NameFields<'a> {
+    pub stem: &'a str,
+    pub axis: &'a Direction,
+    /// The 0-based index of the slice or volume.
+    pub index: usize,
+    /// How many slices or volumes there are, which sets the default width of `{index}`.
+    pub count: usize,
+    pub padded: bool,
+    pub time_ms: Option<f64>,
+    pub mm: Option<f64>,
+}
+
+/// Format a number for a file name, with at most two decimals and no trailing zeros.
+fn format_number(value: f64) -> String {
+    let rounded = (value * 100.0).round() / 100.0;
+    format!("{}", rounded)
+}
+
+impl NameTemplate {
+    /// Whether the template has a `{stem}` placeholder.
+    pub fn has_stem(&self) -> bool {
+        self.parts.contains(&Part::Stem)
+    }
+
+    /// Whether the template has an `{index}` placeholder.
+    pub fn has_index(&self) -> bool {
+        self.parts.iter().any(|p| matches!(p, Part::Index(_)))
+    }
+
+    /// Fill in the template.
+    ///
+    /// Returns a usage error if the template uses a placeholder that has no value here, such
+    /// as `{mm}` for volumes.
+    pub fn render(&self, fields: &NameFields) -> Result<String> {
+        let mut name = String::new();
+        for part in &self.parts {
+            match part {
+                Part::Literal(text) => name.push_str(text),
+                Part::Stem => name.push_str(fields.stem),
+                Part::Axis => name.push_str(&fields.axis.to_string()),
+                Part::Index(width) => {
+                    let width = width
+                        .unwrap_or_else(|| fields.count.to_string().len().max(MIN_INDEX_WIDTH));
+                    name.push_str(&format!("{:0width$}", fields.index + 1, width = width));
+                }
+                Part::Pad => {
+                    if fields.padded {
+                        name.push_str("padded-");
+                    }
+                }
+                Part::TimeMs => match fields.time_ms {
+                    Some(t) => name.push_str(&format_number(t)),
+                    None => {
+                        return Err(Error::Usage(format!(
+                            "{{time_ms}} in '{}' is only available when splitting a time series",
+                            self.text
+                        )))
+                    }
+                },
+                Part::Mm => match fields.mm {
+                    Some(mm) => name.push_str(&format_number(mm)),
+                    None => {
+                        return Err(Error::Usage(format!(
+                            "{{mm}} in '{}' is only available when slicing",
+                            self.text
+                        )))
+                    }
+                },
+            }
+        }
+        Ok(name)
+    }
+
+    /// Parse the 1-based index out of a file name made from this template.
+    ///
+    /// The extension is ignored. Placeholders other than `{index}` match any text, and
+    /// `{index}` matches a run of digits.
+    ///
+    /// # Returns
+    ///
+    /// The index as written in the name, or `None` if the name does not fit the template.
+    pub fn parse_index(&self, file_name: &str) -> Option<u128> {
+        let stem = file_name.strip_suffix(".gz").unwrap_or(file_name);
+        let stem = stem.strip_suffix(".nii").unwrap_or(stem);
+        self.capture(stem, |p| matches!(p, Part::Index(_)))?
+            .parse()
+            .ok()
+    }
+
+    /// Parse the `{stem}` out of a name made from this template, such as a directory name.
+    pub fn parse_stem(&self, name: &str) -> Option<String> {
+        self.capture(name, |p| *p == Part::Stem)
+    }
+
+    /// Match `text` against the template and return what the first part chosen by `wanted`
+    /// matched.
+    fn capture(&self, text: &str, wanted: impl Fn(&Part) -> bool) -> Option<String> {
+        let captures = match_parts(&self.parts, text)?;
+        captures
+            .into_iter()
+            .find(|(i, _)| wanted(&self.parts[*i]))
+            .map(|(_, matched)| matched)
+    }
+}
+
+/// Match `text` against template parts.
+///
+/// Literals must match exactly, `{index}` matches a run of digits and other placeholders
+/// match any text, trying shorter matches first.
+///
+/// # Returns
+///
+/// What each placeholder matched, by its position in `parts`, or `None` if `text` does not fit.
+fn match_parts(parts: &[Part], text: &str) -> Option<Vec<(usize, String)>> {
+    fn go(parts: &[Part], offset: usize, text: &str) -> Option<Vec<(usize, String)>> {
+        let Some((part, rest)) = parts.split_first() else {
+            return if text.is_empty() {
+                Some(Vec::new())
+            } else {
+                None
+            };
+        };
+        let candidates: Vec<usize> = match part {
+            Part::Literal(literal) => {
+                return go(rest, offset + 1, text.strip_prefix(literal.as_str())?);
+            }
+            Part::Index(_) => {
+                let digits =
+                    text.len() - text.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+                // try the longest run of digits first
+                (1..=digits).rev().collect()
+            }
+            _ => (0..=text.len())
+                .filter(|&n| text.is_char_boundary(n))
+                .collect(),
+        };
+        candidates.into_iter().find_map(|n| {
+            let mut captures = go(rest, offset + 1, &text[n..])?;
+            captures.insert(0, (offset, text[..n].to_string()));
+            Some(captures)
+        })
+    }
+    go(parts, 0, text)
+}
+
+/// OutputNaming is how the files of a slicing or splitting run are named.
+#[derive(Debug, Clone)]
+pub struct OutputNaming {
+    /// The template for the directory the files go in.
+    pub dir: NameTemplate,
+    /// The template for the files.
+    pub file: NameTemplate,
+    /// Whether the files are gzipped.
+    pub compress: bool,
+}
+
+impl OutputNaming {
+    /// The naming of slice files, using the defaults for anything not given.
+    pub fn slices(
+        file: Option<&NameTemplate>,
+        dir: Option<&NameTemplate>,
+        compress: bool,
+    ) -> OutputNaming {
+        OutputNaming {
+            dir: dir
+                .cloned()
+                .unwrap_or_else(|| parse_template(SLICE_DIR).expect("default template")),
+            file: file
+                .cloned()
+                .unwrap_or_else(|| parse_template(SLICE_TEMPLATE).expect("default template")),
+            compress,
+        }
+    }
+
+    /// The naming of volume files, using the defaults for anything not given.
+    pub fn vols(
+        file: Option<&NameTemplate>,
+        dir: Option<&NameTemplate>,
+        compress: bool,
+    ) -> OutputNaming {
+        OutputNaming {
+            dir: dir
+                .cloned()
+                .unwrap_or_else(|| parse_template(VOL_DIR).expect("default template")),
+            file: file
+                .cloned()
+                .unwrap_or_else(|| parse_template(VOL_TEMPLATE).expect("default template")),
+            compress,
+        }
+    }
+
+    /// The naming of packet files, using the defaults for anything not given.
+    pub fn packets(
+        file: Option<&NameTemplate>,
+        dir: Option<&NameTemplate>,
+        compress: bool,
+    ) -> OutputNaming {
+        OutputNaming {
+            dir: dir
+                .cloned()
+                .unwrap_or_else(|| parse_template(PACKET_DIR).expect("default template")),
+            file: file
+                .cloned()
+                .unwrap_or_else(|| parse_template(PACKET_TEMPLATE).expect("default template")),
+            compress,
+        }
+    }
+
+    /// The directory the files go in, within `output_basepath`.
+    pub fn dir_path(&self, output_basepath: &Path, fields: &NameFields) -> Result<PathBuf> {
+        Ok(output_basepath.join(self.dir.render(fields)?))
+    }
+
+    /// The path of one file, within `dir`.
+    pub fn file_path(&self, dir: &Path, fields: &NameFields) -> Result<PathBuf> {
+        Ok(dir.join(format!(
+            "{}.{}",
+            self.file.render(fields)?,
+            nifti_extension(self.compress)
+        )))
+    }
+}
+
+/// The start time of volume `index` in milliseconds, from the toffset and TR of `header`.
+///
+/// Returns `None` if the 4th dimension of `header` is not time.
+pub fn volume_time_ms(header: &NiftiHeader, index: usize) -> Option<f64> {
+    seconds_per_unit(header).map(|seconds| volume_onset(header, index) * seconds * 1000.0)
+}
+
+/// The distance of slice `index` from the first slice along `axis`, in millimetres.
+pub fn slice_offset_mm(header: &NiftiHeader, axis: &Direction, index: usize) -> f64 {
+    let mm = match units(header).0 {
+        Unit::Meter => 1000.0,
+        Unit::Micron => 0.001,
+        _ => 1.0,
+    };
+    index as f64 * header.pixdim[axis.to_usize() + 1] as f64 * mm
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(index: usize, count: usize) -> NameFields<'static> {
+        NameFields {
+            stem: "sub-01_T1w",
+            axis: &Direction::Z,
+            index,
+            count,
+            padded: false,
+            time_ms: None,
+            mm: None,
+        }
+    }
+
+    #[test]
+    fn rejects_bad_templates() {
+        assert!(parse_template("{stem}_{index").is_err());
+        assert!(parse_template("{stem}_{slice}").is_err());
+        assert!(parse_template("{index:x}").is_err());
+        assert!(parse_template("{stem}}").is_err());
+        assert!(parse_template("out/{index}").is_err());
+        assert!(parse_file_template("{stem}_axis-{axis}").is_err());
+        assert!(parse_dir_template("{stem}_{index}").is_err());
+        assert!(parse_dir_template(SLICE_DIR).is_ok());
+    }
+
+    #[test]
+    fn index_width_follows_the_count() {
+        let template = parse_file_template(SLICE_TEMPLATE).unwrap();
+        assert_eq!(
+            template.render(&fields(4, 20)).unwrap(),
+            "sub-01_T1w_axis-2_slice-005"
+        );
+        assert_eq!(
+            template.render(&fields(4, 2000)).unwrap(),
+            "sub-01_T1w_axis-2_slice-0005"
+        );
+        let fixed = parse_file_template("{index:2}").unwrap();
+        assert_eq!(fixed.render(&fields(4, 2000)).unwrap(), "05");
+    }
+
+    #[test]
+    fn renders_times_and_distances() {
+        let template = parse_file_template("{stem}_{index}_{time_ms}ms_{mm}mm").unwrap();
+        let mut values = fields(0, 3);
+        assert!(template.render(&values).is_err());
+        values.time_ms = Some(1250.0);
+        values.mm = Some(2.456);
+        assert_eq!(
+            template.render(&values).unwrap(),
+            "sub-01_T1w_001_1250ms_2.46mm"
+        );
+    }
+
+    #[test]
+    fn parses_rendered_names_back() {
+        let template = parse_file_template(SLICE_TEMPLATE).unwrap();
+        let mut values = fields(11, 40);
+        values.padded = true;
+        let name = template.render(&values).unwrap();
+        assert_eq!(template.parse_index(&format!("{}.nii.gz", name)), Some(12));
+        assert_eq!(template.parse_index("sub-01_T1w_axis-2_slice-x.nii"), None);
+        assert_eq!(template.parse_index("other.nii"), None);
+        // a stem with digits and separators of its own still parses
+        let vols = parse_file_template(VOL_TEMPLATE).unwrap();
+        assert_eq!(vols.parse_index("run_vol-2_vol-007.nii"), Some(7));
+    }
+
+    #[test]
+    fn parses_stems_out_of_directories() {
+        let dir = parse_dir_template(SLICE_DIR).unwrap();
+        assert_eq!(
+            dir.parse_stem("sub-01_T1w_slices"),
+            Some("sub-01_T1w".to_string())
+        );
+        assert_eq!(dir.parse_stem("sub-01_T1w_vols"), None);
+    }
+}
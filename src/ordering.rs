@@ -0,0 +1,248 @@
+//! Ordering slices by the time they were acquired instead of by their position.
+//!
+//! The acquisition time of every slice comes from `SliceTiming` in the JSON sidecar, which
+//! can describe multiband acquisitions, or from `slice_code` and `slice_duration` in the
+//! header. Slices acquired at the same time form a group. Time-ordered outputs are listed in
+//! a table mapping each file back to its slices, so they can be put back in spatial order.
+use nifti::NiftiHeader;
+use serde_json::Value;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::OverwritePolicy;
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::header::{seconds_per_unit, slice_dim, slice_time};
+use crate::io::write_text_atomic;
+use crate::placement::AXES;
+use crate::sidecar::{slice_encoding, Sidecar};
+
+/// The suffix of the table that maps time-ordered files back to their slices.
+pub const ORDER_TABLE: &str = "_slice_order.tsv";
+/// How close, in the time units of the header, two slices must be to be acquired together.
+const SIMULTANEOUS_TOLERANCE: f64 = 1e-4;
+
+/// The acquisition time of every slice along `axis`, in the time units of `header`.
+///
+/// `SliceTiming` in the sidecar is used if it runs along `axis` and has an entry for every
+/// slice, otherwise `slice_code` and `slice_duration` if `axis` is the slice axis.
+///
+/// # Returns
+///
+/// One time per slice in spatial order, or `None` if neither describes the slices.
+pub fn slice_times(
+    header: &NiftiHeader,
+    sidecar: Option<&Sidecar>,
+    axis: &Direction,
+) -> Option<Vec<f64>> {
+    let n = header.dim[axis.to_usize() + 1] as usize;
+    let from_sidecar = sidecar.and_then(|sidecar| {
+        let (slice_axis, reversed) = slice_encoding(sidecar, header);
+        let timing = sidecar.get("SliceTiming")?.as_array()?;
+        if slice_axis != axis.to_usize() || timing.len() != n {
+            return None;
+        }
+        // SliceTiming is in seconds
+        let unit = seconds_per_unit(header)?;
+        let mut times: Vec<f64> = timing
+            .iter()
+            .map(|t| Value::as_f64(t).map(|t| t / unit))
+            .collect::<Option<Vec<f64>>>()?;
+        if reversed {
+            times.reverse();
+        }
+        Some(times)
+    });
+    from_sidecar.or_else(|| {
+        if slice_dim(header) != Some(axis.to_usize()) {
+            return None;
+        }
+        (0..n)
+            .map(|i| slice_time(header, i).map(f64::from))
+            .collect()
+    })
+}
+
+/// TimeGroup is the slices acquired at one time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeGroup {
+    /// When the slices were acquired, in the time units of the header.
+    pub time: f64,
+    /// The positions of the slices, in spatial order.
+    pub slices: Vec<usize>,
+}
+
+/// Order slices by acquisition time.
+///
+/// # Arguments
+///
+/// * `times` - The acquisition time of every slice, in spatial order.
+/// * `simultaneous` - Whether slices acquired at the same time, as in multiband, share a
+///   group; otherwise every slice has a group of its own.
+///
+/// # Returns
+///
+/// The groups in the order they were acquired; slices acquired together are ordered by
+/// position.
+pub fn acquisition_groups(times: &[f64], simultaneous: bool) -> Vec<TimeGroup> {
+    let mut order: Vec<usize> = (0..times.len()).collect();
+    order.sort_by(|&a, &b| times[a].total_cmp(&times[b]).then(a.cmp(&b)));
+    let mut groups: Vec<TimeGroup> = Vec::new();
+    for index in order {
+        match groups.last_mut() {
+            Some(group)
+                if simultaneous && (times[index] - group.time).abs() <= SIMULTANEOUS_TOLERANCE =>
+            {
+                group.slices.push(index)
+            }
+            _ => groups.push(TimeGroup {
+                time: times[index],
+                slices: vec![index],
+            }),
+        }
+    }
+    groups
+}
+
+/// OrderRow is one line of a slice order table: which slices a file holds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderRow {
+    /// The number of the file, its place in the order of acquisition counting from 1.
+    pub index: usize,
+    /// The file.
+    pub file: PathBuf,
+    /// The axis the slices were cut along.
+    pub axis: Direction,
+    /// The 0-based positions of the slices in the file, in the order they are stored.
+    pub slices: Vec<usize>,
+    /// When the slices were acquired, in the time units of the source header.
+    pub time: f64,
+}
+
+/// Write a slice order table, `<stem>_slice_order.tsv` in `dir`.
+///
+/// The table has the columns `index`, `file`, `axis`, `slices` (counting from 1, separated
+/// by commas) and `time`. Several shards may write it at once, so it is replaced atomically
+/// whatever the overwrite policy.
+///
+/// # Returns
+///
+/// The path of the table.
+pub fn write_order_table(dir: &Path, stem: &str, rows: &[OrderRow]) -> Result<PathBuf> {
+    fs::create_dir_all(dir)?;
+    let mut table = String::from("index\tfile\taxis\tslices\ttime\n");
+    for row in rows {
+        let file = row.file.file_name().and_then(|n| n.to_str()).unwrap_or("");
+        let slices: Vec<String> = row.slices.iter().map(|s| (s + 1).to_string()).collect();
+        table.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            row.index,
+            file,
+            row.axis,
+            slices.join(","),
+            row.time
+        ));
+    }
+    let path = dir.join(format!("{}{}", stem, ORDER_TABLE));
+    write_text_atomic(&path, &table, &OverwritePolicy::Replace)?;
+    Ok(path)
+}
+
+/// Read a slice order table written by [`write_order_table`].
+///
+/// # Returns
+///
+/// The rows, with the files resolved against the directory of the table.
+pub fn read_order_table(path: &Path) -> Result<Vec<OrderRow>> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let text = fs::read_to_string(path)?;
+    let bad_line = |n: usize| {
+        Error::Input(format!(
+            "Line {} of {} is not index, file, axis, slices and time.",
+            n + 1,
+            path.display()
+        ))
+    };
+    let mut rows = Vec::new();
+    for (n, line) in text.lines().enumerate().skip(1) {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 5 {
+            return Err(bad_line(n));
+        }
+        let axis = match fields[2] {
+            "0" => 0,
+            "1" => 1,
+            "2" => 2,
+            _ => return Err(bad_line(n)),
+        };
+        let slices = fields[3]
+            .split(',')
+            .map(|s| match s.trim().parse::<usize>() {
+                Ok(s) if s >= 1 => Ok(s - 1),
+                _ => Err(bad_line(n)),
+            })
+            .collect::<Result<Vec<usize>>>()?;
+        rows.push(OrderRow {
+            index: fields[0].parse().map_err(|_| bad_line(n))?,
+            file: dir.join(fields[1]),
+            axis: AXES[axis].clone(),
+            slices,
+            time: fields[4].parse().map_err(|_| bad_line(n))?,
+        });
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_slices_by_time() {
+        let groups = acquisition_groups(&[0.0, 1.0, 0.5, 1.5], false);
+        let slices: Vec<Vec<usize>> = groups.iter().map(|g| g.slices.clone()).collect();
+        assert_eq!(slices, vec![vec![0], vec![2], vec![1], vec![3]]);
+        assert_eq!(groups[1].time, 0.5);
+    }
+
+    #[test]
+    fn groups_simultaneous_slices() {
+        let times = [0.0, 0.5, 0.00001, 0.5, 1.0];
+        let groups = acquisition_groups(&times, true);
+        let slices: Vec<Vec<usize>> = groups.iter().map(|g| g.slices.clone()).collect();
+        assert_eq!(slices, vec![vec![0, 2], vec![1, 3], vec![4]]);
+        // without grouping, ties keep their spatial order
+        let single = acquisition_groups(&times, false);
+        assert_eq!(single.len(), 5);
+        assert_eq!(single[2].slices, vec![1]);
+        assert_eq!(single[3].slices, vec![3]);
+    }
+
+    #[test]
+    fn order_table_round_trip() {
+        let dir = std::env::temp_dir().join(format!("slicenii-order-{}", std::process::id()));
+        let rows = vec![
+            OrderRow {
+                index: 1,
+                file: dir.join("a.nii"),
+                axis: Direction::Z,
+                slices: vec![0, 2],
+                time: 0.0,
+            },
+            OrderRow {
+                index: 2,
+                file: dir.join("b.nii"),
+                axis: Direction::Z,
+                slices: vec![1, 3],
+                time: 0.5,
+            },
+        ];
+        let path = write_order_table(&dir, "t", &rows).unwrap();
+        let read = read_order_table(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read, rows);
+    }
+}
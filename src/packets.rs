@@ -0,0 +1,276 @@
+//! Interleaved acquisition packets, as used for slice-to-volume reconstruction.
+//!
+//! An interleaved acquisition with `n` packets acquires every `n`-th slice in one sweep, so
+//! packet `p` holds the slices `p, p + n, p + 2n, ...`. Each packet is a volume of its own,
+//! with the slice spacing multiplied by `n` and its origin on its first slice, and the
+//! packets are put back together by reading those affines.
+use nalgebra::Matrix4;
+use nifti::NiftiHeader;
+
+use crate::common::Direction;
+use crate::header::{slice_dim, slice_order_position, slice_time};
+use crate::placement::{voxel_origin, PLANE_TOLERANCE};
+use crate::slicing::slice_affine;
+use crate::validate::AFFINE_TOLERANCE;
+
+/// Packet is the slices of one acquisition sweep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Packet {
+    /// The first slice of the packet along the axis.
+    pub offset: usize,
+    /// The step between the slices of the packet, which is the number of packets.
+    pub stride: usize,
+    /// How many slices the packet has.
+    pub len: usize,
+}
+
+impl Packet {
+    /// The positions of the slices of the packet in the full volume, in spatial order.
+    pub fn slices(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len).map(|k| self.offset + k * self.stride)
+    }
+}
+
+/// The number of packets implied by the slice timing of `header` along `axis`.
+///
+/// # Returns
+///
+/// 2 for the alternating orders of `slice_code`, 1 for the sequential ones, and `None` if
+/// the order is unknown or `axis` is not the slice axis.
+pub fn packet_count(header: &NiftiHeader, axis: &Direction) -> Option<usize> {
+    if slice_dim(header) != Some(axis.to_usize()) {
+        return None;
+    }
+    match header.slice_code {
+        1 | 2 => Some(1),
+        3..=6 => Some(2),
+        _ => None,
+    }
+}
+
+/// Split the slices along `axis` into `count` interleaved packets.
+///
+/// # Returns
+///
+/// The packets in the order they were acquired, if the header knows it, otherwise in
+/// spatial order.
+pub fn plan_packets(header: &NiftiHeader, axis: &Direction, count: usize) -> Vec<Packet> {
+    let n = header.dim[axis.to_usize() + 1] as usize;
+    let mut packets: Vec<Packet> = (0..count.min(n))
+        .map(|offset| Packet {
+            offset,
+            stride: count,
+            len: (n - offset).div_ceil(count),
+        })
+        .collect();
+    if slice_dim(header) == Some(axis.to_usize()) {
+        // a packet is acquired when its first acquired slice is
+        packets.sort_by_key(|packet| {
+            packet
+                .slices()
+                .filter_map(|i| slice_order_position(header, i))
+                .min()
+                .unwrap_or(usize::MAX)
+        });
+    }
+    packets
+}
+
+/// The affine of a packet: the affine of its first slice with the step along `axis`
+/// multiplied by the stride.
+pub fn packet_affine(header: &NiftiHeader, axis: &Direction, packet: &Packet) -> Matrix4<f64> {
+    let mut affine = slice_affine(header, axis, packet.offset);
+    for row in 0..3 {
+        affine[(row, axis.to_usize())] *= packet.stride as f64;
+    }
+    affine
+}
+
+/// The header of a packet, from the header of the full volume.
+///
+/// The affine and voxel size come from [`packet_affine`]. If the packet was cut along the
+/// slice axis, its slices were acquired one after the other: `toffset` moves on to its first
+/// acquired slice, and `slice_code` becomes sequential, increasing or decreasing. If its
+/// slices were not acquired in a row, the slice timing fields are cleared.
+pub fn packet_header(source: &NiftiHeader, axis: &Direction, packet: &Packet) -> NiftiHeader {
+    let mut header = source.clone();
+    header.set_affine(&packet_affine(source, axis, packet));
+    if slice_dim(source) != Some(axis.to_usize()) {
+        return header;
+    }
+    if let Some(first) = packet
+        .slices()
+        .filter_map(|i| slice_time(source, i))
+        .reduce(f32::min)
+    {
+        header.toffset += first;
+    }
+    let positions: Vec<Option<usize>> = packet
+        .slices()
+        .map(|i| slice_order_position(source, i))
+        .collect();
+    let steps: Vec<Option<i64>> = positions
+        .windows(2)
+        .map(|w| Some(w[1]? as i64 - w[0]? as i64))
+        .collect();
+    header.slice_start = 0;
+    header.slice_end = packet.len as i16 - 1;
+    header.slice_code = if positions.iter().any(Option::is_none) {
+        0
+    } else if steps.iter().all(|&s| s == Some(1)) {
+        1
+    } else if steps.iter().all(|&s| s == Some(-1)) {
+        2
+    } else {
+        0
+    };
+    if header.slice_code == 0 {
+        header.slice_end = 0;
+        header.slice_duration = 0.0;
+    }
+    header
+}
+
+/// Work out which packet of the reference grid a file holds from its header.
+///
+/// # Returns
+///
+/// The packet, or a description of why the file is not a packet of the reference along
+/// `axis`: its shape, rotation or voxel sizes do not fit, or its origin is not on a slice of
+/// the reference.
+pub fn locate_packet(
+    header: &NiftiHeader,
+    reference: &NiftiHeader,
+    axis: &Direction,
+) -> std::result::Result<Packet, String> {
+    let a = axis.to_usize();
+    if header.dim[0] != 3 || (0..3).any(|i| i != a && header.dim[i + 1] != reference.dim[i + 1]) {
+        return Err(format!(
+            "shape {:?} does not fit the reference {:?} along the other axes",
+            &header.dim[1..=header.dim[0] as usize],
+            &reference.dim[1..4]
+        ));
+    }
+    let ref_affine = reference.affine::<f64>();
+    let affine = header.affine::<f64>();
+    let ref_step = ref_affine.fixed_view::<3, 1>(0, a).norm();
+    let ratio = affine.fixed_view::<3, 1>(0, a).norm() / ref_step;
+    let stride = ratio.round();
+    if stride < 1.0 || (ratio - stride).abs() * ref_step > AFFINE_TOLERANCE {
+        return Err(format!(
+            "slice spacing is {:.4} times that of the reference, not a whole number",
+            ratio
+        ));
+    }
+    let mut expected = ref_affine;
+    for row in 0..3 {
+        expected[(row, a)] *= stride;
+    }
+    let linear_diff = (affine.fixed_view::<3, 3>(0, 0) - expected.fixed_view::<3, 3>(0, 0))
+        .abs()
+        .max();
+    if linear_diff > AFFINE_TOLERANCE {
+        return Err(format!(
+            "rotation or voxel sizes differ by up to {:.4} from an interleaved reference",
+            linear_diff
+        ));
+    }
+    let origin = voxel_origin(&ref_affine, &affine)
+        .ok_or_else(|| "the reference affine cannot be inverted".to_string())?;
+    for (i, &v) in origin.iter().enumerate() {
+        let target = if i == a { v.round() } else { 0.0 };
+        if (v - target).abs() > PLANE_TOLERANCE {
+            return Err(format!(
+                "origin lies at voxel [{:.3}, {:.3}, {:.3}], not on a slice along axis {}",
+                origin[0], origin[1], origin[2], axis
+            ));
+        }
+    }
+    let offset = origin[a].round();
+    let n = reference.dim[a + 1] as usize;
+    if offset < 0.0 || offset as usize >= n {
+        return Err(format!(
+            "origin lies at slice {} along axis {}, outside the reference",
+            offset, axis
+        ));
+    }
+    Ok(Packet {
+        offset: offset as usize,
+        stride: stride as usize,
+        len: header.dim[a + 1] as usize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nalgebra::Vector4;
+
+    /// A 6x5x5 grid of 2 mm voxels whose slices along z were acquired 1, 3, 0, 2, 4.
+    fn reference() -> NiftiHeader {
+        let mut header = NiftiHeader {
+            dim: [3, 6, 5, 5, 1, 1, 1, 1],
+            pixdim: [1.0, 2.0, 2.0, 2.0, 1.0, 1.0, 1.0, 1.0],
+            dim_info: 3 << 4,
+            slice_code: 5,
+            slice_start: 0,
+            slice_end: 4,
+            slice_duration: 10.0,
+            ..Default::default()
+        };
+        let mut affine = Matrix4::from_diagonal(&Vector4::new(2.0, 2.0, 2.0, 1.0));
+        affine[(0, 3)] = -6.0;
+        header.set_affine(&affine);
+        header
+    }
+
+    #[test]
+    fn plans_packets_in_acquisition_order() {
+        let packets = plan_packets(&reference(), &Direction::Z, 2);
+        let slices: Vec<Vec<usize>> = packets.iter().map(|p| p.slices().collect()).collect();
+        assert_eq!(slices, vec![vec![1, 3], vec![0, 2, 4]]);
+        assert_eq!(packet_count(&reference(), &Direction::Z), Some(2));
+        assert_eq!(packet_count(&reference(), &Direction::X), None);
+    }
+
+    #[test]
+    fn packet_headers_locate_back() {
+        let reference = reference();
+        for packet in plan_packets(&reference, &Direction::Z, 2) {
+            let mut header = packet_header(&reference, &Direction::Z, &packet);
+            header.dim[3] = packet.len as u16;
+            assert_eq!(header.slice_code, 1);
+            assert_eq!(
+                locate_packet(&header, &reference, &Direction::Z),
+                Ok(packet)
+            );
+        }
+        let packets = plan_packets(&reference, &Direction::Z, 2);
+        let header = packet_header(&reference, &Direction::Z, &packets[1]);
+        assert_eq!(header.toffset, 20.0);
+    }
+
+    #[test]
+    fn rejects_files_off_the_reference() {
+        let reference = reference();
+        let packet = Packet {
+            offset: 1,
+            stride: 2,
+            len: 2,
+        };
+        let mut header = packet_header(&reference, &Direction::Z, &packet);
+        header.dim[3] = 2;
+        let mut shifted = header.clone();
+        shifted.srow_x[3] += 1.0;
+        let err = locate_packet(&shifted, &reference, &Direction::Z).unwrap_err();
+        assert!(err.contains("not on a slice"), "{}", err);
+        let mut narrow = header.clone();
+        narrow.dim[1] = 3;
+        let err = locate_packet(&narrow, &reference, &Direction::Z).unwrap_err();
+        assert!(err.contains("does not fit"), "{}", err);
+        let mut spaced = header;
+        spaced.srow_z[2] = 3.0;
+        let err = locate_packet(&spaced, &reference, &Direction::Z).unwrap_err();
+        assert!(err.contains("not a whole number"), "{}", err);
+    }
+}
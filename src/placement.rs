@@ -0,0 +1,381 @@
+//! Placing slices in the reference grid by their own affine rather than by file name.
+//!
+//! Every slice written by slicenii has the affine of its source with the origin moved to the
+//! first voxel of the slice (see [`crate::slicing::slice_affine`]). Mapping that origin back
+//! through the inverse of the reference affine gives the voxel the slice starts at, which
+//! reveals both the axis it was cut along and its position on that axis. Renamed or
+//! reshuffled files are therefore still put in the right place.
+//!
+//! Inputs can also be placed by the provenance slicenii records in their headers (see
+//! [`crate::provenance`]), which works for volumes too, and volumes can be ordered by the
+//! onset in their `toffset`.
+use nalgebra::{Matrix4, Vector4};
+use nifti::NiftiHeader;
+
+use crate::cli::AxisChoice;
+use crate::commands::combine::InputFile;
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::io::read_header;
+use crate::provenance::{require_provenance, Provenance};
+use crate::validate::AFFINE_TOLERANCE;
+
+/// How far, in voxels, a slice origin may lie from a plane of the reference grid.
+pub const PLANE_TOLERANCE: f64 = 1e-3;
+
+/// The spatial axes, by their position in the header.
+pub const AXES: [Direction; 3] = [Direction::X, Direction::Y, Direction::Z];
+
+/// Placement is where a slice lies in the reference grid, as read from its affine.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Placement {
+    /// The axis the slice was cut along, or `None` if its origin is the origin of the
+    /// reference, which fits every axis.
+    pub axis: Option<Direction>,
+    /// The 0-based position of the slice along its axis.
+    pub index: usize,
+}
+
+/// The origin of `affine` in the voxel coordinates of the reference.
+pub fn voxel_origin(reference: &Matrix4<f64>, affine: &Matrix4<f64>) -> Option<[f64; 3]> {
+    let origin = reference.try_inverse()? * affine * Vector4::new(0.0, 0.0, 0.0, 1.0);
+    Some([origin[0], origin[1], origin[2]])
+}
+
+/// Work out where one slice lies in the reference grid from its header.
+///
+/// # Arguments
+///
+/// * `header` - The header of the slice.
+/// * `reference` - The header of the reference.
+///
+/// # Returns
+///
+/// The placement of the slice, or a description of why it cannot be placed: its rotation or
+/// voxel sizes differ from the reference, its origin is not on a plane of the reference, it
+/// is shifted along more than one axis, or it lies outside the reference.
+pub fn place(
+    header: &NiftiHeader,
+    reference: &NiftiHeader,
+) -> std::result::Result<Placement, String> {
+    let ref_affine = reference.affine::<f64>();
+    let affine = header.affine::<f64>();
+    let linear_diff = (affine.fixed_view::<3, 3>(0, 0) - ref_affine.fixed_view::<3, 3>(0, 0))
+        .abs()
+        .max();
+    if linear_diff > AFFINE_TOLERANCE {
+        return Err(format!(
+            "rotation or voxel sizes differ by up to {:.4} from the reference",
+            linear_diff
+        ));
+    }
+    let origin = voxel_origin(&ref_affine, &affine)
+        .ok_or_else(|| "the reference affine cannot be inverted".to_string())?;
+
+    let mut placement = Placement {
+        axis: None,
+        index: 0,
+    };
+    for (i, &v) in origin.iter().enumerate() {
+        let plane = v.round();
+        if (v - plane).abs() > PLANE_TOLERANCE {
+            return Err(format!(
+                "origin lies at voxel {:.3} along axis {}, between two planes of the reference",
+                v, AXES[i]
+            ));
+        }
+        if plane == 0.0 {
+            continue;
+        }
+        if placement.axis.is_some() {
+            return Err(format!(
+                "origin is at voxel [{:.3}, {:.3}, {:.3}], shifted along more than one axis",
+                origin[0], origin[1], origin[2]
+            ));
+        }
+        if plane < 0.0 || plane >= reference.dim[i + 1] as f64 {
+            return Err(format!(
+                "origin lies at voxel {} along axis {}, outside the reference",
+                plane, AXES[i]
+            ));
+        }
+        placement = Placement {
+            axis: Some(AXES[i].clone()),
+            index: plane as usize,
+        };
+    }
+    Ok(placement)
+}
+
+/// Place every input by its affine and work out the axis they were cut along.
+///
+/// Every problem is printed with its file before returning, as in
+/// [`crate::validate::check_inputs`]. The axis is the one the slices are shifted along; if
+/// none is (a single slice at the origin) it is `choice`, or the axis along which the slices
+/// are thinner than the reference.
+///
+/// # Arguments
+///
+/// * `inputs` - The files to combine, in any order.
+/// * `reference` - The header of the reference, which must be 3D.
+/// * `choice` - The axis given on the command line, checked against the one found.
+/// * `guessed` - The axis guessed from the shape of the first input.
+///
+/// # Returns
+///
+/// The inputs sorted by their position, with their index and number set from it, and the
+/// axis.
+pub fn place_inputs(
+    inputs: &[InputFile],
+    reference: &NiftiHeader,
+    choice: &AxisChoice,
+    guessed: &Direction,
+) -> Result<(Vec<InputFile>, Direction)> {
+    if reference.dim[0] != 3 {
+        return Err(Error::Usage(
+            "Placing by affine needs a 3D reference, as volumes split across time share one affine."
+                .to_string(),
+        ));
+    }
+    let mut placed = Vec::new();
+    let mut problems = 0;
+    for input in inputs {
+        let placement = read_header(&input.path)
+            .map_err(|e| e.to_string())
+            .and_then(|header| place(&header, reference));
+        match placement {
+            Ok(placement) => placed.push((input.path.clone(), placement)),
+            Err(message) => {
+                eprintln!("Mismatch! {}: {}", input.path.display(), message);
+                problems += 1;
+            }
+        }
+    }
+
+    let mut axes: Vec<&Direction> = Vec::new();
+    for axis in placed.iter().filter_map(|(_, p)| p.axis.as_ref()) {
+        if !axes.contains(&axis) {
+            axes.push(axis);
+        }
+    }
+    let axis = match (axes.as_slice(), choice) {
+        ([], AxisChoice::Fixed(axis)) => axis.clone(),
+        ([], AxisChoice::Guess) => guessed.clone(),
+        ([axis], AxisChoice::Guess) => {
+            println!(
+                "Axis not specified. Found axis {:?} from the affines.",
+                axis
+            );
+            (*axis).clone()
+        }
+        ([axis], AxisChoice::Fixed(fixed)) if *axis == fixed => fixed.clone(),
+        ([axis], AxisChoice::Fixed(fixed)) => {
+            return Err(Error::Input(format!(
+                "The affines place the inputs along axis {:?}, not the given axis {:?}.",
+                axis, fixed
+            )))
+        }
+        _ => {
+            for (path, placement) in &placed {
+                if let Some(axis) = &placement.axis {
+                    eprintln!(
+                        "Mismatch! {}: shifted along axis {:?}",
+                        path.display(),
+                        axis
+                    );
+                }
+            }
+            return Err(Error::Input(
+                "The affines place the inputs along more than one axis.".to_string(),
+            ));
+        }
+    };
+    if problems > 0 {
+        return Err(Error::Input(format!(
+            "{} input(s) could not be placed by their affine.",
+            problems
+        )));
+    }
+
+    let mut placed: Vec<InputFile> = placed
+        .into_iter()
+        .map(|(path, placement)| InputFile {
+            path,
+            number: Some(placement.index as u128 + 1),
+            index: placement.index,
+        })
+        .collect();
+    placed.sort_by(|a, b| a.index.cmp(&b.index).then_with(|| a.path.cmp(&b.path)));
+    Ok((placed, axis))
+}
+
+/// Place every input by the provenance recorded in its header.
+///
+/// Every input must have been written by slicenii, and all must come from the same axis,
+/// which must be `choice` if one was given.
+///
+/// # Returns
+///
+/// The inputs sorted by their recorded index, with their index and number set from it, and
+/// the axis.
+pub fn place_by_provenance(
+    inputs: &[InputFile],
+    choice: &AxisChoice,
+) -> Result<(Vec<InputFile>, Direction)> {
+    let mut placed = Vec::new();
+    let mut problems = 0;
+    let mut axis: Option<Direction> = None;
+    for input in inputs {
+        match require_provenance(&input.path) {
+            Ok(Provenance {
+                indices: Some(_),
+                axis,
+                ..
+            }) => {
+                let hint = if axis == Direction::T {
+                    "a group of volumes, combine it with --shells, --groups or --asl"
+                } else {
+                    "a group of slices acquired together, combine it with --slice-order"
+                };
+                eprintln!("Mismatch! {}: holds {}", input.path.display(), hint);
+                problems += 1;
+            }
+            Ok(provenance) => {
+                let expected = match choice {
+                    AxisChoice::Fixed(fixed) => Some(fixed),
+                    AxisChoice::Guess => axis.as_ref(),
+                };
+                if expected.is_some_and(|a| *a != provenance.axis) {
+                    eprintln!(
+                        "Mismatch! {}: cut along axis {:?}, not {:?}",
+                        input.path.display(),
+                        provenance.axis,
+                        expected.expect("checked above")
+                    );
+                    problems += 1;
+                    continue;
+                }
+                axis.get_or_insert(provenance.axis.clone());
+                placed.push(InputFile {
+                    path: input.path.clone(),
+                    number: Some(provenance.index as u128 + 1),
+                    index: provenance.index,
+                });
+            }
+            Err(e) => {
+                eprintln!("Mismatch! {}", e);
+                problems += 1;
+            }
+        }
+    }
+    if problems > 0 {
+        return Err(Error::Input(format!(
+            "{} input(s) could not be placed by their header.",
+            problems
+        )));
+    }
+    let axis = match (axis, choice) {
+        (Some(axis), AxisChoice::Guess) => {
+            println!("Axis not specified. Found axis {:?} in the headers.", axis);
+            axis
+        }
+        (_, AxisChoice::Fixed(axis)) => axis.clone(),
+        (None, AxisChoice::Guess) => {
+            return Err(Error::Input(
+                "Did not find any input files to combine.".to_string(),
+            ))
+        }
+    };
+    placed.sort_by(|a, b| a.index.cmp(&b.index).then_with(|| a.path.cmp(&b.path)));
+    Ok((placed, axis))
+}
+
+/// Order volumes by the onset in their `toffset`, earliest first.
+///
+/// Every input must have its own onset; inputs sharing one are printed and rejected, as
+/// their order would be a guess.
+///
+/// # Returns
+///
+/// The inputs in order of onset, with their index and number set from it.
+pub fn place_by_toffset(inputs: &[InputFile]) -> Result<Vec<InputFile>> {
+    let mut timed = Vec::new();
+    for input in inputs {
+        timed.push((read_header(&input.path)?.toffset, input.path.clone()));
+    }
+    timed.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+    let mut problems = 0;
+    for pair in timed.windows(2) {
+        if pair[0].0 == pair[1].0 {
+            eprintln!(
+                "Mismatch! {}: same toffset {} as {}",
+                pair[1].1.display(),
+                pair[1].0,
+                pair[0].1.display()
+            );
+            problems += 1;
+        }
+    }
+    if problems > 0 {
+        return Err(Error::Input(format!(
+            "{} input(s) could not be ordered by their toffset.",
+            problems
+        )));
+    }
+    Ok(timed
+        .into_iter()
+        .enumerate()
+        .map(|(index, (_, path))| InputFile {
+            path,
+            number: Some(index as u128 + 1),
+            index,
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 2 mm grid of shape 6x5x4, shifted by `origin` voxels from the reference.
+    fn header(origin: [f64; 3]) -> NiftiHeader {
+        let mut header = NiftiHeader {
+            dim: [3, 6, 5, 4, 1, 1, 1, 1],
+            ..Default::default()
+        };
+        let mut affine = Matrix4::from_diagonal(&Vector4::new(2.0, 2.0, 2.0, 1.0));
+        for i in 0..3 {
+            affine[(i, 3)] = -10.0 + 2.0 * origin[i];
+        }
+        header.set_affine(&affine);
+        header
+    }
+
+    #[test]
+    fn places_slices_by_their_origin() {
+        let reference = header([0.0; 3]);
+        let placement = place(&header([0.0, 0.0, 3.0]), &reference).unwrap();
+        assert_eq!(placement.axis, Some(Direction::Z));
+        assert_eq!(placement.index, 3);
+        let placement = place(&header([4.0, 0.0, 0.0]), &reference).unwrap();
+        assert_eq!(placement.axis, Some(Direction::X));
+        // the first slice fits every axis
+        let placement = place(&reference, &reference).unwrap();
+        assert_eq!((placement.axis, placement.index), (None, 0));
+    }
+
+    #[test]
+    fn rejects_slices_off_the_grid() {
+        let reference = header([0.0; 3]);
+        let between = place(&header([0.0, 1.5, 0.0]), &reference).unwrap_err();
+        assert!(between.contains("between two planes"), "{}", between);
+        let diagonal = place(&header([1.0, 1.0, 0.0]), &reference).unwrap_err();
+        assert!(diagonal.contains("more than one axis"), "{}", diagonal);
+        let outside = place(&header([0.0, 0.0, 4.0]), &reference).unwrap_err();
+        assert!(outside.contains("outside the reference"), "{}", outside);
+        let mut scaled = header([0.0; 3]);
+        scaled.srow_x[0] = 3.0;
+        let scaled = place(&scaled, &reference).unwrap_err();
+        assert!(scaled.contains("voxel sizes differ"), "{}", scaled);
+    }
+}
@@ -0,0 +1,135 @@
+//! Plans of the files a command will write, worked out from headers before any voxels are read.
+//!
+//! Commands build a plan first and then carry it out, so `--dry-run` can print exactly what
+//! would be written without touching disk.
+use nalgebra::Matrix4;
+use std::fmt;
+use std::path::PathBuf;
+
+/// PlannedOutput is one file a command would write.
+#[derive(Debug, Clone)]
+pub struct PlannedOutput {
+    /// The index of the slice or volume along the axis it was cut from.
+    pub index: usize,
+    /// Where the file would be written.
+    pub path: PathBuf,
+    /// The shape of the array in the file.
+    pub shape: Vec<usize>,
+    /// The affine in the header of the file.
+    pub affine: Matrix4<f64>,
+}
+
+/// Shard is one of `count` equal parts of a run, for splitting the work between array jobs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Shard {
+    /// Which part this is, from 1 to `count`.
+    pub index: usize,
+    /// How many parts the run is split into.
+    pub count: usize,
+}
+
+impl Shard {
+    /// The range of indices, out of `total`, that belong to this shard.
+    ///
+    /// Shards get contiguous blocks whose sizes differ by at most one, so neighbouring slices
+    /// are read and written by the same job.
+    pub fn range(&self, total: usize) -> std::ops::Range<usize> {
+        let k = self.index - 1;
+        (k * total / self.count)..((k + 1) * total / self.count)
+    }
+
+    /// Keep only the planned outputs that belong to this shard.
+    pub fn select(&self, outputs: Vec<PlannedOutput>) -> Vec<PlannedOutput> {
+        let range = self.range(outputs.len());
+        outputs
+            .into_iter()
+            .filter(|out| range.contains(&out.index))
+            .collect()
+    }
+}
+
+impl fmt::Display for Shard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// Keep the planned outputs of `shard`, or all of them if the run is not sharded.
+pub fn select_shard(outputs: Vec<PlannedOutput>, shard: Option<&Shard>) -> Vec<PlannedOutput> {
+    match shard {
+        Some(shard) => shard.select(outputs),
+        None => outputs,
+    }
+}
+
+/// Format the top three rows of an affine on one line.
+pub fn format_affine(affine: &Matrix4<f64>) -> String {
+    let rows: Vec<String> = (0..3)
+        .map(|r| {
+            let row: Vec<String> = (0..4).map(|c| format!("{:.4}", affine[(r, c)])).collect();
+            format!("[{}]", row.join(", "))
+        })
+        .collect();
+    format!("[{}]", rows.join(", "))
+}
+
+/// Print every planned output with its shape and affine.
+pub fn print_plan(outputs: &[PlannedOutput]) {
+    for out in outputs {
+        println!(
+            "Would write: {} shape {:?} affine {}",
+            out.path.display(),
+            out.shape,
+            format_affine(&out.affine)
+        );
+    }
+    println!("Dry run: {} file(s) would be written.", outputs.len());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn outputs(count: usize) -> Vec<PlannedOutput> {
+        (0..count)
+            .map(|index| PlannedOutput {
+                index,
+                path: PathBuf::from(format!("slice_{}.nii", index + 1)),
+                shape: vec![2, 2, 1],
+                affine: Matrix4::identity(),
+            })
+            .collect()
+    }
+
+    fn ranges(count: usize, total: usize) -> Vec<std::ops::Range<usize>> {
+        (1..=count)
+            .map(|index| Shard { index, count }.range(total))
+            .collect()
+    }
+
+    #[test]
+    fn shards_cover_every_index_once() {
+        assert_eq!(ranges(3, 9), vec![0..3, 3..6, 6..9]);
+        // the sizes of uneven shards differ by at most one
+        assert_eq!(ranges(3, 10), vec![0..3, 3..6, 6..10]);
+        assert_eq!(ranges(4, 6), vec![0..1, 1..3, 3..4, 4..6]);
+        // more shards than items leaves some of them empty
+        let sparse = ranges(5, 2);
+        assert_eq!(sparse.iter().map(|r| r.len()).sum::<usize>(), 2);
+        assert_eq!(sparse.iter().filter(|r| r.is_empty()).count(), 3);
+        assert_eq!(ranges(1, 7), vec![0..7]);
+    }
+
+    #[test]
+    fn selects_the_outputs_of_a_shard() {
+        let indices = |selected: Vec<PlannedOutput>| -> Vec<usize> {
+            selected.iter().map(|out| out.index).collect()
+        };
+        let shard = Shard { index: 2, count: 3 };
+        assert_eq!(indices(select_shard(outputs(7), Some(&shard))), vec![2, 3]);
+        assert_eq!(indices(select_shard(outputs(3), None)), vec![0, 1, 2]);
+        let last = Shard { index: 4, count: 4 };
+        assert_eq!(indices(select_shard(outputs(3), Some(&last))), vec![2]);
+        assert!(select_shard(outputs(0), Some(&last)).is_empty());
+    }
+}
@@ -0,0 +1,325 @@
+//! Provenance of slices and volumes, stored in their own NIfTI headers.
+//!
+//! File names are easily lost, so every slice or volume slicenii writes records where it came
+//! from in a header extension: the source file, the axis, the index, the padding and the
+//! version of slicenii, as JSON in a comment extension. A short summary also goes in
+//! `descrip`, as a fallback for tools that keep the header but drop extensions. Combining can
+//! read either back to order and check its inputs.
+use nifti::{Extension, NiftiHeader};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use crate::common::{ChunkMeta, Direction};
+use crate::error::{Error, Result};
+use crate::io::{read_extensions, read_header};
+
+/// The extension code for comments, which is what the JSON is stored as.
+pub const ECODE_COMMENT: i32 = 6;
+/// The key the provenance is stored under in the JSON of the extension.
+const KEY: &str = "slicenii";
+
+/// Provenance is where a slice or volume was cut from.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The version of slicenii that wrote the file.
+    pub version: String,
+    /// The file name of the source, if it was read from disk.
+    pub source: Option<String>,
+    /// The axis the source was cut along, `T` for volumes.
+    pub axis: Direction,
+    /// The 0-based position of the slice or volume along `axis`.
+    pub index: usize,
+    /// How many slices or volumes the source has along `axis`.
+    pub count: usize,
+    /// How many copies of the slice are stacked in the file.
+    pub padding: usize,
+    /// The 0-based positions along `axis` of the slices or volumes in the file, if it holds
+    /// a group of them, such as slices acquired together or the volumes of a shell.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub indices: Option<Vec<usize>>,
+}
+
+impl Provenance {
+    /// The provenance of a chunk cut by slicenii, from its metadata.
+    pub fn of(meta: &ChunkMeta) -> Self {
+        let source = meta.source.as_ref();
+        let axis = meta
+            .axis
+            .clone()
+            .expect("chunks cut by slicenii know their axis");
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            source: source
+                .and_then(|s| s.path.as_ref())
+                .and_then(|p| p.file_name())
+                .map(|n| n.to_string_lossy().into_owned()),
+            index: meta.index(),
+            count: source.map_or(0, |s| s.dim[axis.to_usize() + 1] as usize),
+            axis,
+            padding: meta.padding,
+            indices: None,
+        }
+    }
+
+    /// The provenance of a group of slices or volumes, from the metadata of the first.
+    pub fn of_group(meta: &ChunkMeta, indices: &[usize]) -> Self {
+        Self {
+            indices: Some(indices.to_vec()),
+            ..Self::of(meta)
+        }
+    }
+
+    /// The header extension holding the provenance as JSON.
+    pub fn to_extension(&self) -> Extension {
+        let json = serde_json::json!({ KEY: self });
+        Extension::from_str(ECODE_COMMENT, &json.to_string())
+    }
+
+    /// The short summary of the provenance stored in `descrip`.
+    pub fn description(&self) -> String {
+        if let Some(indices) = &self.indices {
+            let indices: Vec<String> = indices.iter().map(|i| (i + 1).to_string()).collect();
+            if self.axis == Direction::T {
+                format!(
+                    "slicenii {}: volumes {} of {}",
+                    self.version,
+                    indices.join(","),
+                    self.count
+                )
+            } else {
+                format!(
+                    "slicenii {}: slices {} of {} on axis {}",
+                    self.version,
+                    indices.join(","),
+                    self.count,
+                    self.axis
+                )
+            }
+        } else if self.axis == Direction::T {
+            format!(
+                "slicenii {}: volume {} of {}",
+                self.version,
+                self.index + 1,
+                self.count
+            )
+        } else {
+            format!(
+                "slicenii {}: slice {} of {} on axis {}, padding {}",
+                self.version,
+                self.index + 1,
+                self.count,
+                self.axis,
+                self.padding
+            )
+        }
+    }
+
+    /// Write the summary into the `descrip` of `header`, if it fits.
+    pub fn describe(&self, header: &mut NiftiHeader) {
+        let _ = header.set_description_str(self.description());
+    }
+
+    /// Read the provenance from the extensions of a file, if one of them holds it.
+    pub fn from_extensions(extensions: &[Extension]) -> Option<Self> {
+        extensions
+            .iter()
+            .filter(|e| e.code() == ECODE_COMMENT)
+            .find_map(|e| {
+                // comments are padded with zeros to a multiple of 16 bytes
+                let text = std::str::from_utf8(e.data()).ok()?.trim_end_matches('\0');
+                let mut json: serde_json::Value = serde_json::from_str(text).ok()?;
+                serde_json::from_value(json.get_mut(KEY)?.take()).ok()
+            })
+    }
+
+    /// Read the provenance back from the summary in `descrip`, which has no source file.
+    pub fn from_description(header: &NiftiHeader) -> Option<Self> {
+        let descrip = String::from_utf8_lossy(&header.descrip);
+        let descrip = descrip.trim_end_matches('\0');
+        let (version, rest) = descrip.strip_prefix("slicenii ")?.split_once(": ")?;
+        let numbers = |text: &str| -> Option<(usize, usize)> {
+            let (index, count) = text.split_once(" of ")?;
+            Some((
+                index.parse::<usize>().ok()?.checked_sub(1)?,
+                count.parse().ok()?,
+            ))
+        };
+        let (axis, (index, count), padding) = match rest.strip_prefix("volume ") {
+            Some(rest) => (Direction::T, numbers(rest)?, 1),
+            None => {
+                let (numbers_text, rest) = rest.strip_prefix("slice ")?.split_once(" on axis ")?;
+                let (axis, padding) = rest.split_once(", padding ")?;
+                let axis = match axis {
+                    "0" => Direction::X,
+                    "1" => Direction::Y,
+                    "2" => Direction::Z,
+                    _ => return None,
+                };
+                (axis, numbers(numbers_text)?, padding.parse().ok()?)
+            }
+        };
+        Some(Self {
+            version: version.to_string(),
+            source: None,
+            axis,
+            index,
+            count,
+            padding,
+            indices: None,
+        })
+    }
+}
+
+/// Whether an extension holds the provenance of a file written by slicenii.
+pub fn is_provenance(extension: &Extension) -> bool {
+    Provenance::from_extensions(std::slice::from_ref(extension)).is_some()
+}
+
+/// Read the provenance of a file from its extensions, or from its `descrip` if none holds it.
+///
+/// # Returns
+///
+/// The provenance, or `None` if the file was not written by slicenii or its header was
+/// rewritten since.
+pub fn read_provenance(path: &Path) -> Result<Option<Provenance>> {
+    if let Some(provenance) = Provenance::from_extensions(&read_extensions(path)?) {
+        return Ok(Some(provenance));
+    }
+    Ok(Provenance::from_description(&read_header(path)?))
+}
+
+/// Read the provenance of a file, failing if it has none.
+pub fn require_provenance(path: &Path) -> Result<Provenance> {
+    read_provenance(path)?.ok_or_else(|| {
+        Error::Input(format!(
+            "{} has no slicenii provenance in its header.",
+            path.display()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::OverwritePolicy;
+    use crate::common::SourceInfo;
+    use crate::io::write_image_with_extensions;
+    use ndarray::Array3;
+    use std::fs;
+
+    /// The metadata of the chunk at `index` along `axis` of a 4x3x5x7 source named `bold.nii`.
+    fn meta(axis: Direction, index: usize) -> ChunkMeta {
+        let source = NiftiHeader {
+            dim: [4, 4, 3, 5, 7, 1, 1, 1],
+            ..Default::default()
+        };
+        ChunkMeta {
+            padding: 3,
+            source: Some(SourceInfo::from_header(
+                &source,
+                Some("/data/sub-01/bold.nii".into()),
+            )),
+            ..ChunkMeta::new(Some(axis), index..index + 1)
+        }
+    }
+
+    #[test]
+    fn reads_back_the_extension() {
+        let provenance = Provenance::of(&meta(Direction::Z, 2));
+        assert_eq!(provenance.source.as_deref(), Some("bold.nii"));
+        assert_eq!((provenance.index, provenance.count), (2, 5));
+        assert_eq!(provenance.padding, 3);
+
+        let extension = provenance.to_extension();
+        assert_eq!(extension.code(), ECODE_COMMENT);
+        assert!(is_provenance(&extension));
+        let note = Extension::from_str(ECODE_COMMENT, "{\"note\": 1}");
+        assert!(!is_provenance(&note));
+        assert_eq!(
+            Provenance::from_extensions(&[note, extension]),
+            Some(provenance.clone())
+        );
+
+        // extensions are padded with zeros in the file
+        let dir = std::env::temp_dir().join(format!("slicenii-provenance-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("slice.nii");
+        let data = Array3::<f64>::zeros((4, 3, 3));
+        write_image_with_extensions(
+            &path,
+            &NiftiHeader::default(),
+            &[provenance.to_extension()],
+            &data.view(),
+            &OverwritePolicy::Error,
+        )
+        .unwrap();
+        let read = read_provenance(&path);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(read.unwrap(), Some(provenance));
+    }
+
+    #[test]
+    fn summarises_in_descrip() {
+        let mut header = NiftiHeader::default();
+        let slice = Provenance::of(&meta(Direction::Y, 1));
+        slice.describe(&mut header);
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            slice.description(),
+            format!("slicenii {}: slice 2 of 3 on axis 1, padding 3", version)
+        );
+        let read = Provenance::from_description(&header).unwrap();
+        assert_eq!(
+            read,
+            Provenance {
+                source: None,
+                ..slice
+            }
+        );
+
+        let volume = Provenance::of(&meta(Direction::T, 6));
+        volume.describe(&mut header);
+        let read = Provenance::from_description(&header).unwrap();
+        assert_eq!((read.axis, read.index, read.count), (Direction::T, 6, 7));
+
+        // descrip holds 80 bytes, so a long summary leaves it as it was
+        let group = Provenance::of_group(&meta(Direction::T, 0), &(0..40).collect::<Vec<_>>());
+        assert!(group.description().len() > 80);
+        group.describe(&mut header);
+        assert_eq!(
+            Provenance::from_description(&header).map(|p| p.index),
+            Some(6)
+        );
+        assert!(Provenance::from_description(&NiftiHeader::default()).is_none());
+    }
+
+    #[test]
+    fn groups_keep_the_first_chunk() {
+        let group = Provenance::of_group(&meta(Direction::Z, 1), &[1, 3]);
+        assert_eq!(group.indices, Some(vec![1, 3]));
+        assert_eq!(group.index, 1);
+        assert_eq!(
+            group,
+            Provenance {
+                indices: Some(vec![1, 3]),
+                ..Provenance::of(&meta(Direction::Z, 1))
+            }
+        );
+        let version = env!("CARGO_PKG_VERSION");
+        assert_eq!(
+            group.description(),
+            format!("slicenii {}: slices 2,4 of 5 on axis 2", version)
+        );
+        let volumes = Provenance::of_group(&meta(Direction::T, 0), &[0, 2]);
+        assert_eq!(
+            volumes.description(),
+            format!("slicenii {}: volumes 1,3 of 7", version)
+        );
+        // the summary of a group cannot be read back without the extension
+        let mut header = NiftiHeader::default();
+        group.describe(&mut header);
+        assert!(Provenance::from_description(&header).is_none());
+        let parsed = Provenance::from_extensions(&[group.to_extension()]).unwrap();
+        assert_eq!(parsed.indices, Some(vec![1, 3]));
+    }
+}
@@ -0,0 +1,357 @@
+//! BIDS JSON sidecars carried along with the images they describe.
+//!
+//! A BIDS image such as `sub-01_task-rest_bold.nii.gz` comes with `sub-01_task-rest_bold.json`,
+//! holding fields like `RepetitionTime`, `SliceTiming`, `PhaseEncodingDirection` and
+//! `EchoTime`. When such a sidecar sits next to the input, every slice or volume written gets
+//! an adjusted copy, and a combined image gets one rebuilt from its reference or its inputs.
+use nifti::NiftiHeader;
+use serde_json::{Map, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::OverwritePolicy;
+use crate::commands::combine::InputFile;
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::header::{seconds_per_unit, slice_dim, volume_onset};
+use crate::io::{nifti_stem, write_text_atomic};
+
+/// Sidecar is the contents of a JSON sidecar.
+pub type Sidecar = Map<String, Value>;
+
+/// The path of the JSON sidecar of a NIfTI file: the same name with `.json` instead of
+/// `.nii` or `.nii.gz`.
+pub fn sidecar_path(nifti: &Path) -> Result<PathBuf> {
+    Ok(nifti.with_file_name(format!("{}.json", nifti_stem(nifti)?)))
+}
+
+/// Read the JSON sidecar of a NIfTI file, if it has one.
+pub fn read_sidecar(nifti: &Path) -> Result<Option<Sidecar>> {
+    let path = sidecar_path(nifti)?;
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(&path)?;
+    match serde_json::from_str(&text) {
+        Ok(Value::Object(sidecar)) => Ok(Some(sidecar)),
+        Ok(_) => Err(Error::Input(format!(
+            "{} is not a JSON object.",
+            path.display()
+        ))),
+        Err(e) => Err(Error::Input(format!(
+            "Could not read {}: {}",
+            path.display(),
+            e
+        ))),
+    }
+}
+
+/// Write the JSON sidecar of a NIfTI file, following `policy` if it already exists.
+pub fn write_sidecar(nifti: &Path, sidecar: &Sidecar, policy: &OverwritePolicy) -> Result<()> {
+    let text = serde_json::to_string_pretty(sidecar)
+        .map_err(|e| Error::Output(format!("Could not write sidecar: {}", e)))?;
+    write_text_atomic(&sidecar_path(nifti)?, &(text + "\n"), policy)?;
+    Ok(())
+}
+
+/// The axis `SliceTiming` runs along, and whether it runs from the last slice to the first.
+///
+/// This is `SliceEncodingDirection` if the sidecar has it, otherwise the slice axis of
+/// `dim_info`, otherwise the third axis as BIDS assumes.
+pub fn slice_encoding(sidecar: &Sidecar, header: &NiftiHeader) -> (usize, bool) {
+    match sidecar
+        .get("SliceEncodingDirection")
+        .and_then(Value::as_str)
+    {
+        Some(direction) => {
+            let axis = match direction.trim_end_matches('-') {
+                "i" => 0,
+                "j" => 1,
+                _ => 2,
+            };
+            (axis, direction.ends_with('-'))
+        }
+        None => (slice_dim(header).unwrap_or(2), false),
+    }
+}
+
+/// The sidecar of one slice.
+///
+/// If the slice was cut along the slice encoding axis, `SliceTiming` keeps only the time of
+/// that slice, once for every padded copy. Otherwise the slice still holds every acquired
+/// slice and the sidecar is copied as is.
+///
+/// # Arguments
+///
+/// * `sidecar` - The sidecar of the source.
+/// * `header` - The header of the source.
+/// * `axis` - The axis the slice was cut along.
+/// * `index` - The position of the slice along `axis`.
+/// * `padding` - How many copies of the slice are stacked in the file.
+pub fn for_slice(
+    sidecar: &Sidecar,
+    header: &NiftiHeader,
+    axis: &Direction,
+    index: usize,
+    padding: usize,
+) -> Sidecar {
+    let mut slice = sidecar.clone();
+    let (slice_axis, reversed) = slice_encoding(sidecar, header);
+    if slice_axis != axis.to_usize() {
+        return slice;
+    }
+    if let Some(Value::Array(timing)) = sidecar.get("SliceTiming") {
+        let entry = if reversed {
+            timing.len().checked_sub(index + 1)
+        } else {
+            Some(index)
+        };
+        match entry.and_then(|i| timing.get(i)) {
+            Some(time) => {
+                slice.insert(
+                    "SliceTiming".to_string(),
+                    Value::Array(vec![time.clone(); padding.max(1)]),
+                );
+            }
+            None => {
+                slice.remove("SliceTiming");
+            }
+        }
+    }
+    slice
+}
+
+/// The sidecar of one interleaved packet.
+///
+/// If the packet was cut along the slice encoding axis, `SliceTiming` keeps the entries of
+/// its slices, still counted from the start of the whole volume. Otherwise the sidecar is
+/// copied as is.
+///
+/// # Arguments
+///
+/// * `sidecar` - The sidecar of the source.
+/// * `header` - The header of the source.
+/// * `axis` - The axis the packet was cut along.
+/// * `slices` - The positions of the slices of the packet along `axis`, in spatial order.
+pub fn for_packet(
+    sidecar: &Sidecar,
+    header: &NiftiHeader,
+    axis: &Direction,
+    slices: &[usize],
+) -> Sidecar {
+    let mut packet = sidecar.clone();
+    let (slice_axis, reversed) = slice_encoding(sidecar, header);
+    if slice_axis != axis.to_usize() {
+        return packet;
+    }
+    if let Some(Value::Array(timing)) = sidecar.get("SliceTiming") {
+        let times: Option<Vec<Value>> = slices
+            .iter()
+            .map(|&index| {
+                let entry = if reversed {
+                    timing.len().checked_sub(index + 1)?
+                } else {
+                    index
+                };
+                timing.get(entry).cloned()
+            })
+            .collect();
+        match times {
+            Some(mut times) => {
+                if reversed {
+                    times.reverse();
+                }
+                packet.insert("SliceTiming".to_string(), Value::Array(times));
+            }
+            None => {
+                packet.remove("SliceTiming");
+            }
+        }
+    }
+    packet
+}
+
+/// The sidecar of one volume, with `AcquisitionTime` moved on by `offset_s` seconds.
+pub fn for_volume(sidecar: &Sidecar, offset_s: Option<f64>) -> Sidecar {
+    let mut volume = sidecar.clone();
+    let shifted = volume
+        .get("AcquisitionTime")
+        .and_then(Value::as_str)
+        .and_then(parse_clock)
+        .zip(offset_s)
+        .map(|(start, offset)| format_clock(start + offset));
+    if let Some(time) = shifted {
+        volume.insert("AcquisitionTime".to_string(), Value::String(time));
+    }
+    volume
+}
+
+/// The sidecar of a group of volumes taken from a series, with `AcquisitionTime` moved on to
+/// the first of `indices`.
+pub fn for_group(sidecar: &Sidecar, header: &NiftiHeader, indices: &[usize]) -> Sidecar {
+    let offset_s = indices.first().and_then(|&first| {
+        seconds_per_unit(header)
+            .map(|seconds| (volume_onset(header, first) - header.toffset as f64) * seconds)
+    });
+    for_volume(sidecar, offset_s)
+}
+
+/// Parse a BIDS time of day, `hh:mm:ss[.ffffff]`, into seconds since midnight.
+fn parse_clock(text: &str) -> Option<f64> {
+    let mut parts = text.split(':');
+    let hours: f64 = parts.next()?.parse().ok()?;
+    let minutes: f64 = parts.next()?.parse().ok()?;
+    let seconds: f64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    Some(hours * 3600.0 + minutes * 60.0 + seconds)
+}
+
+/// Format seconds since midnight as a BIDS time of day, wrapping past midnight.
+fn format_clock(seconds: f64) -> String {
+    let seconds = seconds.rem_euclid(24.0 * 3600.0);
+    let hours = (seconds / 3600.0).floor();
+    let minutes = ((seconds - hours * 3600.0) / 60.0).floor();
+    let rest = seconds - hours * 3600.0 - minutes * 60.0;
+    format!("{:02}:{:02}:{:09.6}", hours as u32, minutes as u32, rest)
+}
+
+/// Build the sidecar of a combined image.
+///
+/// The sidecar of the reference is used if it has one, as it describes the original image.
+/// Otherwise it is rebuilt from the sidecars of the inputs: the first input's sidecar, with
+/// `SliceTiming` gathered from every slice if they were cut along the slice encoding axis.
+/// The first volume already has the `AcquisitionTime` of the series.
+///
+/// # Arguments
+///
+/// * `reference` - The path of the reference.
+/// * `header` - The header of the reference.
+/// * `inputs` - The combined inputs, in order.
+/// * `axis` - The axis they were combined along.
+///
+/// # Returns
+///
+/// The sidecar, or `None` if neither the reference nor the first input has one.
+pub fn for_combined(
+    reference: &Path,
+    header: &NiftiHeader,
+    inputs: &[InputFile],
+    axis: &Direction,
+) -> Result<Option<Sidecar>> {
+    if let Some(sidecar) = read_sidecar(reference)? {
+        return Ok(Some(sidecar));
+    }
+    let Some(first) = inputs.first() else {
+        return Ok(None);
+    };
+    let Some(mut combined) = read_sidecar(&first.path)? else {
+        return Ok(None);
+    };
+    let (slice_axis, reversed) = slice_encoding(&combined, header);
+    if *axis != Direction::T && slice_axis == axis.to_usize() {
+        let mut timing = Vec::new();
+        for input in inputs {
+            let time = read_sidecar(&input.path)?
+                .and_then(|s| s.get("SliceTiming")?.as_array()?.first().cloned());
+            match time {
+                Some(time) => timing.push(time),
+                None => {
+                    println!(
+                        "Warning! {} has no SliceTiming, leaving it out of the combined sidecar.",
+                        input.path.display()
+                    );
+                    combined.remove("SliceTiming");
+                    return Ok(Some(combined));
+                }
+            }
+        }
+        if reversed {
+            timing.reverse();
+        }
+        combined.insert("SliceTiming".to_string(), Value::Array(timing));
+    }
+    Ok(Some(combined))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn timed(direction: &str) -> Sidecar {
+        match json!({
+            "RepetitionTime": 2.0,
+            "SliceEncodingDirection": direction,
+            "SliceTiming": [0.0, 1.0, 0.5, 1.5],
+            "AcquisitionTime": "23:59:59.000000",
+        }) {
+            Value::Object(sidecar) => sidecar,
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn keeps_the_time_of_each_slice() {
+        let header = NiftiHeader::default();
+        let slice = for_slice(&timed("k"), &header, &Direction::Z, 2, 3);
+        assert_eq!(slice["SliceTiming"], json!([0.5, 0.5, 0.5]));
+        let slice = for_slice(&timed("k-"), &header, &Direction::Z, 0, 1);
+        assert_eq!(slice["SliceTiming"], json!([1.5]));
+        let slice = for_slice(&timed("k"), &header, &Direction::Z, 4, 1);
+        assert!(!slice.contains_key("SliceTiming"));
+        // slices along another axis hold every acquired slice
+        let slice = for_slice(&timed("k"), &header, &Direction::X, 2, 1);
+        assert_eq!(slice, timed("k"));
+    }
+
+    #[test]
+    fn moves_volumes_on_past_midnight() {
+        let volume = for_volume(&timed("k"), Some(2.5));
+        assert_eq!(volume["AcquisitionTime"], json!("00:00:01.500000"));
+        assert_eq!(for_volume(&timed("k"), None), timed("k"));
+    }
+
+    #[test]
+    fn gathers_slice_timing_back() {
+        let dir = std::env::temp_dir().join(format!("slicenii-sidecar-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let header = NiftiHeader::default();
+        for direction in ["k", "k-"] {
+            let source = timed(direction);
+            let inputs: Vec<InputFile> = (0..4)
+                .map(|index| {
+                    let path = dir.join(format!("slice-{}.nii", index));
+                    let slice = for_slice(&source, &header, &Direction::Z, index, 1);
+                    write_sidecar(&path, &slice, &OverwritePolicy::Replace).unwrap();
+                    InputFile {
+                        path,
+                        number: Some(index as u128 + 1),
+                        index,
+                    }
+                })
+                .collect();
+            let combined =
+                for_combined(&dir.join("reference.nii"), &header, &inputs, &Direction::Z)
+                    .unwrap()
+                    .unwrap();
+            assert_eq!(combined, source);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_the_times_of_each_packet() {
+        let header = NiftiHeader::default();
+        let packet = for_packet(&timed("k"), &header, &Direction::Z, &[1, 3]);
+        assert_eq!(packet["SliceTiming"], json!([1.0, 1.5]));
+        let packet = for_packet(&timed("k-"), &header, &Direction::Z, &[0, 2]);
+        // still running from the last slice to the first
+        assert_eq!(packet["SliceTiming"], json!([1.0, 1.5]));
+        let packet = for_packet(&timed("k"), &header, &Direction::Z, &[2, 4]);
+        assert!(!packet.contains_key("SliceTiming"));
+        let packet = for_packet(&timed("k"), &header, &Direction::Y, &[1, 3]);
+        assert_eq!(packet, timed("k"));
+    }
+}
@@ -0,0 +1,373 @@
+//! Iterator-based slicing of 3D volumes and splitting of 4D series.
+//!
+//! The iterators in this module yield borrowed views into the source array together with
+//! their index and the geometry needed to write them out, so callers can stream, filter or
+//! parallelise the slices without first materialising a second copy of the whole volume.
+use nalgebra::{Matrix4, Point4};
+use ndarray::prelude::*;
+use ndarray::{Array3, Slice};
+use nifti::NiftiHeader;
+
+use std::path::PathBuf;
+
+use crate::common::{ChunkMeta, Direction, Slice3D, SourceInfo, Vol3D};
+use crate::header::volume_onset;
+
+/// A borrowed 2D slice of a 3D array, kept 3D with a singleton dimension along the slice axis.
+#[derive(Debug, Clone)]
+pub struct SliceView<'a> {
+    /// The position of the slice along the slicing axis.
+    pub index: usize,
+    /// A view of the slice with a length of one along the slicing axis.
+    pub view: ArrayView3<'a, f64>,
+    /// The affine that places this slice in world space.
+    pub affine: Matrix4<f64>,
+    axis: Direction,
+    source: SourceInfo,
+}
+
+impl<'a> SliceView<'a> {
+    /// The axis this slice was taken along.
+    pub fn axis(&self) -> &Direction {
+        &self.axis
+    }
+
+    /// Copy the view into an owned array with `padding` copies of the slice stacked along the
+    /// slicing axis. A padding of 0 or 1 returns a single copy.
+    pub fn padded(&self, padding: usize) -> Array3<f64> {
+        if padding <= 1 {
+            return self.view.to_owned();
+        }
+        let copies = vec![self.view.view(); padding];
+        ndarray::concatenate(Axis(self.axis.to_usize()), &copies)
+            .expect("copies of the same view always have matching shapes")
+    }
+
+    /// The chunk metadata of this slice once stored with `padding` copies.
+    pub fn meta(&self, padding: usize) -> ChunkMeta {
+        ChunkMeta {
+            axis: Some(self.axis.clone()),
+            range: self.index..self.index + 1,
+            padding: padding.max(1),
+            affine: self.affine,
+            toffset: self.source.toffset,
+            source: Some(self.source.clone()),
+        }
+    }
+
+    /// Copy the view into an owned `Slice3D`, padded as in [`SliceView::padded`].
+    pub fn to_slice3d(&self, padding: usize) -> Slice3D {
+        Slice3D::new(self.padded(padding), self.index).with_meta(self.meta(padding))
+    }
+}
+
+/// Iterator over the slices of a 3D array along one axis.
+///
+/// Created with [`SliceIter::new`]; each item borrows from the source array.
+#[derive(Debug, Clone)]
+pub struct SliceIter<'a> {
+    img: ArrayView3<'a, f64>,
+    axis: Direction,
+    header: NiftiHeader,
+    source: SourceInfo,
+    next: usize,
+    end: usize,
+}
+
+impl<'a> SliceIter<'a> {
+    /// Create an iterator over the slices of `img` along `axis`. The `header` of the source
+    /// image is used to compute the affine of each slice.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `axis` is `Direction::T`, which has no meaning for a 3D array.
+    pub fn new(img: ArrayView3<'a, f64>, axis: &Direction, header: &NiftiHeader) -> Self {
+        assert!(*axis != Direction::T, "cannot slice a 3D array along time");
+        let end = img.shape()[axis.to_usize()];
+        Self {
+            img,
+            axis: axis.clone(),
+            header: header.clone(),
+            source: SourceInfo::from_header(header, None),
+            next: 0,
+            end,
+        }
+    }
+
+    /// Record `path` as the source file in the metadata of every slice.
+    pub fn with_source_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.source.path = Some(path.into());
+        self
+    }
+
+    /// The axis the slices are taken along.
+    pub fn axis(&self) -> &Direction {
+        &self.axis
+    }
+
+    /// The header of the source image.
+    pub fn header(&self) -> &NiftiHeader {
+        &self.header
+    }
+
+    /// The slices at `indices` stacked into one array, in that order.
+    pub fn select(&self, indices: &[usize]) -> Array3<f64> {
+        self.img.select(Axis(self.axis.to_usize()), indices)
+    }
+
+    /// The slice at `index`, whether or not the iterator has already passed it.
+    pub fn get(&self, index: usize) -> Option<SliceView<'a>> {
+        if index < self.img.shape()[self.axis.to_usize()] {
+            Some(self.view_at(index))
+        } else {
+            None
+        }
+    }
+
+    fn view_at(&self, index: usize) -> SliceView<'a> {
+        let a = Axis(self.axis.to_usize());
+        let mut view = self.img;
+        view.slice_axis_inplace(a, Slice::from(index..index + 1));
+        SliceView {
+            index,
+            view,
+            affine: slice_affine(&self.header, &self.axis, index),
+            axis: self.axis.clone(),
+            source: self.source.clone(),
+        }
+    }
+}
+
+impl<'a> Iterator for SliceIter<'a> {
+    type Item = SliceView<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let item = self.view_at(self.next);
+        self.next += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.end - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> DoubleEndedIterator for SliceIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        self.end -= 1;
+        Some(self.view_at(self.end))
+    }
+}
+
+impl<'a> ExactSizeIterator for SliceIter<'a> {}
+
+/// A borrowed 3D volume from a 4D series.
+#[derive(Debug, Clone)]
+pub struct VolView<'a> {
+    /// The position of the volume along the 4th (time) dimension.
+    pub index: usize,
+    /// A view of the volume.
+    pub view: ArrayView3<'a, f64>,
+    /// The affine of the volume, which is the same as the source image.
+    pub affine: Matrix4<f64>,
+    /// The onset of the volume, `toffset + index * pixdim[4]` of the source, in its units.
+    pub toffset: f32,
+    source: SourceInfo,
+}
+
+impl<'a> VolView<'a> {
+    /// The chunk metadata of this volume.
+    pub fn meta(&self) -> ChunkMeta {
+        ChunkMeta {
+            axis: Some(Direction::T),
+            range: self.index..self.index + 1,
+            padding: 1,
+            affine: self.affine,
+            toffset: self.toffset,
+            source: Some(self.source.clone()),
+        }
+    }
+
+    /// Copy the view into an owned `Vol3D`.
+    pub fn to_vol3d(&self) -> Vol3D {
+        Vol3D::new(self.view.to_owned(), self.index).with_meta(self.meta())
+    }
+}
+
+/// Iterator over the 3D volumes of a 4D array.
+///
+/// Created with [`VolIter::new`]; each item borrows from the source array.
+#[derive(Debug, Clone)]
+pub struct VolIter<'a> {
+    img: ArrayView4<'a, f64>,
+    affine: Matrix4<f64>,
+    header: NiftiHeader,
+    source: SourceInfo,
+    next: usize,
+    end: usize,
+}
+
+impl<'a> VolIter<'a> {
+    /// Create an iterator over the volumes of `img`, using `header` for the affine and the
+    /// onset of each volume.
+    pub fn new(img: ArrayView4<'a, f64>, header: &NiftiHeader) -> Self {
+        let end = img.shape()[3];
+        Self {
+            img,
+            affine: header.affine::<f64>(),
+            header: header.clone(),
+            source: SourceInfo::from_header(header, None),
+            next: 0,
+            end,
+        }
+    }
+
+    /// Record `path` as the source file in the metadata of every volume.
+    pub fn with_source_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.source.path = Some(path.into());
+        self
+    }
+
+    /// The volume at `index`, whether or not the iterator has already passed it.
+    pub fn get(&self, index: usize) -> Option<VolView<'a>> {
+        if index < self.img.shape()[3] {
+            Some(self.view_at(index))
+        } else {
+            None
+        }
+    }
+
+    fn view_at(&self, index: usize) -> VolView<'a> {
+        VolView {
+            index,
+            view: self.img.index_axis_move(Axis(3), index),
+            affine: self.affine,
+            toffset: volume_onset(&self.header, index) as f32,
+            source: self.source.clone(),
+        }
+    }
+}
+
+impl<'a> Iterator for VolIter<'a> {
+    type Item = VolView<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        let item = self.view_at(self.next);
+        self.next += 1;
+        Some(item)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.end - self.next;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<'a> DoubleEndedIterator for VolIter<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.next >= self.end {
+            return None;
+        }
+        self.end -= 1;
+        Some(self.view_at(self.end))
+    }
+}
+
+impl<'a> ExactSizeIterator for VolIter<'a> {}
+
+/// Compute the affine for a single slice taken at `index` along `axis`.
+///
+/// # Arguments
+///
+/// * `header` - The header of the source image.
+/// * `axis` - The axis along which the slice was taken.
+/// * `index` - The position of the slice along `axis`.
+///
+/// # Returns
+///
+/// A copy of the source affine with its origin moved to the first voxel of the slice, so
+/// the slice lies where it was in the source, whatever the rotation of the image.
+pub fn slice_affine(header: &NiftiHeader, axis: &Direction, index: usize) -> Matrix4<f64> {
+    let affine = header.affine::<f64>();
+
+    // the first voxel of the slice, in the voxel coordinates of the source
+    let mut pos_vox = Point4::new(0.0, 0.0, 0.0, 1.0);
+    pos_vox[axis.to_usize()] = index as f64;
+    // and in world coordinates, which is where the slice's own origin must be
+    let pos_world = affine * pos_vox;
+    let mut slice_affine = affine;
+    for i in 0..3 {
+        slice_affine[(i, 3)] = pos_world[i];
+    }
+    slice_affine
+}
+
+/// Score each spatial axis on how likely it is to be the slice direction.
+///
+/// Each pair of axes is compared and an axis scores a point for having fewer voxels and
+/// another for having larger voxels than the other axis, so the thick, sparse slice
+/// direction of a 2D acquisition scores highest.
+///
+/// # Arguments
+///
+/// * `dims` - The `dim` field of the NIfTI header.
+/// * `pixdims` - The `pixdim` field of the NIfTI header.
+///
+/// # Returns
+///
+/// The scores of the X, Y and Z axes.
+pub fn guess_scores(dims: [u16; 8], pixdims: [f32; 8]) -> [usize; 3] {
+    let dimensions = [
+        (dims[1], pixdims[1]),
+        (dims[2], pixdims[2]),
+        (dims[3], pixdims[3]),
+    ];
+    let mut scores = [0, 0, 0];
+    for i in 0..3 {
+        for j in (i + 1)..3 {
+            // increase chance if dim is smaller and/or pixdim is larger
+            if dimensions[i].0 < dimensions[j].0 {
+                scores[i] += 1;
+            } else if dimensions[i].0 > dimensions[j].0 {
+                scores[j] += 1;
+            }
+            if dimensions[i].1 > dimensions[j].1 {
+                scores[i] += 1;
+            } else if dimensions[i].1 < dimensions[j].1 {
+                scores[j] += 1;
+            }
+        }
+    }
+    scores
+}
+
+/// Guess which spatial axis an image was acquired along.
+///
+/// The axis with the highest [`guess_scores`] wins, and ties go to the last of the tied axes.
+///
+/// # Arguments
+///
+/// * `dims` - The `dim` field of the NIfTI header.
+/// * `pixdims` - The `pixdim` field of the NIfTI header.
+///
+/// # Returns
+///
+/// The guessed `Direction`, which is always X, Y or Z.
+pub fn guess_dir(dims: [u16; 8], pixdims: [f32; 8]) -> Direction {
+    let scores = guess_scores(dims, pixdims);
+    match scores.iter().enumerate().max_by_key(|&(_, score)| score) {
+        Some((2, _)) => Direction::Z,
+        Some((1, _)) => Direction::Y,
+        _ => Direction::X,
+    }
+}
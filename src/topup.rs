@@ -0,0 +1,250 @@
+//! Preparing b0 volumes for FSL's TOPUP, and undoing the changes made to their grid.
+//!
+//! TOPUP subsamples its inputs, so every spatial dimension must be even. Odd dimensions are
+//! padded by repeating the last plane, or cropped by dropping it. Either way only the high end
+//! of the axis changes, so voxel 0 and the affine stay the same, and the change is recorded in
+//! a small JSON file so the TOPUP outputs can be put back on the original grid.
+use ndarray::{Array4, ArrayD, Axis, IxDyn, Slice};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+use crate::cli::OverwritePolicy;
+use crate::error::{Error, Result};
+use crate::io::write_text_atomic;
+use crate::sidecar::Sidecar;
+
+/// EvenGrid records how a grid was made even for TOPUP.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EvenGrid {
+    /// The spatial shape before it was made even.
+    pub source_shape: [usize; 3],
+    /// The spatial shape after, with every dimension even.
+    pub even_shape: [usize; 3],
+    /// Whether odd dimensions were cropped rather than padded.
+    pub cropped: bool,
+}
+
+impl EvenGrid {
+    /// Plan how to make `shape` even, by padding or by cropping.
+    ///
+    /// # Returns
+    ///
+    /// The plan, or a usage error if cropping would leave an axis with no planes.
+    pub fn of(shape: [usize; 3], crop: bool) -> Result<EvenGrid> {
+        if crop && shape.contains(&1) {
+            return Err(Error::Usage(format!(
+                "Cannot crop the grid {:?}, an axis of one plane would be left empty. Pad it instead.",
+                shape
+            )));
+        }
+        let even_shape = shape.map(|n| match (n % 2, crop) {
+            (0, _) => n,
+            (_, true) => n - 1,
+            (_, false) => n + 1,
+        });
+        Ok(EvenGrid {
+            source_shape: shape,
+            even_shape,
+            cropped: crop,
+        })
+    }
+
+    /// Whether the grid is already even, so nothing changes.
+    pub fn is_unchanged(&self) -> bool {
+        self.source_shape == self.even_shape
+    }
+
+    /// Make the volumes of a series even, padding by repeating the last plane of each odd axis.
+    pub fn apply(&self, data: &Array4<f64>) -> Array4<f64> {
+        let mut data = data.clone();
+        for axis in 0..3 {
+            let n = self.source_shape[axis];
+            if self.even_shape[axis] < n {
+                data = data
+                    .slice_axis(Axis(axis), Slice::from(0..self.even_shape[axis]))
+                    .to_owned();
+            } else if self.even_shape[axis] > n {
+                let last = data
+                    .slice_axis(Axis(axis), Slice::from(n - 1..n))
+                    .to_owned();
+                data = ndarray::concatenate(Axis(axis), &[data.view(), last.view()])
+                    .expect("shapes agree apart from the padded axis");
+            }
+        }
+        data
+    }
+
+    /// Put an image on the even grid back on the source grid.
+    ///
+    /// Padded planes are dropped, and cropped planes come back filled with zeros, as their
+    /// voxels were never seen by TOPUP. Any dimensions after the third are kept.
+    ///
+    /// # Returns
+    ///
+    /// The image on the source grid, or an input error if it is not on the even grid.
+    pub fn restore(&self, data: &ArrayD<f64>) -> Result<ArrayD<f64>> {
+        let shape = data.shape();
+        if shape.len() < 3 || shape[..3] != self.even_shape {
+            return Err(Error::Input(format!(
+                "The image has shape {:?}, but the TOPUP grid was {:?}.",
+                shape, self.even_shape
+            )));
+        }
+        let mut restored_shape = shape.to_vec();
+        restored_shape[..3].copy_from_slice(&self.source_shape);
+        let mut restored = ArrayD::<f64>::zeros(IxDyn(&restored_shape));
+        let kept: Vec<usize> = (0..3)
+            .map(|i| self.source_shape[i].min(self.even_shape[i]))
+            .collect();
+        restored
+            .slice_each_axis_mut(|ax| match ax.axis.index() {
+                i if i < 3 => Slice::from(0..kept[i]),
+                _ => Slice::from(..),
+            })
+            .assign(&data.slice_each_axis(|ax| match ax.axis.index() {
+                i if i < 3 => Slice::from(0..kept[i]),
+                _ => Slice::from(..),
+            }));
+        Ok(restored)
+    }
+
+    /// Read a record written by `write`.
+    pub fn read(path: &Path) -> Result<EvenGrid> {
+        let text = fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|e| {
+            Error::Input(format!(
+                "{} is not a TOPUP grid record: {}",
+                path.display(),
+                e
+            ))
+        })
+    }
+
+    /// Write the record as JSON, following `policy` if it already exists.
+    pub fn write(&self, path: &Path, policy: &OverwritePolicy) -> Result<()> {
+        let text = serde_json::to_string_pretty(self)
+            .map_err(|e| Error::Output(format!("Could not write {}: {}", path.display(), e)))?;
+        write_text_atomic(path, &(text + "\n"), policy)?;
+        Ok(())
+    }
+}
+
+/// The unit vector of a BIDS phase encoding direction, such as `j-`.
+pub fn phase_vector(direction: &str) -> Option<[i32; 3]> {
+    let sign = if direction.ends_with('-') { -1 } else { 1 };
+    match direction.trim_end_matches('-') {
+        "i" => Some([sign, 0, 0]),
+        "j" => Some([0, sign, 0]),
+        "k" => Some([0, 0, sign]),
+        _ => None,
+    }
+}
+
+/// One line of `acqparams.txt`: the phase encoding vector and the total readout time.
+///
+/// # Arguments
+///
+/// * `source` - The series, to name in errors.
+/// * `sidecar` - The sidecar of the series, if it has one.
+/// * `default_direction` - The phase encoding direction to use if the sidecar has none.
+/// * `readout_time` - The total readout time to use if the sidecar has none, in seconds.
+///
+/// # Returns
+///
+/// The line, and whether the default direction was used.
+pub fn acqparams_line(
+    source: &Path,
+    sidecar: Option<&Sidecar>,
+    default_direction: &str,
+    readout_time: Option<f64>,
+) -> Result<(String, bool)> {
+    let direction = sidecar
+        .and_then(|s| s.get("PhaseEncodingDirection"))
+        .and_then(Value::as_str);
+    let defaulted = direction.is_none();
+    let direction = direction.unwrap_or(default_direction);
+    let vector = phase_vector(direction).ok_or_else(|| {
+        Error::Input(format!(
+            "'{}' is not a phase encoding direction.",
+            direction
+        ))
+    })?;
+    let readout = sidecar
+        .and_then(|s| s.get("TotalReadoutTime"))
+        .and_then(Value::as_f64)
+        .or(readout_time)
+        .ok_or_else(|| {
+            Error::Usage(format!(
+                "{} has no TotalReadoutTime in its sidecar. Use --readout-time to give it.",
+                source.display()
+            ))
+        })?;
+    Ok((
+        format!("{} {} {} {}", vector[0], vector[1], vector[2], readout),
+        defaulted,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ramp(shape: [usize; 4]) -> Array4<f64> {
+        Array4::from_shape_fn(shape, |(x, y, z, t)| {
+            (x + 10 * y + 100 * z + 1000 * t) as f64
+        })
+    }
+
+    #[test]
+    fn pads_and_restores_odd_axes() {
+        let grid = EvenGrid::of([3, 4, 5], false).unwrap();
+        assert_eq!(grid.even_shape, [4, 4, 6]);
+        let data = ramp([3, 4, 5, 2]);
+        let even = grid.apply(&data);
+        assert_eq!(even.shape(), &[4, 4, 6, 2]);
+        // the last plane is repeated
+        assert_eq!(even[[3, 1, 2, 1]], data[[2, 1, 2, 1]]);
+        assert_eq!(even[[1, 1, 5, 0]], data[[1, 1, 4, 0]]);
+        let restored = grid.restore(&even.into_dyn()).unwrap();
+        assert_eq!(restored, data.into_dyn());
+    }
+
+    #[test]
+    fn crops_and_restores_with_zeros() {
+        let grid = EvenGrid::of([3, 4, 5], true).unwrap();
+        assert_eq!(grid.even_shape, [2, 4, 4]);
+        let data = ramp([3, 4, 5, 1]);
+        let even = grid.apply(&data);
+        assert_eq!(even.shape(), &[2, 4, 4, 1]);
+        let restored = grid.restore(&even.into_dyn()).unwrap();
+        assert_eq!(restored.shape(), &[3, 4, 5, 1]);
+        assert_eq!(restored[[1, 2, 3, 0]], data[[1, 2, 3, 0]]);
+        assert_eq!(restored[[2, 2, 3, 0]], 0.0);
+        assert_eq!(restored[[1, 2, 4, 0]], 0.0);
+    }
+
+    #[test]
+    fn even_grid_is_unchanged() {
+        let grid = EvenGrid::of([2, 4, 6], true).unwrap();
+        assert!(grid.is_unchanged());
+        let data = ramp([2, 4, 6, 1]);
+        assert_eq!(grid.apply(&data), data);
+    }
+
+    #[test]
+    fn rejects_cropping_a_single_plane() {
+        assert!(EvenGrid::of([4, 4, 1], true).is_err());
+        assert_eq!(
+            EvenGrid::of([4, 4, 1], false).unwrap().even_shape,
+            [4, 4, 2]
+        );
+    }
+
+    #[test]
+    fn restore_rejects_other_grids() {
+        let grid = EvenGrid::of([3, 4, 5], false).unwrap();
+        assert!(grid.restore(&ramp([3, 4, 5, 1]).into_dyn()).is_err());
+    }
+}
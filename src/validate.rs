@@ -0,0 +1,435 @@
+//! Checks of the inputs of a combine against the reference, made from their headers before
+//! any voxels are decoded.
+//!
+//! Every input is checked for its shape, voxel sizes, datatype and affine, and all problems are
+//! reported together with the files they were found in. Shape problems are always fatal, as
+//! such a slice cannot be placed; the others are fatal unless the check is lenient.
+use nifti::NiftiHeader;
+use std::path::PathBuf;
+
+use crate::commands::combine::InputFile;
+use crate::common::Direction;
+use crate::error::{Error, Result};
+use crate::header::datatype_name;
+use crate::io::read_header;
+use crate::provenance::{read_provenance, Provenance};
+use crate::slicing::slice_affine;
+
+/// The largest difference allowed between an affine and the expected one, in mm.
+pub const AFFINE_TOLERANCE: f64 = 1e-3;
+/// The largest difference allowed between voxel sizes, in the units of the reference.
+pub const PIXDIM_TOLERANCE: f32 = 1e-4;
+
+/// Problem is a mismatch found in the header of one input.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    pub path: PathBuf,
+    pub message: String,
+    /// Whether the input cannot be combined at all, whatever the check mode.
+    pub always_fatal: bool,
+}
+
+/// The affine a slice or volume at `index` is expected to have, given the reference.
+pub fn expected_affine(
+    reference: &NiftiHeader,
+    axis: &Direction,
+    index: usize,
+) -> nalgebra::Matrix4<f64> {
+    if *axis == Direction::T {
+        reference.affine::<f64>()
+    } else {
+        slice_affine(reference, axis, index)
+    }
+}
+
+/// Check the header of one input against the reference.
+///
+/// # Arguments
+///
+/// * `input` - The input file and the index it will be placed at.
+/// * `header` - The header of the input.
+/// * `reference` - The header of the reference.
+/// * `axis` - The axis the inputs are combined along.
+///
+/// # Returns
+///
+/// The problems found, if any.
+pub fn check_header(
+    input: &InputFile,
+    header: &NiftiHeader,
+    reference: &NiftiHeader,
+    axis: &Direction,
+) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |message: String, always_fatal: bool| {
+        problems.push(Problem {
+            path: input.path.clone(),
+            message,
+            always_fatal,
+        })
+    };
+
+    let dims = &header.dim[1..4];
+    let ref_dims = &reference.dim[1..4];
+    let shape_ok = header.dim[0] == 3
+        && (0..3).all(|i| {
+            if *axis != Direction::T && i == axis.to_usize() {
+                dims[i] >= 1
+            } else {
+                dims[i] == ref_dims[i]
+            }
+        });
+    if !shape_ok {
+        let dims = &header.dim[1..=(header.dim[0].clamp(1, 7) as usize)];
+        problem(
+            format!(
+                "shape {:?} does not fit reference shape {:?} along axis {:?}",
+                dims, ref_dims, axis
+            ),
+            true,
+        );
+    }
+
+    let pixdim_diff = (1..4)
+        .map(|i| (header.pixdim[i] - reference.pixdim[i]).abs())
+        .fold(0.0, f32::max);
+    if pixdim_diff > PIXDIM_TOLERANCE {
+        problem(
+            format!(
+                "voxel sizes {:?} differ from reference {:?}",
+                &header.pixdim[1..4],
+                &reference.pixdim[1..4]
+            ),
+            false,
+        );
+    }
+
+    let expected = expected_affine(reference, axis, input.index);
+    let affine_diff = (header.affine::<f64>() - expected).abs().max();
+    if affine_diff > AFFINE_TOLERANCE {
+        problem(
+            format!(
+                "affine differs by up to {:.4} from the one expected at index {}",
+                affine_diff,
+                input.index + 1
+            ),
+            false,
+        );
+    }
+    problems
+}
+
+/// Check the provenance slicenii recorded in an input against where it will be placed.
+///
+/// # Returns
+///
+/// A problem if the input was cut along another axis or from another position.
+pub fn check_provenance(
+    input: &InputFile,
+    provenance: &Provenance,
+    axis: &Direction,
+) -> Option<Problem> {
+    if provenance.axis == *axis && provenance.index == input.index {
+        return None;
+    }
+    Some(Problem {
+        path: input.path.clone(),
+        message: format!(
+            "header says it is index {} on axis {:?}{}, but it would be placed at index {} on axis {:?}",
+            provenance.index + 1,
+            provenance.axis,
+            provenance
+                .source
+                .as_ref()
+                .map_or(String::new(), |s| format!(" of {}", s)),
+            input.index + 1,
+            axis
+        ),
+        always_fatal: false,
+    })
+}
+
+/// Check the headers of all inputs against the reference, and against each other for the
+/// datatype.
+///
+/// Inputs written by slicenii are also checked against the provenance in their headers.
+/// Every problem is printed with its file. Unreadable headers and shape problems are always
+/// fatal; the rest are fatal only if `strict` is set.
+///
+/// # Arguments
+///
+/// * `inputs` - The files to combine, with the indices they will be placed at.
+/// * `reference` - The header of the reference.
+/// * `axis` - The axis the inputs are combined along.
+/// * `strict` - Whether voxel size, datatype and affine mismatches are fatal.
+///
+/// # Returns
+///
+/// An input error counting the problems if any are fatal.
+pub fn check_inputs(
+    inputs: &[InputFile],
+    reference: &NiftiHeader,
+    axis: &Direction,
+    strict: bool,
+) -> Result<()> {
+    let mut problems = Vec::new();
+    let mut first_datatype: Option<(i16, String, PathBuf)> = None;
+    for input in inputs {
+        let header = match read_header(&input.path) {
+            Ok(header) => header,
+            Err(e) => {
+                problems.push(Problem {
+                    path: input.path.clone(),
+                    message: e.to_string(),
+                    always_fatal: true,
+                });
+                continue;
+            }
+        };
+        problems.extend(check_header(input, &header, reference, axis));
+        if let Ok(Some(provenance)) = read_provenance(&input.path) {
+            problems.extend(check_provenance(input, &provenance, axis));
+        }
+        match &first_datatype {
+            None => {
+                first_datatype = Some((header.datatype, datatype_name(&header), input.path.clone()))
+            }
+            Some((datatype, name, first)) if *datatype != header.datatype => {
+                problems.push(Problem {
+                    path: input.path.clone(),
+                    message: format!(
+                        "datatype {} differs from {} of {}",
+                        datatype_name(&header),
+                        name,
+                        first.display()
+                    ),
+                    always_fatal: false,
+                })
+            }
+            Some(_) => {}
+        }
+    }
+
+    let fatal = problems.iter().filter(|p| strict || p.always_fatal).count();
+    for p in &problems {
+        if strict || p.always_fatal {
+            eprintln!("Mismatch! {}: {}", p.path.display(), p.message);
+        } else {
+            eprintln!("Warning! {}: {}", p.path.display(), p.message);
+        }
+    }
+    if fatal > 0 {
+        let hint = if strict {
+            " Use --lenient to combine despite voxel size, datatype or affine mismatches."
+        } else {
+            ""
+        };
+        Err(Error::Input(format!(
+            "{} problem(s) found in the headers of the inputs.{}",
+            fatal, hint
+        )))
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::OverwritePolicy;
+    use crate::io::{write_image, write_image_with_extensions};
+    use nalgebra::{Matrix4, Vector4};
+    use ndarray::Array3;
+    use nifti::writer::WriterOptions;
+    use std::fs;
+
+    /// A 2 mm grid of shape 4x3x5.
+    fn reference() -> NiftiHeader {
+        let mut header = NiftiHeader {
+            dim: [3, 4, 3, 5, 1, 1, 1, 1],
+            pixdim: [1.0, 2.0, 2.0, 2.0, 1.0, 1.0, 1.0, 1.0],
+            ..Default::default()
+        };
+        header.set_affine(&Matrix4::from_diagonal(&Vector4::new(2.0, 2.0, 2.0, 1.0)));
+        header
+    }
+
+    /// The header of the slice at `index` along Z of the reference.
+    fn slice(index: usize) -> NiftiHeader {
+        let reference = reference();
+        let mut header = NiftiHeader {
+            dim: [3, 4, 3, 1, 1, 1, 1, 1],
+            ..reference.clone()
+        };
+        header.set_affine(&slice_affine(&reference, &Direction::Z, index));
+        header
+    }
+
+    fn input(dir: &std::path::Path, index: usize) -> InputFile {
+        InputFile {
+            path: dir.join(format!("slice_{}.nii", index + 1)),
+            number: Some(index as u128 + 1),
+            index,
+        }
+    }
+
+    #[test]
+    fn accepts_fitting_inputs() {
+        let dir = std::path::Path::new("slices");
+        let reference = reference();
+        for index in 0..5 {
+            assert!(
+                check_header(&input(dir, index), &slice(index), &reference, &Direction::Z)
+                    .is_empty()
+            );
+        }
+        // a volume has the shape and affine of the reference itself
+        assert!(check_header(&input(dir, 3), &reference, &reference, &Direction::T).is_empty());
+    }
+
+    #[test]
+    fn reports_each_header_mismatch() {
+        let dir = std::path::Path::new("slices");
+        let reference = reference();
+        let check = |header: &NiftiHeader, index: usize| {
+            check_header(&input(dir, index), header, &reference, &Direction::Z)
+        };
+
+        let mut wide = slice(1);
+        wide.dim[1] = 5;
+        let problems = check(&wide, 1);
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].always_fatal);
+        assert!(
+            problems[0].message.contains("shape [5, 3, 1]"),
+            "{}",
+            problems[0].message
+        );
+        let mut series = slice(1);
+        series.dim[0] = 4;
+        assert!(check(&series, 1)[0].always_fatal);
+
+        let mut coarse = slice(1);
+        coarse.pixdim[2] = 2.5;
+        let problems = check(&coarse, 1);
+        assert_eq!(problems.len(), 1);
+        assert!(!problems[0].always_fatal);
+        assert!(
+            problems[0].message.contains("voxel sizes"),
+            "{}",
+            problems[0].message
+        );
+
+        // the slice cut at index 2 does not belong at index 4
+        let problems = check(&slice(1), 3);
+        assert_eq!(problems.len(), 1);
+        assert!(!problems[0].always_fatal);
+        assert!(
+            problems[0].message.contains("expected at index 4"),
+            "{}",
+            problems[0].message
+        );
+    }
+
+    #[test]
+    fn checks_the_provenance_against_the_placement() {
+        let dir = std::path::Path::new("slices");
+        let provenance = Provenance {
+            version: "0.0.0".to_string(),
+            source: Some("bold.nii".to_string()),
+            axis: Direction::Z,
+            index: 2,
+            count: 5,
+            padding: 1,
+            indices: None,
+        };
+        assert!(check_provenance(&input(dir, 2), &provenance, &Direction::Z).is_none());
+
+        let moved = check_provenance(&input(dir, 3), &provenance, &Direction::Z).unwrap();
+        assert!(!moved.always_fatal);
+        assert!(
+            moved
+                .message
+                .contains("index 3 on axis Z of bold.nii, but it would be placed at index 4"),
+            "{}",
+            moved.message
+        );
+        let turned = check_provenance(&input(dir, 2), &provenance, &Direction::X).unwrap();
+        assert!(turned.message.contains("on axis X"), "{}", turned.message);
+    }
+
+    #[test]
+    fn strict_checks_reject_what_lenient_ones_warn_about() {
+        let dir = std::env::temp_dir().join(format!("slicenii-validate-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let reference = reference();
+        let data = Array3::<f64>::zeros((4, 3, 1));
+        let write = |index: usize, header: &NiftiHeader| {
+            let path = input(&dir, index).path;
+            let _ = fs::remove_file(&path);
+            write_image(&path, header, &data.view(), &OverwritePolicy::Error).unwrap();
+        };
+        let inputs: Vec<InputFile> = (0..3).map(|index| input(&dir, index)).collect();
+        let check = |strict| check_inputs(&inputs, &reference, &Direction::Z, strict);
+        for index in 0..3 {
+            write(index, &slice(index));
+        }
+        check(true).unwrap();
+
+        // an affine mismatch
+        write(1, &slice(2));
+        assert!(matches!(check(true), Err(Error::Input(_))));
+        check(false).unwrap();
+        write(1, &slice(1));
+
+        // a datatype mismatch
+        fs::remove_file(&inputs[2].path).unwrap();
+        WriterOptions::new(&inputs[2].path)
+            .reference_header(&slice(2))
+            .write_nifti(&data.mapv(|v| v as f32))
+            .unwrap();
+        let err = check(true).unwrap_err().to_string();
+        assert!(err.contains("--lenient"), "{}", err);
+        check(false).unwrap();
+        write(2, &slice(2));
+
+        // provenance saying the file belongs elsewhere
+        let provenance = Provenance {
+            version: "0.0.0".to_string(),
+            source: None,
+            axis: Direction::Z,
+            index: 0,
+            count: 5,
+            padding: 1,
+            indices: None,
+        };
+        fs::remove_file(&inputs[1].path).unwrap();
+        write_image_with_extensions(
+            &inputs[1].path,
+            &slice(1),
+            &[provenance.to_extension()],
+            &data.view(),
+            &OverwritePolicy::Error,
+        )
+        .unwrap();
+        assert!(check(true).is_err());
+        check(false).unwrap();
+
+        // shape problems and unreadable files are fatal either way
+        let wide = Array3::<f64>::zeros((5, 3, 1));
+        fs::remove_file(&inputs[0].path).unwrap();
+        write_image(
+            &inputs[0].path,
+            &slice(0),
+            &wide.view(),
+            &OverwritePolicy::Error,
+        )
+        .unwrap();
+        assert!(check(false).is_err());
+        write(0, &slice(0));
+        fs::remove_file(&inputs[2].path).unwrap();
+        let missing = check(false);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(missing, Err(Error::Input(_))));
+    }
+}
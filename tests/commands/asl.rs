@@ -0,0 +1,17 @@
+//! Splitting an ASL series into label and control series and interleaving them back.
+use crate::support::{series, slicenii, source, Scratch};
+
+#[test]
+fn label_and_control_interleave_back_into_the_series() {
+    let scratch = Scratch::new("asl-round-trip");
+    let src = source(&scratch, &series());
+    let out = scratch.path("out");
+    let types = scratch.path("out/src_types");
+    let combined = scratch.path("combined.nii");
+    slicenii(&["split-asl", "-i", &src, "-o", &out, "--pattern", "LC"]).unwrap();
+    slicenii(&[
+        "combine", "-i", &types, "-o", &combined, "-r", &src, "--asl",
+    ])
+    .unwrap();
+    slicenii(&["verify", "-i", &combined, "-r", &src, "-s", &src]).unwrap();
+}
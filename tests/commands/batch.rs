@@ -0,0 +1,73 @@
+//! Batches of inputs whose outputs would collide.
+use ndarray::Axis;
+use std::fs;
+use std::path::Path;
+
+use slicenii::error::Error;
+
+use crate::support::{series, slicenii, source, Scratch};
+
+/// Copy the source written by [`source`] to each of `paths`, inside the scratch directory.
+fn copies(scratch: &Scratch, src: &str, paths: &[&str]) {
+    for path in paths {
+        let path = scratch.dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::copy(src, path).unwrap();
+    }
+}
+
+#[test]
+fn batch_skips_inputs_of_the_same_name() {
+    let scratch = Scratch::new("batch-collision");
+    let src = source(&scratch, &series().index_axis(Axis(3), 0).to_owned());
+    copies(&scratch, &src, &["a/sub.nii", "b/sub.nii", "c/other.nii"]);
+    let out = scratch.path("out");
+    let result = slicenii(&[
+        "batch",
+        "-i",
+        &scratch.path("a"),
+        &scratch.path("b"),
+        &scratch.path("c"),
+        "-o",
+        &out,
+    ]);
+    assert!(matches!(result, Err(Error::Batch(_))), "{:?}", result);
+    assert!(Path::new(&out).join("other_slices").is_dir());
+    assert!(!Path::new(&out).join("sub_slices").exists());
+}
+
+#[test]
+fn combine_batch_skips_directories_of_the_same_name() {
+    let scratch = Scratch::new("combine-batch-collision");
+    let src = source(&scratch, &series().index_axis(Axis(3), 0).to_owned());
+    copies(&scratch, &src, &["refs/sub.nii", "refs/other.nii"]);
+    for (reference, out) in [("sub", "a"), ("sub", "b"), ("other", "a")] {
+        let reference = scratch.path(&format!("refs/{}.nii", reference));
+        slicenii(&[
+            "slice",
+            "-i",
+            &reference,
+            "-o",
+            &scratch.path(out),
+            "-a",
+            "z",
+        ])
+        .unwrap();
+    }
+    let combined = scratch.path("combined");
+    let result = slicenii(&[
+        "combine-batch",
+        "-i",
+        &scratch.path("a/sub_slices"),
+        &scratch.path("b/sub_slices"),
+        &scratch.path("a/other_slices"),
+        "-r",
+        &scratch.path("refs"),
+        "-o",
+        &combined,
+    ]);
+    assert!(matches!(result, Err(Error::Batch(_))), "{:?}", result);
+    let other = scratch.path("combined/other_combined.nii");
+    assert!(!Path::new(&combined).join("sub_combined.nii").exists());
+    slicenii(&["verify", "-i", &other, "-r", &src, "-s", &src]).unwrap();
+}
@@ -0,0 +1,66 @@
+//! Dry runs print their plans without writing anything.
+use ndarray::Axis;
+use std::fs;
+
+use crate::support::{series, slicenii, source, Scratch};
+
+/// Whether `dir` is missing or empty.
+fn is_empty(dir: &str) -> bool {
+    fs::read_dir(dir).map_or(true, |mut entries| entries.next().is_none())
+}
+
+#[test]
+fn slicing_writes_nothing() {
+    let scratch = Scratch::new("dry-run-slice");
+    let src = source(&scratch, &series().index_axis(Axis(3), 0).to_owned());
+    let out = scratch.path("out");
+    slicenii(&["slice", "-i", &src, "-o", &out, "--dry-run"]).unwrap();
+    assert!(is_empty(&out));
+}
+
+#[test]
+fn splitting_writes_nothing() {
+    let scratch = Scratch::new("dry-run-split");
+    let src = source(&scratch, &series());
+    fs::write(scratch.dir.join("src.bval"), "0 1000 2000 995 0 2005\n").unwrap();
+    let bvec = "0 1 0 0 0 0.6\n0 0 1 0 0 0.8\n0 0 0 1 0 0\n";
+    fs::write(scratch.dir.join("src.bvec"), bvec).unwrap();
+    let out = scratch.path("out");
+    let runs: [&[&str]; 3] = [
+        &["split-time"],
+        &["split-time", "--group-by", "3"],
+        &["split-dwi", "--shells"],
+    ];
+    for run in runs {
+        let mut argv = run.to_vec();
+        argv.extend(["-i", &src, "-o", &out, "--dry-run"]);
+        slicenii(&argv).unwrap();
+        assert!(is_empty(&out), "{:?} wrote into {}", run, out);
+    }
+}
+
+#[test]
+fn combining_writes_nothing() {
+    let scratch = Scratch::new("dry-run-combine");
+    let src = source(&scratch, &series().index_axis(Axis(3), 0).to_owned());
+    let out = scratch.path("out");
+    slicenii(&["slice", "-i", &src, "-o", &out, "-a", "z"]).unwrap();
+    let slices = scratch.path("out/src_slices");
+    let before = fs::read_dir(&slices).unwrap().count();
+    let combined_dir = scratch.path("combined");
+    let combined = scratch.path("combined/src.nii");
+    slicenii(&[
+        "combine",
+        "-i",
+        &slices,
+        "-o",
+        &combined,
+        "-r",
+        &src,
+        "--dry-run",
+    ])
+    .unwrap();
+    assert!(is_empty(&combined_dir));
+    // nor next to the inputs, such as a journal
+    assert_eq!(fs::read_dir(&slices).unwrap().count(), before);
+}
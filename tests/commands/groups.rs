@@ -0,0 +1,17 @@
+//! Grouping the volumes of a series by cycle and interleaving them back.
+use crate::support::{series, slicenii, source, Scratch};
+
+#[test]
+fn cycled_groups_interleave_back_into_the_series() {
+    let scratch = Scratch::new("group-round-trip");
+    let src = source(&scratch, &series());
+    let out = scratch.path("out");
+    let groups = scratch.path("out/src_groups");
+    let combined = scratch.path("combined.nii");
+    slicenii(&["split-time", "-i", &src, "-o", &out, "--group-by", "3"]).unwrap();
+    slicenii(&[
+        "combine", "-i", &groups, "-o", &combined, "-r", &src, "--groups",
+    ])
+    .unwrap();
+    slicenii(&["verify", "-i", &combined, "-r", &src, "-s", &src]).unwrap();
+}
@@ -0,0 +1,14 @@
+//! End-to-end tests that cut images apart with slicenii and put them back together.
+//!
+//! Each module runs the subcommands of one workflow from their command lines, as a user
+//! would, and checks the result against the source with `verify`.
+mod support;
+
+mod asl;
+mod batch;
+mod dry_run;
+mod groups;
+mod packets;
+mod round_trip;
+mod shells;
+mod slice_order;
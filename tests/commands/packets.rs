@@ -0,0 +1,36 @@
+//! Splitting a volume into its interleaved packets and interleaving them back.
+use ndarray::Array3;
+use nifti::NiftiHeader;
+
+use crate::support::{slicenii, source_with, Scratch};
+
+#[test]
+fn packets_interleave_back_into_the_source() {
+    let scratch = Scratch::new("packet-round-trip");
+    let data = Array3::from_shape_fn((4, 3, 5), |(i, j, k)| (i * 100 + j * 10 + k) as f64);
+    // slices along z acquired 1, 3, 0, 2, 4
+    let header = NiftiHeader {
+        dim_info: 3 << 4,
+        slice_code: 5,
+        slice_end: 4,
+        slice_duration: 0.1,
+        ..Default::default()
+    };
+    let src = source_with(&scratch, &data, header);
+    let out = scratch.path("out");
+    let packets = scratch.path("out/src_packets");
+    let combined = scratch.path("combined.nii");
+    slicenii(&["packets", "-i", &src, "-o", &out, "-a", "z"]).unwrap();
+    slicenii(&[
+        "combine",
+        "-i",
+        &packets,
+        "-o",
+        &combined,
+        "-r",
+        &src,
+        "--packets",
+    ])
+    .unwrap();
+    slicenii(&["verify", "-i", &combined, "-r", &src, "-s", &src, "-a", "z"]).unwrap();
+}
@@ -0,0 +1,40 @@
+//! Slicing and splitting, combining the pieces and checking them against the source.
+use ndarray::Array3;
+
+use crate::support::{series, slicenii, source, Scratch};
+
+#[test]
+fn slices_combine_back_into_the_source() {
+    let scratch = Scratch::new("slice-round-trip");
+    let data = Array3::from_shape_fn((6, 5, 4), |(i, j, k)| (i * 100 + j * 10 + k) as f64);
+    let src = source(&scratch, &data);
+    for (axis, pad) in [("x", "1"), ("y", "3"), ("z", "2")] {
+        let out = scratch.path(axis);
+        let slices = scratch.path(&format!("{}/src_slices", axis));
+        let combined = scratch.path(&format!("{}.nii", axis));
+        slicenii(&["slice", "-i", &src, "-o", &out, "-a", axis, "-p", pad]).unwrap();
+        slicenii(&[
+            "combine", "-i", &slices, "-o", &combined, "-r", &src, "-a", axis,
+        ])
+        .unwrap();
+        slicenii(&[
+            "verify", "-i", &combined, "-r", &src, "-s", &src, "-a", axis,
+        ])
+        .unwrap();
+    }
+}
+
+#[test]
+fn volumes_combine_back_into_the_series() {
+    let scratch = Scratch::new("split-round-trip");
+    let src = source(&scratch, &series());
+    let out = scratch.path("out");
+    let vols = scratch.path("out/src_vols");
+    let combined = scratch.path("combined.nii");
+    slicenii(&["split-time", "-i", &src, "-o", &out]).unwrap();
+    slicenii(&[
+        "combine", "-i", &vols, "-o", &combined, "-r", &src, "-a", "t",
+    ])
+    .unwrap();
+    slicenii(&["verify", "-i", &combined, "-r", &src, "-s", &src]).unwrap();
+}
@@ -0,0 +1,25 @@
+//! Splitting a diffusion series into b-value shells and merging them back.
+use std::fs;
+
+use crate::support::{series, slicenii, source, Scratch};
+
+#[test]
+fn shells_merge_back_into_the_series() {
+    let scratch = Scratch::new("shell-round-trip");
+    let src = source(&scratch, &series());
+    let bval = "0 1000 2000 995 0 2005\n";
+    fs::write(scratch.dir.join("src.bval"), bval).unwrap();
+    let bvec = "0 1 0 0 0 0.6\n0 0 1 0 0 0.8\n0 0 0 1 0 0\n";
+    fs::write(scratch.dir.join("src.bvec"), bvec).unwrap();
+    let out = scratch.path("out");
+    let shells = scratch.path("out/src_shells");
+    let combined = scratch.path("combined.nii");
+    slicenii(&["split-dwi", "-i", &src, "-o", &out, "--shells"]).unwrap();
+    slicenii(&[
+        "combine", "-i", &shells, "-o", &combined, "-r", &src, "--shells",
+    ])
+    .unwrap();
+    slicenii(&["verify", "-i", &combined, "-r", &src, "-s", &src]).unwrap();
+    let merged = fs::read_to_string(scratch.dir.join("combined.bval")).unwrap();
+    assert_eq!(merged, bval);
+}
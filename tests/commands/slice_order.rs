@@ -0,0 +1,44 @@
+//! Numbering slices by acquisition time and putting them back in spatial order.
+use ndarray::Array3;
+use nifti::NiftiHeader;
+use slicenii::error::Error;
+
+use crate::support::{slicenii, source_with, Scratch};
+
+#[test]
+fn time_ordered_slices_combine_back_into_the_source() {
+    let scratch = Scratch::new("order-round-trip");
+    let data = Array3::from_shape_fn((4, 3, 5), |(i, j, k)| (i * 100 + j * 10 + k) as f64);
+    // slices along z acquired 0, 2, 4, 1, 3
+    let header = NiftiHeader {
+        dim_info: 3 << 4,
+        slice_code: 3,
+        slice_end: 4,
+        slice_duration: 0.1,
+        ..Default::default()
+    };
+    let src = source_with(&scratch, &data, header);
+    let out = scratch.path("out");
+    let slices = scratch.path("out/src_slices");
+    let combined = scratch.path("combined.nii");
+    slicenii(&[
+        "slice", "-i", &src, "-o", &out, "-a", "z", "--order", "time",
+    ])
+    .unwrap();
+    // numbered by time, the slices only land in place through the order table
+    let by_name = scratch.path("by-name.nii");
+    let unordered = slicenii(&["combine", "-i", &slices, "-o", &by_name, "-r", &src]);
+    assert!(matches!(unordered, Err(Error::Input(_))));
+    slicenii(&[
+        "combine",
+        "-i",
+        &slices,
+        "-o",
+        &combined,
+        "-r",
+        &src,
+        "--slice-order",
+    ])
+    .unwrap();
+    slicenii(&["verify", "-i", &combined, "-r", &src, "-s", &src, "-a", "z"]).unwrap();
+}
@@ -0,0 +1,97 @@
+//! The harness shared by the end-to-end tests: running subcommands and writing sources.
+use clap::Parser;
+use nalgebra::{Matrix4, Vector4};
+use ndarray::{Array, Array4, Dimension, RemoveAxis};
+use nifti::NiftiHeader;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use slicenii::cli::{Command, OverwritePolicy};
+use slicenii::commands::{asl, batch, combine, dwi, packets, slice, split, verify};
+use slicenii::error::Result;
+use slicenii::io::write_image;
+
+#[derive(Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Run a slicenii subcommand from its command line.
+pub fn slicenii(argv: &[&str]) -> Result<()> {
+    let argv = std::iter::once("slicenii").chain(argv.iter().copied());
+    match Cli::parse_from(argv).command {
+        Command::Slice(args) => slice::run(&args),
+        Command::SplitTime(args) => split::run(&args),
+        Command::SplitDwi(args) => dwi::run(&args),
+        Command::SplitAsl(args) => asl::run(&args),
+        Command::Packets(args) => packets::run(&args),
+        Command::Combine(args) => combine::run(&args),
+        Command::Verify(args) => verify::run(&args),
+        Command::Batch(args) => batch::run(&args),
+        Command::CombineBatch(args) => batch::run_combine(&args),
+        command => panic!("{:?} is not run by these tests", command),
+    }
+}
+
+/// A fresh directory for one test, removed when it is dropped.
+pub struct Scratch {
+    pub dir: PathBuf,
+}
+
+impl Scratch {
+    /// Make an empty directory named after the test.
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("slicenii-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        Scratch { dir }
+    }
+
+    /// A path inside the directory, as an argument.
+    pub fn path(&self, relative: &str) -> String {
+        self.dir.join(relative).display().to_string()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Write `data` as `src.nii`, with 2 mm voxels and 1.5 s volumes.
+///
+/// # Returns
+///
+/// The path of the source, as an argument.
+pub fn source<D: Dimension + RemoveAxis>(scratch: &Scratch, data: &Array<f64, D>) -> String {
+    source_with(scratch, data, NiftiHeader::default())
+}
+
+/// Write `data` as `src.nii` like [`source`], with the other fields of the header from
+/// `header`, such as its slice timing.
+pub fn source_with<D: Dimension + RemoveAxis>(
+    scratch: &Scratch,
+    data: &Array<f64, D>,
+    header: NiftiHeader,
+) -> String {
+    let mut header = NiftiHeader {
+        datatype: 64,
+        bitpix: 64,
+        pixdim: [1.0, 2.0, 2.0, 2.0, 1.5, 1.0, 1.0, 1.0],
+        xyzt_units: 10,
+        ..header
+    };
+    header.set_affine(&Matrix4::from_diagonal(&Vector4::new(2.0, 2.0, 2.0, 1.0)));
+    let path = Path::new(&scratch.dir).join("src.nii");
+    write_image(&path, &header, &data.view(), &OverwritePolicy::Error).unwrap();
+    path.display().to_string()
+}
+
+/// A series of 6 volumes of 3x2x2 voxels, every voxel of every volume different.
+pub fn series() -> Array4<f64> {
+    Array4::from_shape_fn((3, 2, 2, 6), |(i, j, k, t)| {
+        (i * 1000 + j * 100 + k * 10 + t) as f64
+    })
+}